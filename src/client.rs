@@ -41,7 +41,7 @@ use std::env;
 use AppId::*;
 use ntrumls::{Signature, PrivateKey, PublicKey, NTRUMLS, PQParamSetID};
 use storage::Hive;
-use model::{Transaction, TransactionObject};
+use model::{Transaction, TransactionObject, TransactionBuilder};
 use model::transaction::*;
 
 use rand::Rng;
@@ -220,7 +220,8 @@ fn send_coins(addr: Address, amount: u32) {
             let mut st = json::encode(&rpc::GetTransactionsToApprove {
                 depth: 1,
                 num_walks: 5,
-                reference: HASH_NULL
+                reference: HASH_NULL,
+                strategy: None
             }).unwrap();
             let mut s = Json::from_str(&st).unwrap();
             s.as_object_mut().unwrap().insert("method".to_string(), "getTransactionsToApprove".to_string().to_json());
@@ -260,54 +261,31 @@ fn send_coins(addr: Address, amount: u32) {
     }
 
     info!("sending {} to {:?}", amount, addr);
-//    let mut st = json::encode(&rpc::GetNodeInfo {}).unwrap();
-    let mut transaction = TransactionObject {
-        address: addr,
-        attachment_timestamp: 0u64,
-        attachment_timestamp_lower_bound: 0u64,
-        attachment_timestamp_upper_bound: 0u64,
-        branch_transaction: h0,
-        trunk_transaction: h1,
-        hash: HASH_NULL,
-        nonce: 0,
-        tag: HASH_NULL,
-        timestamp: time::SystemTime::now().elapsed().unwrap().as_secs(),
-        value: amount,
-        data_type: TransactionType::Full,
-        signature: Signature(vec![]),
-        signature_pubkey: PublicKey(vec![]),
-        snapshot: 0,
-        solid: false,
-        height: 0,
-    };
-
-    let mut transaction = Transaction::from_object(transaction);
-    let mwm = 8u32;
-    transaction.object.nonce = transaction.find_nonce(mwm);
-    transaction.object.hash = transaction.calculate_hash();
-
-    unsafe {
-        if let Some(ref sk) = SK {
-            if let Some(ref pk) = PK {
-//            if let Some(my_addr) = ADDRESS {
-                transaction.object.signature = transaction.calculate_signature(sk, pk).expect("failed to calculate signature");
-//                println!("signed");
-                transaction.object.signature_pubkey = pk.clone();
-
-//                println!("{:?}", pk);
-                debug!("{:?}", transaction.object.hash);
-//                println!("{:?}", transaction.object.signature);
-//            } else {
-//                println!("Address is None")
-//            }
-
-            } else {
-                debug!("pk is none");
+    let mwm = Difficulty::new(get_min_weight_magnitude().unwrap_or(8));
+
+    let transaction = unsafe {
+        match (&SK, &PK) {
+            (&Some(ref sk), &Some(ref pk)) => {
+                match TransactionBuilder::new()
+                    .recipient(addr)
+                    .value(amount)
+                    .parents(h1, h0)
+                    .min_weight_magnitude(mwm)
+                    .build(sk, pk) {
+                    Ok(transaction) => transaction,
+                    Err(e) => {
+                        error!("failed to build transaction: {}", e);
+                        return;
+                    }
+                }
+            }
+            _ => {
+                debug!("sk or pk is none");
+                return;
             }
-        } else {
-            debug!("sk is none");
         }
-    }
+    };
+    debug!("{:?}", transaction.object.hash);
 
     let mut st = json::encode(&rpc::BroadcastTransaction { transaction: transaction.object.clone() })
         .unwrap();
@@ -350,6 +328,22 @@ fn send_coins(addr: Address, amount: u32) {
     }
 }
 
+// MWM the node currently requires, it grows while the node is under load
+fn get_min_weight_magnitude() -> Option<u32> {
+    unsafe {
+        if let Some(ref n) = NEIGHBORS {
+            let mut st = json::encode(&rpc::GetNodeInfo {}).unwrap();
+            let mut s = Json::from_str(&st).unwrap();
+            s.as_object_mut().unwrap().insert("method".to_string(), "getNodeInfo".to_string().to_json());
+            send_request(s, n[0].clone())
+                .and_then(|json| json.find("min_weight_magnitude").and_then(|v| v.as_u64()))
+                .map(|v| v as u32)
+        } else {
+            None
+        }
+    }
+}
+
 fn send_request(request: Json, addr: SocketAddr) -> Option<Json> {
     let content = request.to_string();
     let content_length = content.len();
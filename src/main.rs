@@ -69,6 +69,10 @@ fn main() {
     let jh = Builder::new().name(format!("pmnc")).spawn(move || {
         let mut config = Configuration::new();
         let port = config.get_int(ConfigurationSettings::Port).unwrap();
+        if let Err(e) = config.coordinator() {
+            error!("Can't start with {}: {}", config.get_string(ConfigurationSettings::Config).unwrap_or_default(), e);
+            return;
+        }
 
         let pmnc = Arc::new(Mutex::new(PaymonCoin::new(config)));
 
@@ -215,4 +219,1328 @@ fn hive_transaction_test() {
 //    println!("sk={}", sk_data.to_hex().to_uppercase());
 //    println!("address={:?}", addr);
 //    println!("address={:?} balance={}", addr, balance);
-}
\ No newline at end of file
+}
+#[test]
+fn transaction_timestamp_test() {
+    use model::{Transaction, TransactionObject};
+    use model::transaction::{Hash, HASH_SIZE};
+    use model::transaction_validator::{has_invalid_timestamp, MAX_TIMESTAMP_FUTURE_MS};
+    use utils::{Clock, FixedClock};
+
+    let snapshot_ms = 1526912331000;
+    let clock = FixedClock(1530000000000);
+
+    let mut object = TransactionObject::new();
+    object.hash = Hash([1u8; HASH_SIZE]);
+    object.timestamp = clock.now_ms() / 1000;
+    assert!(!has_invalid_timestamp(&Transaction::from_object(object.clone()), snapshot_ms, clock.now_ms()));
+
+    object.timestamp = snapshot_ms / 1000 - 1;
+    assert!(has_invalid_timestamp(&Transaction::from_object(object.clone()), snapshot_ms, clock.now_ms()));
+
+    object.timestamp = (clock.now_ms() + MAX_TIMESTAMP_FUTURE_MS) / 1000 + 1;
+    assert!(has_invalid_timestamp(&Transaction::from_object(object.clone()), snapshot_ms, clock.now_ms()));
+
+    object.timestamp = clock.now_ms() / 1000;
+    object.attachment_timestamp = clock.now_ms();
+    object.attachment_timestamp_lower_bound = 0;
+    object.attachment_timestamp_upper_bound = clock.now_ms() + 1;
+    assert!(!has_invalid_timestamp(&Transaction::from_object(object.clone()), snapshot_ms, clock.now_ms()));
+
+    object.attachment_timestamp_upper_bound = clock.now_ms() - 1;
+    assert!(has_invalid_timestamp(&Transaction::from_object(object.clone()), snapshot_ms, clock.now_ms()));
+
+    object.attachment_timestamp_upper_bound = ::std::u64::MAX;
+    object.attachment_timestamp_lower_bound = clock.now_ms() + 1;
+    assert!(has_invalid_timestamp(&Transaction::from_object(object.clone()), snapshot_ms, clock.now_ms()));
+
+    object.attachment_timestamp_lower_bound = 0;
+    object.attachment_timestamp = snapshot_ms - 1;
+    assert!(has_invalid_timestamp(&Transaction::from_object(object.clone()), snapshot_ms, clock.now_ms()));
+}
+
+#[test]
+fn transaction_builder_test() {
+    use model::TransactionBuilder;
+    use model::transaction::{Hash, Difficulty, HASH_SIZE};
+    use model::transaction_validator;
+    use ntrumls::{NTRUMLS, PQParamSetID};
+    use utils::FixedClock;
+
+    let ntrumls = NTRUMLS::with_param_set(PQParamSetID::Security269Bit);
+    let (sk, pk) = ntrumls.generate_keypair().expect("failed to generate keypair");
+    let now_ms = 1530000000000;
+
+    let mut transaction = TransactionBuilder::new()
+        .value(0)
+        .parents(Hash([1u8; HASH_SIZE]), Hash([2u8; HASH_SIZE]))
+        .payload(vec![1, 2, 3])
+        .min_weight_magnitude(Difficulty(4))
+        .clock(Arc::new(FixedClock(now_ms)))
+        .build(&sk, &pk)
+        .expect("failed to build transaction");
+
+    assert_eq!(transaction.object.timestamp, now_ms / 1000);
+    assert_eq!(transaction.object.attachment_timestamp, now_ms);
+    assert_eq!(transaction.object.payload, vec![1, 2, 3]);
+    assert_eq!(transaction.object.signature_pubkey, pk);
+    assert!(transaction_validator::validate(&mut transaction, Difficulty(4), 0, now_ms).is_ok());
+
+    transaction.object.payload = vec![];
+    assert!(transaction_validator::validate(&mut transaction, Difficulty(4), 0, now_ms).is_err());
+}
+
+#[test]
+fn pow_commits_to_transaction_test() {
+    use model::{Transaction, TransactionObject};
+    use model::transaction::{Address, Difficulty, Hash, ADDRESS_SIZE, HASH_SIZE};
+
+    let transaction = |value: u32| {
+        let mut object = TransactionObject::new();
+        object.trunk_transaction = Hash([1u8; HASH_SIZE]);
+        object.branch_transaction = Hash([2u8; HASH_SIZE]);
+        object.address = Address([3u8; ADDRESS_SIZE]);
+        object.value = value;
+        let mut transaction = Transaction::from_object(object);
+        transaction.object.hash = transaction.calculate_hash();
+        transaction
+    };
+
+    let mwm = Difficulty(16);
+    let mut first = transaction(1);
+    first.object.nonce = first.find_nonce(mwm);
+    assert!(mwm.is_met_by(first.object.pow_weight()));
+
+    // same parents, different transaction: the nonce proves nothing for it
+    let mut second = transaction(2);
+    second.object.nonce = first.object.nonce;
+    assert_eq!(second.object.trunk_transaction, first.object.trunk_transaction);
+    assert_eq!(second.object.branch_transaction, first.object.branch_transaction);
+    assert!(!mwm.is_met_by(second.object.pow_weight()));
+}
+
+#[test]
+fn merkle_proof_test() {
+    use model::merkle::{MerkleTree, MerkleProof};
+    use model::transaction::{Hash, HASH_NULL, HASH_SIZE};
+    use ntrumls::PublicKey;
+
+    let keys: Vec<PublicKey> = (0..5u8).map(|i| PublicKey(vec![i; 32])).collect();
+    let tree = MerkleTree::new(&keys, 4);
+    let root = tree.root();
+
+    for (i, pk) in keys.iter().enumerate() {
+        let proof = tree.proof(i as u32).expect("no proof");
+        assert_eq!(proof.path.len(), 4);
+        assert!(proof.verify(pk, &root));
+        assert_eq!(MerkleProof::from_payload(&proof.to_payload()), Some(proof.clone()));
+    }
+    assert!(tree.proof(5).is_none());
+
+    let mut proof = tree.proof(2).unwrap();
+    assert!(!proof.verify(&keys[3], &root));
+
+    proof.key_index = 3;
+    assert!(!proof.verify(&keys[2], &root));
+
+    proof.key_index = 2 + 16;
+    assert!(!proof.verify(&keys[2], &root));
+
+    let mut proof = tree.proof(2).unwrap();
+    proof.path[1] = Hash([1u8; HASH_SIZE]);
+    assert!(!proof.verify(&keys[2], &root));
+
+    let payload = tree.proof(1).unwrap().to_payload();
+    assert!(MerkleProof::from_payload(&payload[..payload.len() - 1]).is_none());
+    assert!(MerkleProof::from_payload(&[]).is_none());
+    assert!(root != HASH_NULL);
+}
+
+#[test]
+fn transition_weights_test() {
+    use model::tip_selection::{transition_weights, walk_approvers};
+
+    // equal ratings used to give infinite weights
+    let weights = transition_weights(&[7, 7, 7], 3.0);
+    assert!(weights.iter().all(|w| *w == 1.0));
+
+    let weights = transition_weights(&[0, 1000000], 1.0);
+    assert!(weights.iter().all(|w| w.is_finite()));
+    assert_eq!(weights[1], 1.0);
+    assert_eq!(walk_approvers(&[0, 1000000], 1.0, 0.0), Some(1));
+    assert_eq!(walk_approvers(&[0, 1000000], 1.0, 0.999), Some(1));
+
+    // alpha 0 is a uniform walk
+    assert_eq!(walk_approvers(&[1, 50, 100], 0.0, 0.1), Some(0));
+    assert_eq!(walk_approvers(&[1, 50, 100], 0.0, 0.5), Some(1));
+    assert_eq!(walk_approvers(&[1, 50, 100], 0.0, 0.9), Some(2));
+
+    assert_eq!(walk_approvers(&[], 1.0, 0.5), None);
+}
+
+#[test]
+fn weighted_walk_test() {
+    use model::{MilestoneObject, TransactionValidator};
+    use model::tip_selection::TipHeuristics;
+    use model::transaction::{Hash, HASH_NULL};
+    use model::walker::Walker;
+    use rand::{SeedableRng, StdRng};
+    use std::collections::{HashMap, HashSet, VecDeque};
+
+    let hive = make_am!(Hive::new());
+    let queue = make_am!(VecDeque::new());
+    let put = |i: u8, trunk: Hash| {
+        let hash = put_test_transaction(&hive, i, trunk, trunk, 0, 0);
+        queue.lock().unwrap().push_back(hash);
+        hash
+    };
+
+    // milestone 5 is approved by 1 and 2; 1 by the tips 3, 4 and 5 (a busy branch); 2 by the lazy tip 6
+    let milestone = put_test_transaction(&hive, 10, HASH_NULL, HASH_NULL, 5, 0);
+    let busy = put(1, milestone);
+    let lazy = put(2, milestone);
+    for i in 3..6 {
+        put(i, busy);
+    }
+    let lazy_tip = put(6, lazy);
+    TransactionValidator::update_cumulative_weights(&hive, &queue);
+    assert_eq!(hive.lock().unwrap().cumulative_weight(&busy), 4);
+    assert_eq!(hive.lock().unwrap().cumulative_weight(&lazy), 2);
+
+    let (m, lv, tv, _) = tip_selection_components(&hive, MilestoneObject::new(5, milestone));
+    let walker = Walker::new(hive.clone(), m, lv, tv, TipHeuristics::disabled());
+    let clock = walker.milestone_clock();
+    let walk = |alpha: f64, seed: usize| {
+        let mut rng: StdRng = SeedableRng::from_seed(&[seed][..]);
+        walker.random_walk(&HashSet::new(), &HashMap::new(), Some(milestone), None, 0, &mut HashSet::new(), clock,
+                           alpha, &mut rng, None).expect("walk failed").expect("walk found no tail")
+    };
+
+    // weights 4 and 2: with a large alpha the lazy branch is practically never taken, unweighted
+    // walks take it about every other time
+    assert!((0..20).all(|seed| walk(10.0, seed) != lazy_tip));
+    assert!((0..20).any(|seed| walk(0.0, seed) == lazy_tip));
+}
+
+#[test]
+fn seeded_tip_selection_test() {
+    use model::TipsViewModel;
+    use model::transaction::{Hash, HASH_SIZE};
+    use utils::{seeded_rng, random};
+    use rand::Rng;
+
+    let mut a = TipsViewModel::new(seeded_rng(Some(42), random::RNG_TIPS_VIEW_MODEL));
+    let mut b = TipsViewModel::new(seeded_rng(Some(42), random::RNG_TIPS_VIEW_MODEL));
+    for i in 0..20u8 {
+        a.add_tip(Hash([i; HASH_SIZE]));
+        b.add_tip(Hash([i; HASH_SIZE]));
+    }
+
+    let picks_a = (0..50).map(|_| a.get_random_tip()).collect::<Vec<Option<Hash>>>();
+    let picks_b = (0..50).map(|_| b.get_random_tip()).collect::<Vec<Option<Hash>>>();
+    assert!(picks_a == picks_b);
+
+    let mut tm = seeded_rng(Some(42), random::RNG_TIPS_MANAGER);
+    let mut tvm = seeded_rng(Some(42), random::RNG_TIPS_VIEW_MODEL);
+    assert!(tm.gen::<u64>() != tvm.gen::<u64>());
+}
+
+#[test]
+fn load_tips_test() {
+    use model::TipsViewModel;
+    use utils::{seeded_rng, random};
+
+    let mut hive = Hive::new();
+    hive.init();
+
+    let tips = hive.storage_load_tips();
+    assert!(!tips.is_empty());
+    for &(hash, _) in &tips {
+        assert!(hive.storage_load_approvee(&hash).map(|a| a.is_empty()).unwrap_or(true));
+    }
+
+    let mut tvm = TipsViewModel::new(seeded_rng(Some(1), random::RNG_TIPS_VIEW_MODEL));
+    tvm.load_tips(&tips);
+    assert_eq!(tvm.get_tips().len(), tips.len());
+    for &(hash, solid) in &tips {
+        assert_eq!(tvm.solid_tips.contains(&hash), solid);
+    }
+}
+
+#[test]
+fn tip_heuristics_test() {
+    use model::tip_selection::{TipHeuristics, TipScore, TipExclusion};
+
+    let heuristics = TipHeuristics {
+        max_age_ms: 60000,
+        max_milestone_lag: 3,
+        max_parent_lag_ms: 30000,
+    };
+    let score = |age_ms, milestone_lag, parent_lag_ms| TipScore { age_ms, milestone_lag, parent_lag_ms };
+
+    assert_eq!(heuristics.classify(&score(1000, 1, 1000)), None);
+    assert_eq!(heuristics.classify(&score(61000, 0, 0)), Some(TipExclusion::Lazy));
+    assert_eq!(heuristics.classify(&score(1000, 0, 31000)), Some(TipExclusion::Lazy));
+    assert_eq!(heuristics.classify(&score(1000, 4, 1000)), Some(TipExclusion::Parasitic));
+    // old parents explain the lag better than a side chain would
+    assert_eq!(heuristics.classify(&score(1000, 4, 31000)), Some(TipExclusion::Lazy));
+
+    assert_eq!(TipHeuristics::disabled().classify(&score(u64::max_value(), 100, u64::max_value())), None);
+}
+
+#[test]
+fn tip_selection_depth_test() {
+    use model::tip_selection::{entry_point_index, min_walk_index, check_depth};
+    use model::transaction_validator::TransactionError;
+
+    // young subhives used to underflow
+    assert_eq!(entry_point_index(3, 15), 0);
+    assert_eq!(min_walk_index(3, 15), 0);
+    assert_eq!(min_walk_index(5, u32::max_value()), 0);
+
+    assert_eq!(entry_point_index(100, 15), 85);
+    assert_eq!(min_walk_index(100, 15), 70);
+    assert_eq!(entry_point_index(100, 0), 100);
+
+    assert_eq!(check_depth(3, 15, false), Ok(3));
+    assert_eq!(check_depth(15, 15, false), Ok(15));
+    assert_eq!(check_depth(0, 15, true), Ok(0));
+    assert_eq!(check_depth(0, 15, false), Err(TransactionError::InvalidDepth { depth: 0, max_depth: 15 }));
+    assert_eq!(check_depth(16, 15, true), Ok(15));
+    assert_eq!(check_depth(u32::max_value(), 15, false), Ok(15));
+}
+
+#[test]
+fn check_reference_test() {
+    use model::{TipsManager, TransactionObject, TransactionMetadata, TransactionStatus};
+    use model::transaction::{Hash, HASH_SIZE, HASH_NULL};
+    use model::transaction_validator::TransactionError;
+    use storage::hive::CFType;
+
+    let mut hive = Hive::new();
+
+    // milestone 5 <- confirmed 2 <- solid <- not solid, plus a conflicting one on the milestone
+    let put = |hive: &mut Hive, i: u8, trunk: Hash, snapshot: u32, solid: bool| {
+        let mut t = TransactionObject::new_random();
+        t.hash = Hash([i; HASH_SIZE]);
+        t.trunk_transaction = trunk;
+        t.branch_transaction = trunk;
+        t.snapshot = snapshot;
+        t.solid = solid;
+        hive.storage_put(CFType::Transaction, &t.hash, &t);
+        hive.put_approvee(trunk, t.hash);
+        t.hash
+    };
+    let milestone = put(&mut hive, 1, HASH_NULL, 5, true);
+    let old = put(&mut hive, 2, milestone, 2, true);
+    let solid = put(&mut hive, 3, milestone, 0, true);
+    let pending = put(&mut hive, 4, solid, 0, false);
+    let conflicting = put(&mut hive, 5, milestone, 0, true);
+    let mut metadata = TransactionMetadata::new();
+    metadata.status = TransactionStatus::Conflicting;
+    hive.put_transaction_metadata(&conflicting, &metadata);
+
+    assert_eq!(TipsManager::check_reference(&hive, &solid, 4), Ok(()));
+    assert_eq!(TipsManager::check_reference(&hive, &milestone, 4), Ok(()));
+    assert_eq!(TipsManager::check_reference(&hive, &old, 4),
+               Err(TransactionError::InvalidReference(old, "confirmed below depth")));
+    assert_eq!(TipsManager::check_reference(&hive, &old, 2), Ok(()));
+    assert_eq!(TipsManager::check_reference(&hive, &pending, 4),
+               Err(TransactionError::InvalidReference(pending, "not solid")));
+    assert_eq!(TipsManager::check_reference(&hive, &conflicting, 4),
+               Err(TransactionError::InvalidReference(conflicting, "conflicting")));
+    assert_eq!(TipsManager::check_reference(&hive, &Hash([9u8; HASH_SIZE]), 4), Err(TransactionError::InvalidHash));
+}
+
+#[test]
+fn transaction_metadata_status_test() {
+    use model::{TransactionMetadata, TransactionStatus};
+    use model::transaction::{Hash, HASH_SIZE};
+
+    let mut hive = Hive::new();
+    let stored = |hive: &mut Hive, i: u8, status: TransactionStatus| {
+        let mut metadata = TransactionMetadata::new();
+        metadata.status = status;
+        metadata.approvers_count = i as u32;
+        hive.put_transaction_metadata(&Hash([i; HASH_SIZE]), &metadata);
+        hive.storage_load_transaction_metadata(&Hash([i; HASH_SIZE]))
+    };
+
+    assert_eq!(stored(&mut hive, 1, TransactionStatus::Confirmed(7)).status, TransactionStatus::Confirmed(7));
+    assert_eq!(stored(&mut hive, 2, TransactionStatus::Conflicting).status, TransactionStatus::Conflicting);
+    // derived statuses aren't stored
+    assert_eq!(stored(&mut hive, 3, TransactionStatus::Solid).status, TransactionStatus::Unknown);
+    assert_eq!(stored(&mut hive, 4, TransactionStatus::Orphaned).status, TransactionStatus::Unknown);
+    assert_eq!(stored(&mut hive, 4, TransactionStatus::Orphaned).approvers_count, 4);
+}
+
+#[test]
+fn interrupt_attaching_test() {
+    use model::{Transaction, TransactionObject};
+    use model::transaction::{Difficulty, Hash, HASH_SIZE};
+    use network::api::APIError;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    let object = |i: u8| {
+        let mut object = TransactionObject::new();
+        object.timestamp = i as u64;
+        object.hash = Transaction::from_object(object.clone()).calculate_hash();
+        vec![object]
+    };
+    let trunk = Hash([1u8; HASH_SIZE]);
+
+    // interrupting with nothing in progress doesn't affect the next attach
+    API::interrupt_attaching_to_tangle();
+    assert!(API::attach_to_tangle(trunk, trunk, Difficulty(1), object(1)).is_ok());
+
+    // every attach in progress is interrupted, not just the latest one
+    let (tx, rx) = channel();
+    for i in 2..4 {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let _ = tx.send(API::attach_to_tangle(trunk, trunk, Difficulty(200), object(i)));
+        });
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    let mut interrupted = 0;
+    while interrupted < 2 {
+        API::interrupt_attaching_to_tangle();
+        match rx.recv_timeout(Duration::from_millis(500)) {
+            Ok(Err(APIError::Interrupted)) => interrupted += 1,
+            Ok(_) => panic!("attach was not interrupted"),
+            Err(_) => {}
+        }
+    }
+}
+
+#[test]
+fn difficulty_policy_test() {
+    use model::difficulty_policy::{DifficultyPolicy, TransactionSource};
+    use model::transaction::Difficulty;
+    use std::time::{Duration, Instant};
+
+    let mut policy = DifficultyPolicy::new(Difficulty(8), Difficulty(12), 100, 20,
+                                           Duration::from_secs(60));
+    let now = Instant::now();
+    let neighbor = TransactionSource::Neighbor("10.0.0.2".parse().unwrap());
+
+    // base
+    assert_eq!(policy.current(0), Difficulty(8));
+    assert_eq!(policy.current(99), Difficulty(8));
+    assert_eq!(policy.required(&TransactionSource::Api, 0, now), Difficulty(8));
+    assert_eq!(policy.required(&neighbor, 0, now), Difficulty(8));
+
+    // congested queue: one bit per doubling past the threshold
+    assert_eq!(policy.current(100), Difficulty(9));
+    assert_eq!(policy.current(200), Difficulty(10));
+    assert_eq!(policy.current(400), Difficulty(11));
+    assert_eq!(policy.required(&TransactionSource::Api, 200, now), Difficulty(10));
+
+    // capped at the max MWM
+    assert_eq!(policy.current(100_000), Difficulty(12));
+
+    // API submissions above the rate pay for it
+    for _ in 0..40 {
+        policy.record(TransactionSource::Api, now);
+    }
+    assert_eq!(policy.recent_arrivals(&TransactionSource::Api, now), 40);
+    assert_eq!(policy.required(&TransactionSource::Api, 0, now), Difficulty(10));
+    assert_eq!(policy.required(&TransactionSource::Api, 100, now), Difficulty(11));
+    assert_eq!(policy.required(&TransactionSource::Api, 100_000, now), Difficulty(12));
+
+    // a neighbor relaying a lot only pays for the queue it fills
+    for _ in 0..40 {
+        policy.record(neighbor, now);
+    }
+    assert_eq!(policy.required(&neighbor, 0, now), Difficulty(8));
+    assert_eq!(policy.required(&neighbor, 200, now), Difficulty(10));
+
+    // arrivals expire with the window
+    let later = now + Duration::from_secs(61);
+    assert_eq!(policy.recent_arrivals(&TransactionSource::Api, later), 0);
+    assert_eq!(policy.required(&TransactionSource::Api, 0, later), Difficulty(8));
+
+    // max below base never lowers the base
+    let policy = DifficultyPolicy::new(Difficulty(14), Difficulty(12), 100, 20,
+                                       Duration::from_secs(60));
+    assert_eq!(policy.current(100_000), Difficulty(14));
+}
+
+#[test]
+fn synced_difficulty_test() {
+    use model::difficulty_policy::{DifficultyPolicy, TransactionSource};
+    use model::transaction::Difficulty;
+    use std::time::{Duration, Instant};
+
+    let policy = DifficultyPolicy::new(Difficulty(8), Difficulty(12), 100, 20, Duration::from_secs(60));
+    let peer = "10.0.0.1".parse().unwrap();
+
+    // synced transactions only have to reach the base MWM, however congested the node is
+    assert_eq!(policy.required(&TransactionSource::Sync(peer), 100_000, Instant::now()), Difficulty(8));
+    assert_eq!(policy.required(&TransactionSource::Neighbor(peer), 100_000, Instant::now()), Difficulty(12));
+}
+
+#[test]
+fn transaction_payload_test() {
+    use model::{Transaction, TransactionBuilder};
+    use model::transaction::{Difficulty, MAX_PAYLOAD_SIZE};
+    use model::transaction_validator::{self, TransactionError};
+    use network::packet::{SerializedBuffer, Serializable, get_serialized_object};
+    use ntrumls::{NTRUMLS, PQParamSetID};
+    use utils::FixedClock;
+
+    let ntrumls = NTRUMLS::with_param_set(PQParamSetID::Security269Bit);
+    let (sk, pk) = ntrumls.generate_keypair().expect("failed to generate keypair");
+    let now_ms = 1530000000000;
+    let builder = || TransactionBuilder::new()
+        .min_weight_magnitude(Difficulty(4))
+        .clock(Arc::new(FixedClock(now_ms)));
+
+    assert_eq!(builder().payload(vec![7u8; MAX_PAYLOAD_SIZE + 1]).build(&sk, &pk).err(),
+               Some(TransactionError::InvalidData));
+
+    let mut transaction = builder().payload(vec![7u8; MAX_PAYLOAD_SIZE]).build(&sk, &pk)
+        .expect("failed to build transaction");
+    transaction.object.payload.push(7);
+    assert_eq!(transaction_validator::validate(&mut transaction, Difficulty(4), 0, now_ms),
+               Err(TransactionError::InvalidData));
+
+    let transaction = builder().payload(vec![1, 2, 3]).build(&sk, &pk).expect("failed to build transaction");
+    let bytes = get_serialized_object(&transaction.object, true);
+    let read = |len: usize| {
+        let mut stream = SerializedBuffer::from_slice(&bytes.buffer[..len]);
+        let _ = stream.read_i32();
+        let mut transaction = Transaction::new();
+        transaction.read_params(&mut stream);
+        transaction
+    };
+
+    let mut complete = read(bytes.buffer.len());
+    assert!(!complete.malformed);
+    assert_eq!(complete.object.payload, vec![1, 2, 3]);
+    assert!(transaction_validator::validate(&mut complete, Difficulty(4), 0, now_ms).is_ok());
+
+    // a truncated payload is invalid, not an empty one
+    let mut truncated = read(bytes.buffer.len() - 4);
+    assert!(truncated.malformed);
+    assert_eq!(transaction_validator::validate(&mut truncated, Difficulty(4), 0, now_ms),
+               Err(TransactionError::InvalidData));
+}
+
+#[test]
+fn milestone_sync_test() {
+    use model::MilestoneSync;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    let peer: IpAddr = "10.0.0.1".parse().unwrap();
+    let other: IpAddr = "10.0.0.2".parse().unwrap();
+    let now = Instant::now();
+    let secs = |s: u64| now + Duration::from_secs(s);
+    let mut sync = MilestoneSync::with_timeouts(Duration::from_secs(10), Duration::from_secs(120),
+                                                Duration::from_secs(30));
+
+    // behind the latest known milestone: one request at a time, resent only after a timeout
+    assert_eq!(sync.next_request(5, 2, now), Some((3, 0)));
+    assert!(sync.syncing);
+    sync.on_request_sent(3, 0, peer, now);
+    assert_eq!(sync.next_request(5, 2, secs(1)), None);
+    assert_eq!(sync.next_request(5, 2, secs(10)), Some((3, 0)));
+    sync.on_request_sent(3, 0, peer, secs(10));
+
+    // unsolicited subhives and answers from another peer or to another request are gossip
+    assert!(!sync.on_subhive(4, 0, peer, 1000, 10, true, secs(11)));
+    assert!(!sync.on_subhive(3, 0, other, 1000, 10, true, secs(11)));
+    assert_eq!(sync.target_index(), None);
+    assert_eq!(sync.in_flight(), Some((3, 0, peer)));
+
+    // a truncated answer is continued from where it stopped
+    assert!(sync.on_subhive(3, 0, peer, 20, 500, false, secs(11)));
+    assert!(!sync.on_subhive(3, 0, peer, 20, 500, false, secs(11)));
+    assert_eq!(sync.in_flight(), None);
+    assert_eq!(sync.target_index(), Some(20));
+    assert_eq!(sync.next_request(5, 2, secs(12)), Some((3, 500)));
+    sync.on_request_sent(3, 500, peer, secs(12));
+    assert!(sync.on_subhive(3, 500, peer, 20, 120, true, secs(13)));
+
+    // a milestone received whole but not solid yet is asked for again after a growing back-off
+    assert_eq!(sync.next_request(5, 2, secs(14)), None);
+    assert_eq!(sync.next_request(5, 2, secs(23)), Some((3, 0)));
+    sync.on_request_sent(3, 0, peer, secs(23));
+    assert!(sync.on_subhive(3, 0, peer, 20, 10, true, secs(24)));
+    assert_eq!(sync.next_request(5, 2, secs(34)), None);
+    assert_eq!(sync.next_request(5, 2, secs(44)), Some((3, 0)));
+    sync.on_request_sent(3, 0, peer, secs(44));
+    assert!(sync.on_subhive(3, 0, peer, 20, 10, true, secs(45)));
+
+    // the claimed target keeps the node syncing past its own latest milestone, but expires
+    assert_eq!(sync.next_request(5, 5, secs(46)), Some((6, 0)));
+    sync.on_request_sent(6, 0, peer, secs(46));
+    assert_eq!(sync.next_request(5, 5, secs(200)), Some((6, 0)));
+    assert_eq!(sync.target_index(), None);
+    assert!(!sync.syncing);
+
+    // a peer that can't send the milestone it claims to be at loses its claim
+    sync.on_request_sent(6, 0, peer, secs(200));
+    assert!(sync.on_subhive(6, 0, peer, 1000, 10, true, secs(201)));
+    assert_eq!(sync.target_index(), Some(1000));
+    assert_eq!(sync.next_request(5, 6, secs(202)), Some((7, 0)));
+    sync.on_request_sent(7, 0, peer, secs(202));
+    assert!(sync.on_subhive(7, 0, peer, 1000, 0, true, secs(203)));
+    assert_eq!(sync.target_index(), None);
+
+    // synced: the network's latest index is only probed now and then
+    assert_eq!(sync.next_request(6, 6, secs(204)), None);
+    assert_eq!(sync.next_request(6, 6, secs(230)), Some((7, 0)));
+    sync.on_request_sent(7, 0, peer, secs(230));
+    assert!(sync.on_subhive(7, 0, peer, 6, 0, true, secs(231)));
+    assert_eq!(sync.next_request(6, 6, secs(232)), None);
+    assert_eq!(sync.next_request(6, 6, secs(260)), Some((7, 0)));
+
+    // and less often while nobody has the next milestone
+    sync.on_request_sent(7, 0, peer, secs(260));
+    assert!(sync.on_subhive(7, 0, peer, 6, 0, true, secs(261)));
+    assert_eq!(sync.next_request(6, 6, secs(291)), None);
+    assert_eq!(sync.next_request(6, 6, secs(301)), Some((7, 0)));
+}
+
+#[test]
+fn milestone_request_budget_test() {
+    use network::neighbor::{Neighbor, MAX_MILESTONE_REQUESTS, MILESTONE_REQUEST_WINDOW};
+    use std::time::{Duration, Instant};
+
+    let mut neighbor = Neighbor::from_address("10.0.0.1:44832".parse().unwrap());
+    let now = Instant::now();
+    for _ in 0..MAX_MILESTONE_REQUESTS {
+        assert!(neighbor.take_milestone_request(now));
+    }
+    assert!(!neighbor.take_milestone_request(now + Duration::from_secs(1)));
+    assert!(neighbor.take_milestone_request(now + Duration::from_secs(MILESTONE_REQUEST_WINDOW)));
+
+    // every neighbor has a budget of its own
+    let mut other = Neighbor::from_address("10.0.0.2:44832".parse().unwrap());
+    assert!(other.take_milestone_request(now));
+}
+
+#[test]
+fn milestone_subhive_test() {
+    use model::{MilestoneObject, TransactionObject};
+    use model::transaction::{Hash, HASH_SIZE, HASH_NULL};
+    use storage::hive::CFType;
+
+    let mut hive = Hive::new();
+
+    // milestone #7 <- 6 <- 5 <- 4 <- 3 <- 2 <- 1, where 2 and 1 are confirmed by milestone #6
+    let mut trunk = HASH_NULL;
+    for i in 1..8u8 {
+        let mut t = TransactionObject::new_random();
+        t.hash = Hash([i; HASH_SIZE]);
+        t.trunk_transaction = trunk;
+        t.branch_transaction = trunk;
+        t.snapshot = if i <= 2 { 6 } else { 0 };
+        hive.storage_put(CFType::Transaction, &t.hash, &t);
+        trunk = t.hash;
+    }
+    hive.put_milestone(&MilestoneObject { index: 7, hash: trunk });
+
+    let hashes = |page: Vec<TransactionObject>| page.iter().map(|t| t.hash.0[0]).collect::<Vec<u8>>();
+
+    let (page, complete) = hive.load_milestone_subhive(7, 0, 10).unwrap();
+    assert_eq!(hashes(page), vec![3, 4, 5, 6, 7]);
+    assert!(complete);
+
+    let (page, complete) = hive.load_milestone_subhive(7, 0, 2).unwrap();
+    assert_eq!(hashes(page), vec![3, 4]);
+    assert!(!complete);
+    let (page, complete) = hive.load_milestone_subhive(7, 2, 2).unwrap();
+    assert_eq!(hashes(page), vec![5, 6]);
+    assert!(!complete);
+    let (page, complete) = hive.load_milestone_subhive(7, 4, 2).unwrap();
+    assert_eq!(hashes(page), vec![7]);
+    assert!(complete);
+
+    assert!(hive.load_milestone_subhive(8, 0, 10).is_none());
+}
+
+#[test]
+fn conflicting_spends_test() {
+    use model::LedgerValidator;
+    use model::transaction::{Hash, HASH_SIZE};
+
+    let h = |i: u8| Hash([i; HASH_SIZE]);
+
+    // 10 available: the first spend attached is kept, the later double spend conflicts
+    assert_eq!(LedgerValidator::conflicting_spends(vec![(200, h(2), 10), (100, h(1), 10)], -10), vec![h(2)]);
+
+    // 30 available: 10 and 15 are covered, 20 isn't but the 5 after it still is
+    let spends = vec![(100, h(1), 10), (200, h(2), 15), (300, h(3), 20), (400, h(4), 5)];
+    assert_eq!(LedgerValidator::conflicting_spends(spends, -20), vec![h(3)]);
+
+    // nothing available: every spend conflicts, ties are broken by hash
+    let spends = vec![(100, h(2), 10), (100, h(1), 10)];
+    assert_eq!(LedgerValidator::conflicting_spends(spends, -20), vec![h(1), h(2)]);
+}
+
+#[test]
+fn replay_test() {
+    use model::{Transaction, TransactionBuilder, TransactionStatus};
+    use model::transaction::{Difficulty, Hash, HASH_SIZE};
+    use model::transaction_validator::{self, TransactionError};
+    use ntrumls::{NTRUMLS, PQParamSetID};
+    use utils::{Clock, FixedClock, SystemClock};
+
+    let ntrumls = NTRUMLS::with_param_set(PQParamSetID::Security269Bit);
+    let (sk, pk) = ntrumls.generate_keypair().expect("failed to generate keypair");
+
+    // attached long ago on parents that are below max depth by now
+    let old = Hash([1u8; HASH_SIZE]);
+    let stuck = TransactionBuilder::new()
+        .parents(old, old)
+        .min_weight_magnitude(Difficulty(1))
+        .clock(Arc::new(FixedClock(1530000000000)))
+        .build(&sk, &pk)
+        .expect("failed to build transaction");
+    let mut hive = Hive::new();
+    assert!(hive.put_transaction(&stuck));
+
+    assert_eq!(API::check_replayable(TransactionStatus::Orphaned), Ok(()));
+    assert_eq!(API::check_replayable(TransactionStatus::Pending), Ok(()));
+    assert_eq!(API::check_replayable(TransactionStatus::Confirmed(3)),
+               Err(TransactionError::NotReplayable(TransactionStatus::Confirmed(3))));
+    assert_eq!(API::check_replayable(TransactionStatus::Conflicting),
+               Err(TransactionError::NotReplayable(TransactionStatus::Conflicting)));
+
+    let (trunk, branch) = (Hash([2u8; HASH_SIZE]), Hash([3u8; HASH_SIZE]));
+    let attached = API::attach_to_tangle(trunk, branch, Difficulty(1), vec![stuck.object.clone()])
+        .expect("failed to replay");
+    let mut replayed = Transaction::from_object(attached[0].clone());
+
+    // new parents and a new hash, the signature still holds
+    assert_eq!(replayed.object.trunk_transaction, trunk);
+    assert_eq!(replayed.object.branch_transaction, branch);
+    assert!(replayed.get_hash() != stuck.get_hash());
+    assert_eq!(replayed.object.signature, stuck.object.signature);
+    assert!(transaction_validator::validate(&mut replayed, Difficulty(1), 0, SystemClock.now_ms()).is_ok());
+
+    // stored next to the original attachment
+    assert!(hive.put_transaction(&replayed));
+    assert!(hive.exists_transaction(stuck.get_hash()));
+    assert!(hive.exists_transaction(replayed.get_hash()));
+}
+
+#[test]
+fn promote_test() {
+    use model::TransactionStatus;
+    use model::transaction::{Address, Difficulty, Hash, HASH_SIZE};
+    use model::transaction_validator::{self, TransactionError};
+    use ntrumls::{NTRUMLS, PQParamSetID};
+    use utils::{Clock, SystemClock};
+
+    let ntrumls = NTRUMLS::with_param_set(PQParamSetID::Security269Bit);
+    let (sk, pk) = ntrumls.generate_keypair().expect("failed to generate keypair");
+
+    assert_eq!(API::check_promotable(TransactionStatus::Solid), Ok(()));
+    for &status in [TransactionStatus::Unknown, TransactionStatus::Pending, TransactionStatus::Confirmed(2),
+        TransactionStatus::Conflicting, TransactionStatus::Orphaned].iter() {
+        assert_eq!(API::check_promotable(status), Err(TransactionError::NotPromotable(status)));
+    }
+
+    let (stuck, tip) = (Hash([1u8; HASH_SIZE]), Hash([2u8; HASH_SIZE]));
+    let mut promotion = API::promotion(stuck, tip, Difficulty(4), &sk, &pk).expect("failed to promote");
+    assert_eq!(promotion.object.trunk_transaction, stuck);
+    assert_eq!(promotion.object.branch_transaction, tip);
+    assert_eq!(promotion.object.value, 0);
+    assert_eq!(promotion.object.address, Address::from_public_key(&pk));
+    assert!(transaction_validator::validate(&mut promotion, Difficulty(4), 0, SystemClock.now_ms()).is_ok());
+}
+
+#[test]
+fn cumulative_weights_test() {
+    use model::TransactionValidator;
+    use utils::AM;
+    use model::transaction::{Hash, HASH_SIZE, HASH_NULL};
+
+    // 1 <- 2 <- 3 <- 4, 4 also approves 2
+    let put = |hive: &AM<Hive>, queue: &AM<VecDeque<Hash>>, i: u8, trunk: Hash, branch: Hash| {
+        let hash = put_test_transaction(hive, i, trunk, branch, 0, 0);
+        queue.lock().unwrap().push_back(hash);
+    };
+    let h = |i: u8| Hash([i; HASH_SIZE]);
+    let weights = |hive: &AM<Hive>| {
+        let hive = hive.lock().unwrap();
+        (1..5).map(|i| hive.cumulative_weight(&h(i))).collect::<Vec<i64>>()
+    };
+
+    let in_order = make_am!(Hive::new());
+    let queue = make_am!(VecDeque::new());
+    put(&in_order, &queue, 1, HASH_NULL, HASH_NULL);
+    TransactionValidator::update_cumulative_weights(&in_order, &queue);
+    put(&in_order, &queue, 2, h(1), h(1));
+    TransactionValidator::update_cumulative_weights(&in_order, &queue);
+    put(&in_order, &queue, 3, h(2), h(1));
+    put(&in_order, &queue, 4, h(3), h(2));
+    TransactionValidator::update_cumulative_weights(&in_order, &queue);
+    assert_eq!(weights(&in_order), vec![4, 3, 2, 1]);
+    // both use the database of this thread, which only one hive can have open
+    drop(in_order);
+
+    // approvers stored before the transactions they approve
+    let out_of_order = make_am!(Hive::new());
+    put(&out_of_order, &queue, 4, h(3), h(2));
+    TransactionValidator::update_cumulative_weights(&out_of_order, &queue);
+    put(&out_of_order, &queue, 3, h(2), h(1));
+    TransactionValidator::update_cumulative_weights(&out_of_order, &queue);
+    put(&out_of_order, &queue, 1, HASH_NULL, HASH_NULL);
+    put(&out_of_order, &queue, 2, h(1), h(1));
+    TransactionValidator::update_cumulative_weights(&out_of_order, &queue);
+    assert_eq!(weights(&out_of_order), vec![4, 3, 2, 1]);
+}
+
+// Stores a solid transaction `i` without value approving `trunk` and `branch`, confirmed by
+// milestone `snapshot` unless 0 and attached at `attached` ms
+#[cfg(test)]
+fn put_test_transaction(hive: &utils::AM<Hive>, i: u8, trunk: model::transaction::Hash,
+                        branch: model::transaction::Hash, snapshot: u32, attached: u64) -> model::transaction::Hash {
+    use model::TransactionObject;
+    use model::transaction::{Hash, HASH_SIZE};
+
+    let mut t = TransactionObject::new_random();
+    t.hash = Hash([i; HASH_SIZE]);
+    t.trunk_transaction = trunk;
+    t.branch_transaction = branch;
+    t.snapshot = snapshot;
+    t.solid = true;
+    t.value = 0;
+    t.attachment_timestamp = attached;
+
+    let mut hive = hive.lock().unwrap();
+    hive.storage_put(CFType::Transaction, &t.hash, &t);
+    hive.put_approvee(trunk, t.hash);
+    if branch != trunk {
+        hive.put_approvee(branch, t.hash);
+    }
+    t.hash
+}
+
+// Components tip selection needs over `hive`, without the tracker threads the node starts, with the
+// latest solid milestone set to `milestone`
+#[cfg(test)]
+fn tip_selection_components(hive: &utils::AM<Hive>, milestone: model::MilestoneObject)
+    -> (utils::AM<model::Milestone>, utils::AM<model::LedgerValidator>, utils::AM<model::TransactionValidator>,
+        utils::AM<model::TipsViewModel>) {
+    use model::{LedgerValidator, Milestone, Snapshot, TipsViewModel, TransactionRequester, TransactionValidator};
+    use model::transaction::ADDRESS_NULL;
+    use utils::{seeded_rng, random};
+    use std::collections::HashMap;
+
+    let tips_view_model = make_am!(TipsViewModel::new(seeded_rng(Some(1), random::RNG_TIPS_VIEW_MODEL)));
+    let transaction_requester = make_am!(TransactionRequester::new(hive.clone(), 0.001,
+        seeded_rng(Some(1), random::RNG_TRANSACTION_REQUESTER)));
+    let transaction_validator = TransactionValidator::new(hive.clone(), tips_view_model.clone(), 0,
+                                                          transaction_requester.clone());
+    let snapshot = Snapshot { state: HashMap::new(), index: milestone.index };
+    let m = Milestone::new(hive.clone(), ADDRESS_NULL, snapshot, transaction_validator.clone(), true, 0,
+                           milestone.index, true);
+    {
+        let mut m = m.lock().unwrap();
+        m.latest_solid_subhive_milestone_index = milestone.index;
+        m.latest_solid_subhive_milestone = milestone.hash;
+        m.latest_milestone_index = milestone.index;
+        m.latest_milestone = milestone.hash;
+    }
+    hive.lock().unwrap().put_milestone(&milestone);
+    let ledger_validator = make_am!(LedgerValidator::new(hive.clone(), m.clone(), transaction_requester));
+
+    (m, ledger_validator, transaction_validator, tips_view_model)
+}
+
+#[test]
+fn milestone_before_trunk_test() {
+    use model::{MilestoneObject, TransactionObject};
+    use model::milestone::Validity;
+    use model::transaction::{Hash, HASH_NULL, HASH_SIZE, ADDRESS_NULL};
+    use storage::hive::CFType;
+
+    let hive = make_am!(Hive::new());
+    let genesis = put_test_transaction(&hive, 1, HASH_NULL, HASH_NULL, 1, 0);
+    let (m, _, _, _) = tip_selection_components(&hive, MilestoneObject::new(1, genesis));
+    let notifier = m.lock().unwrap().notifier();
+
+    // milestone #2 arrives before its trunk
+    let trunk = Hash([2u8; HASH_SIZE]);
+    let mut object = TransactionObject::new_random();
+    object.hash = Hash([3u8; HASH_SIZE]);
+    object.address = ADDRESS_NULL;
+    object.trunk_transaction = trunk;
+    object.branch_transaction = genesis;
+    object.tag.0[HASH_SIZE - 1] = 2;
+    hive.lock().unwrap().storage_put(CFType::Transaction, &object.hash, &object);
+    let candidate = hive.lock().unwrap().storage_load_transaction(&object.hash).expect("candidate not stored");
+
+    let mut milestone = m.lock().unwrap();
+    assert_eq!(milestone.validate_milestone(&candidate, 2), Validity::Incomplete);
+    milestone.analyze_candidate(object.hash);
+    assert_eq!(milestone.latest_milestone_index, 1);
+    assert!(milestone.take_queued_candidates().is_empty());
+
+    // storing the trunk queues the candidate again
+    put_test_transaction(&hive, 2, genesis, genesis, 0, 0);
+    let stored = hive.lock().unwrap().storage_load_transaction(&trunk).expect("trunk not stored");
+    notifier.on_transaction_stored(&stored, false);
+    let queued = milestone.take_queued_candidates();
+    assert_eq!(queued, vec![object.hash]);
+    for hash in queued {
+        milestone.analyze_candidate(hash);
+    }
+    assert_eq!(milestone.latest_milestone_index, 2);
+    assert_eq!(milestone.latest_milestone, object.hash);
+}
+
+#[test]
+fn tip_score_test() {
+    use model::MilestoneObject;
+    use model::tip_selection::{TipHeuristics, TipScore};
+    use model::transaction::{Hash, HASH_SIZE, HASH_NULL};
+    use model::walker::Walker;
+
+    let hive = make_am!(Hive::new());
+    let put = |i: u8, trunk: Hash, snapshot: u32, attached: u64| put_test_transaction(&hive, i, trunk, trunk, snapshot, attached);
+
+    // old confirmed 2 <- milestone 5, attached at 1000s
+    let old = put(1, HASH_NULL, 2, 50000);
+    let milestone = put(2, old, 5, 1000000);
+    let fresh = put(3, milestone, 0, 1000500);
+    let lazy = put(4, old, 0, 100000);
+    // attached long after the milestone on a quiet network, on a parent from just before it
+    let unconfirmed = put(5, milestone, 0, 900000);
+    let quiet = put(6, unconfirmed, 0, 2000000);
+
+    let (m, lv, tv, _) = tip_selection_components(&hive, MilestoneObject::new(5, milestone));
+    let walker = Walker::new(hive.clone(), m, lv, tv, TipHeuristics::disabled());
+    let score = |age_ms, milestone_lag, parent_lag_ms| Some(TipScore { age_ms, milestone_lag, parent_lag_ms });
+
+    assert_eq!(walker.tip_score(&fresh, 5, 1000000), score(0, 0, 0));
+    assert_eq!(walker.tip_score(&lazy, 5, 1000000), score(900000, 3, 50000));
+    assert_eq!(walker.tip_score(&quiet, 5, 1000000), score(0, 0, 100000));
+    assert_eq!(walker.tip_score(&unconfirmed, 5, 1000000), score(100000, 0, 0));
+    // unknown milestone time: only the milestone lag is measured
+    assert_eq!(walker.tip_score(&lazy, 5, 0), score(0, 3, 0));
+    assert_eq!(walker.tip_score(&milestone, 5, 1000000), None);
+    assert_eq!(walker.tip_score(&Hash([9u8; HASH_SIZE]), 5, 1000000), None);
+
+    let heuristics = TipHeuristics { max_age_ms: 3600000, max_milestone_lag: 5, max_parent_lag_ms: 1800000 };
+    let (m, lv, tv, _) = tip_selection_components(&hive, MilestoneObject::new(5, milestone));
+    let walker = Walker::new(hive.clone(), m, lv, tv, heuristics);
+    let clock = walker.milestone_clock();
+    assert_eq!(clock.index, 5);
+    assert_eq!(walker.tip_exclusion(&quiet, clock), None);
+    assert_eq!(walker.tip_exclusion(&fresh, clock), None);
+}
+
+#[test]
+fn walk_exclusion_test() {
+    use model::MilestoneObject;
+    use model::tip_selection::{TipHeuristics, TipExclusion, StopReason, WalkTrace};
+    use model::transaction::{Hash, HASH_NULL};
+    use model::walker::Walker;
+    use utils::{seeded_rng, random};
+    use std::collections::{HashMap, HashSet};
+
+    let hive = make_am!(Hive::new());
+    let put = |i: u8, trunk: Hash, snapshot: u32| put_test_transaction(&hive, i, trunk, trunk, snapshot, 0);
+
+    // confirmed 2 <- milestone 5 <- tip, and confirmed 2 <- unconfirmed <- parasitic tip
+    let old = put(1, HASH_NULL, 2);
+    let milestone = put(2, old, 5);
+    let tip = put(3, milestone, 0);
+    let side = put(4, old, 0);
+    let parasitic = put(5, side, 0);
+
+    let (m, lv, tv, _) = tip_selection_components(&hive, MilestoneObject::new(5, milestone));
+    let heuristics = TipHeuristics { max_age_ms: 0, max_milestone_lag: 1, max_parent_lag_ms: 0 };
+    let walker = Walker::new(hive.clone(), m, lv, tv, heuristics);
+    let clock = walker.milestone_clock();
+    let mut rng = seeded_rng(Some(1), random::RNG_TIPS_MANAGER);
+    let mut walk = |start: Hash| {
+        let mut trace = WalkTrace::new();
+        let tail = walker.random_walk(&HashSet::new(), &HashMap::new(), Some(start), None, 0,
+                                      &mut HashSet::new(), clock, 0.0, &mut rng, Some(&mut trace))
+            .expect("walk failed");
+        assert_eq!(tail, trace.tail);
+        trace
+    };
+
+    let trace = walk(milestone);
+    assert_eq!(trace.tail, Some(tip));
+    assert_eq!(trace.stop_reason, Some(StopReason::Tip));
+    assert!(trace.path.iter().all(|step| step.excluded.is_empty()));
+    assert_eq!(walker.excluded_tips(), (0, 0));
+
+    // the walk is stuck on the parent of the parasitic tip, not on a tip
+    let trace = walk(side);
+    assert_eq!(trace.tail, Some(side));
+    assert_eq!(trace.stop_reason, Some(StopReason::ExcludedTips));
+    assert_eq!(trace.path.len(), 1);
+    assert!(trace.path[0].approvers.is_empty());
+    assert_eq!(trace.path[0].excluded, vec![(parasitic, TipExclusion::Parasitic)]);
+    assert_eq!(walker.excluded_tips(), (0, 1));
+}
+
+#[test]
+fn entry_point_test() {
+    use model::{MilestoneObject, TipsManager};
+    use model::tip_selection::{SelectionTrace, TipHeuristics, UniformRandom, UnweightedWalk, TipSelector, DEFAULT_ALPHA};
+    use model::transaction::{Hash, HASH_NULL};
+    use model::transaction_validator::TransactionError;
+    use utils::{seeded_rng, random};
+    use std::collections::{HashMap, HashSet};
+
+    let hive = make_am!(Hive::new());
+    let put = |i: u8, trunk: Hash, snapshot: u32| put_test_transaction(&hive, i, trunk, trunk, snapshot, 0);
+
+    // milestones 1 to 5 in a chain, a reference on milestone 3 with a single approver
+    let mut milestones = vec![HASH_NULL];
+    for i in 1..6 {
+        let parent = milestones[i as usize - 1];
+        let hash = put(i, parent, i as u32);
+        hive.lock().unwrap().put_milestone(&MilestoneObject::new(i as u32, hash));
+        milestones.push(hash);
+    }
+    let reference = put(10, milestones[3], 0);
+    let approver = put(11, reference, 0);
+    put(12, milestones[5], 0);
+
+    let (m, lv, tv, tvm) = tip_selection_components(&hive, MilestoneObject::new(5, milestones[5]));
+    let tm = TipsManager::new(hive.clone(), m, lv, tv, tvm, 3, true, 0, Box::new(UnweightedWalk), DEFAULT_ALPHA,
+                              seeded_rng(Some(1), random::RNG_TIPS_MANAGER), TipHeuristics::disabled(), 1);
+    let tm = tm.lock().unwrap();
+    let select = |reference: Option<Hash>, depth: u32, selector: Option<&TipSelector>| {
+        let mut trace = SelectionTrace::new();
+        tm.transaction_to_approve(&mut HashSet::new(), &mut HashMap::new(), reference, None, depth, 1, selector,
+                                  Some(&mut trace))
+            .map(|tail| (tail, trace.entry_point))
+    };
+
+    assert_eq!(select(None, 2, None).map(|(_, entry_point)| entry_point), Ok(milestones[3]));
+    assert_eq!(select(None, 1, None).map(|(_, entry_point)| entry_point), Ok(milestones[4]));
+    // capped to the max depth of 3
+    assert_eq!(select(None, 20, None).map(|(_, entry_point)| entry_point), Ok(milestones[2]));
+    assert_eq!(select(None, 0, None), Err(TransactionError::InvalidDepth { depth: 0, max_depth: 3 }));
+
+    // the walk starts at the reference and ends on its only descendant
+    assert_eq!(select(Some(reference), 2, None), Ok((Some(approver), reference)));
+    assert_eq!(select(Some(reference), 3, None), Ok((Some(approver), reference)));
+    assert_eq!(select(Some(reference), 2, Some(&UniformRandom as &TipSelector)),
+               Err(TransactionError::InvalidReference(reference, "ignored by the uniform strategy")));
+}
+
+#[test]
+fn check_consistency_test() {
+    use model::{MilestoneObject, TipsManager, TransactionBuilder, TransactionObject};
+    use model::tip_selection::{TipHeuristics, UnweightedWalk, DEFAULT_ALPHA};
+    use model::transaction::{Address, Difficulty, Hash, HASH_NULL, HASH_SIZE, ADDRESS_SIZE};
+    use storage::hive::CFType;
+    use utils::{seeded_rng, random};
+    use ntrumls::{NTRUMLS, PQParamSetID};
+
+    let ntrumls = NTRUMLS::with_param_set(PQParamSetID::Security269Bit);
+    let (sk, pk) = ntrumls.generate_keypair().expect("failed to generate keypair");
+    let sender = Address::from_public_key(&pk);
+
+    let hive = make_am!(Hive::new());
+    let put = |i: u8, trunk: Hash, snapshot: u32| put_test_transaction(&hive, i, trunk, trunk, snapshot, 0);
+
+    // confirmed 1 <- milestone 10 <- solid, and confirmed 1 <- orphaned
+    let old = put(1, HASH_NULL, 1);
+    let milestone = put(2, old, 10);
+    let solid = put(3, milestone, 0);
+    let orphaned = put(4, old, 0);
+    let missing = Hash([9u8; HASH_SIZE]);
+    let mut pending = TransactionObject::new_random();
+    pending.hash = Hash([5u8; HASH_SIZE]);
+    pending.trunk_transaction = missing;
+    pending.branch_transaction = missing;
+    hive.lock().unwrap().storage_put(CFType::Transaction, &pending.hash, &pending);
+
+    // two transfers of 4 each from a balance of 5
+    let transfer = |recipient: u8| {
+        let mut object = TransactionBuilder::new()
+            .recipient(Address([recipient; ADDRESS_SIZE]))
+            .value(4)
+            .parents(milestone, milestone)
+            .min_weight_magnitude(Difficulty(1))
+            .build(&sk, &pk)
+            .expect("failed to build transaction")
+            .object;
+        object.solid = true;
+        let mut hive = hive.lock().unwrap();
+        hive.storage_put(CFType::Transaction, &object.hash, &object);
+        hive.put_approvee(milestone, object.hash);
+        object.hash
+    };
+    let first = transfer(7);
+    let second = transfer(8);
+
+    let (m, lv, tv, tvm) = tip_selection_components(&hive, MilestoneObject::new(10, milestone));
+    m.lock().unwrap().latest_snapshot.state.insert(sender, 5);
+    // max depth 3: anything approving a milestone before #4 is orphaned
+    let tm = TipsManager::new(hive.clone(), m, lv.clone(), tv, tvm, 3, true, 0, Box::new(UnweightedWalk),
+                              DEFAULT_ALPHA, seeded_rng(Some(1), random::RNG_TIPS_MANAGER), TipHeuristics::disabled(), 1);
+    let check = |hashes: Vec<Hash>| API::check_consistency(&lv, &tm, &hashes).expect("check failed");
+
+    let result = check(vec![solid, missing]);
+    assert!(!result.consistent);
+    assert_eq!(result.reason, format!("transaction is missing: {:?}", missing));
+    assert_eq!(result.states.iter().map(|s| s.consistent).collect::<Vec<bool>>(), vec![true, false]);
+
+    let result = check(vec![pending.hash, solid]);
+    assert_eq!(result.reason, format!("transaction is not solid: {:?}", pending.hash));
+
+    let result = check(vec![solid, orphaned]);
+    assert_eq!(result.reason, format!("transaction is below max depth: {:?}", orphaned));
+    assert_eq!(result.states[1].status, "orphaned");
+
+    // each transfer alone is fine, together they overspend
+    assert!(check(vec![first]).consistent);
+    assert!(check(vec![second]).consistent);
+    let result = check(vec![first, second]);
+    assert!(!result.consistent);
+    assert_eq!(result.reason, "inconsistent ledger");
+    assert!(result.states.iter().all(|s| s.consistent));
+
+    let result = check(vec![solid, milestone, first]);
+    assert!(result.consistent);
+    assert_eq!(result.reason, "");
+    assert_eq!(result.states.len(), 3);
+}
+
+#[test]
+fn orphaned_conflict_test() {
+    use model::{MilestoneObject, Snapshot, TipsManager, TransactionBuilder, TransactionStatus};
+    use model::tip_selection::{TipHeuristics, UnweightedWalk, DEFAULT_ALPHA};
+    use model::transaction::{Address, Difficulty, HASH_NULL, ADDRESS_SIZE};
+    use storage::hive::CFType;
+    use utils::{seeded_rng, random};
+    use ntrumls::{NTRUMLS, PQParamSetID};
+    use std::collections::HashMap;
+
+    let ntrumls = NTRUMLS::with_param_set(PQParamSetID::Security269Bit);
+    let (sk, pk) = ntrumls.generate_keypair().expect("failed to generate keypair");
+    let sender = Address::from_public_key(&pk);
+
+    let hive = make_am!(Hive::new());
+    let genesis = put_test_transaction(&hive, 1, HASH_NULL, HASH_NULL, 1, 0);
+
+    // two transfers of 4 each from a balance of 5, the second attached later
+    let transfer = |recipient: u8, attached: u64| {
+        let mut object = TransactionBuilder::new()
+            .recipient(Address([recipient; ADDRESS_SIZE]))
+            .value(4)
+            .parents(genesis, genesis)
+            .min_weight_magnitude(Difficulty(1))
+            .build(&sk, &pk)
+            .expect("failed to build transaction")
+            .object;
+        object.solid = true;
+        object.attachment_timestamp = attached;
+        let mut hive = hive.lock().unwrap();
+        hive.storage_put(CFType::Transaction, &object.hash, &object);
+        hive.put_approvee(genesis, object.hash);
+        object.hash
+    };
+    let first = transfer(7, 100);
+    let second = transfer(8, 200);
+
+    let (m, lv, tv, tvm) = tip_selection_components(&hive, MilestoneObject::new(1, genesis));
+    let mut state = HashMap::new();
+    state.insert(sender, 5);
+    let mut snapshot = Snapshot { state, index: 1 };
+
+    // a milestone approving both can't be confirmed, the next one leaves the second spend out
+    let both = put_test_transaction(&hive, 2, first, second, 0, 0);
+    let only_first = put_test_transaction(&hive, 3, first, first, 0, 0);
+    {
+        let mut lv = lv.lock().unwrap();
+        assert_eq!(lv.update_snapshot(&MilestoneObject::new(2, both), &mut snapshot), Ok(false));
+        assert_eq!(lv.marked_spends(), vec![second]);
+        assert_eq!(lv.update_snapshot(&MilestoneObject::new(2, only_first), &mut snapshot), Ok(true));
+    }
+    assert!(hive.lock().unwrap().is_conflicting(&second));
+
+    // max depth 1: anything approving a milestone before #2 is orphaned once #4 is solid
+    let tm = TipsManager::new(hive.clone(), m.clone(), lv.clone(), tv, tvm, 1, true, 0, Box::new(UnweightedWalk),
+                              DEFAULT_ALPHA, seeded_rng(Some(1), random::RNG_TIPS_MANAGER), TipHeuristics::disabled(), 1);
+    let tm = tm.lock().unwrap();
+    tm.clear_orphaned_conflicts();
+    assert!(hive.lock().unwrap().is_conflicting(&second));
+    assert_eq!(lv.lock().unwrap().marked_spends(), vec![second]);
+
+    m.lock().unwrap().latest_solid_subhive_milestone_index = 4;
+    tm.clear_orphaned_conflicts();
+    assert!(!hive.lock().unwrap().is_conflicting(&second));
+    assert!(tm.is_orphaned(second));
+    assert_eq!(lv.lock().unwrap().get_transaction_status(&second), TransactionStatus::Solid);
+    assert_eq!(lv.lock().unwrap().get_transaction_status(&first), TransactionStatus::Confirmed(2));
+    assert!(lv.lock().unwrap().marked_spends().is_empty());
+}
+
+#[test]
+fn parallel_walks_test() {
+    use model::{MilestoneObject, TipsManager, TransactionValidator};
+    use model::tip_selection::{Mcmc, SelectionTrace, TipHeuristics, UnweightedWalk, WalkTrace, DEFAULT_ALPHA};
+    use model::transaction::{Hash, HASH_NULL};
+    use model::walker::Walker;
+    use utils::{seeded_rng, random};
+    use rand::{SeedableRng, StdRng};
+    use std::collections::{HashMap, HashSet};
+
+    let hive = make_am!(Hive::new());
+    let weights = make_am!(VecDeque::new());
+    let put = |i: u8, trunk: Hash, branch: Hash, snapshot: u32| {
+        let hash = put_test_transaction(&hive, i, trunk, branch, snapshot, 0);
+        weights.lock().unwrap().push_back(hash);
+        hash
+    };
+
+    let milestone = put(1, HASH_NULL, HASH_NULL, 5);
+    let a = put(2, milestone, milestone, 0);
+    let b = put(3, milestone, milestone, 0);
+    let c = put(4, a, a, 0);
+    put(5, a, b, 0);
+    put(6, c, a, 0);
+    put(7, b, b, 0);
+    TransactionValidator::update_cumulative_weights(&hive, &weights);
+
+    let select = |walk_threads: usize| {
+        let (m, lv, tv, tvm) = tip_selection_components(&hive, MilestoneObject::new(5, milestone));
+        let tm = TipsManager::new(hive.clone(), m, lv, tv, tvm, 3, true, 0, Box::new(UnweightedWalk), DEFAULT_ALPHA,
+                                  seeded_rng(Some(7), random::RNG_TIPS_MANAGER), TipHeuristics::disabled(),
+                                  walk_threads);
+        let tm = tm.lock().unwrap();
+        let mut trace = SelectionTrace::new();
+        let tail = tm.transaction_to_approve(&mut HashSet::new(), &mut HashMap::new(), None, None, 1, 40,
+                                             Some(&Mcmc { alpha: 0.5 }), Some(&mut trace))
+            .expect("selection failed");
+        (tail, trace)
+    };
+
+    let (sequential, sequential_trace) = select(1);
+    let (parallel, parallel_trace) = select(4);
+    assert!(sequential.is_some());
+    assert_eq!(parallel, sequential);
+    assert_eq!(parallel_trace, sequential_trace);
+    assert_eq!(parallel_trace.walks.len(), 40);
+
+    // a walk on the snapshot takes the steps a walk on the storage takes
+    let (m, lv, tv, _) = tip_selection_components(&hive, MilestoneObject::new(5, milestone));
+    let walker = Walker::new(hive.clone(), m, lv, tv, TipHeuristics::disabled());
+    let clock = walker.milestone_clock();
+    let snapshot = walker.snapshot(&HashSet::new(), &HashMap::new(), milestone, None, 0, &HashSet::new(), clock);
+    assert_eq!(snapshot.len(), 0);
+    for seed in 0..10 {
+        let mut rng: StdRng = SeedableRng::from_seed(&[seed][..]);
+        let mut live = WalkTrace::new();
+        walker.random_walk(&HashSet::new(), &HashMap::new(), Some(milestone), None, 0, &mut HashSet::new(), clock,
+                           0.5, &mut rng, Some(&mut live)).expect("walk failed");
+        let mut rng: StdRng = SeedableRng::from_seed(&[seed][..]);
+        let mut snapshotted = WalkTrace::new();
+        Walker::snapshot_walk(&snapshot, 0.5, &mut rng, Some(&mut snapshotted)).expect("walk failed");
+        assert_eq!(snapshotted, live);
+    }
+    assert!(snapshot.len() > 0 && snapshot.len() <= 7);
+}
+
+#[test]
+fn wide_cone_snapshot_test() {
+    use model::MilestoneObject;
+    use model::tip_selection::{TipHeuristics, WalkTrace};
+    use model::transaction::HASH_NULL;
+    use model::walker::Walker;
+    use rand::{SeedableRng, StdRng};
+    use std::collections::{HashMap, HashSet};
+
+    let hive = make_am!(Hive::new());
+    let milestone = put_test_transaction(&hive, 1, HASH_NULL, HASH_NULL, 5, 0);
+    // 40 chains of 5 above the milestone
+    let mut i = 2u8;
+    for _ in 0..40 {
+        let mut parent = milestone;
+        for _ in 0..5 {
+            parent = put_test_transaction(&hive, i, parent, parent, 0, 0);
+            i += 1;
+        }
+    }
+
+    let (m, lv, tv, _) = tip_selection_components(&hive, MilestoneObject::new(5, milestone));
+    let walker = Walker::new(hive.clone(), m, lv, tv, TipHeuristics::disabled());
+    let snapshot = walker.snapshot(&HashSet::new(), &HashMap::new(), milestone, None, 0, &HashSet::new(),
+                                   walker.milestone_clock());
+    for seed in 0..3 {
+        let mut rng: StdRng = SeedableRng::from_seed(&[seed][..]);
+        let mut trace = WalkTrace::new();
+        let tail = Walker::snapshot_walk(&snapshot, 0.0, &mut rng, Some(&mut trace)).expect("walk failed");
+        assert!(tail.is_some());
+        assert_eq!(trace.path.len(), 6);
+    }
+    // only what the walks stood on is read, not the 201 transactions of the cone
+    assert!(snapshot.len() <= 1 + 3 * 5);
+}
+
+#[test]
+fn reattached_transfer_test() {
+    use model::{MilestoneObject, Snapshot, TransactionBuilder};
+    use model::transaction::{Address, Difficulty, HASH_NULL, ADDRESS_SIZE};
+    use storage::hive::CFType;
+    use ntrumls::{NTRUMLS, PQParamSetID};
+    use std::collections::{HashMap, HashSet};
+
+    let ntrumls = NTRUMLS::with_param_set(PQParamSetID::Security269Bit);
+    let (sk, pk) = ntrumls.generate_keypair().expect("failed to generate keypair");
+    let sender = Address::from_public_key(&pk);
+    let recipient = Address([7u8; ADDRESS_SIZE]);
+
+    let hive = make_am!(Hive::new());
+    let genesis = put_test_transaction(&hive, 1, HASH_NULL, HASH_NULL, 1, 0);
+
+    // one signed transfer attached twice, each attachment confirmed by its own milestone
+    let transfer = TransactionBuilder::new()
+        .recipient(recipient)
+        .value(4)
+        .parents(genesis, genesis)
+        .min_weight_magnitude(Difficulty(1))
+        .build(&sk, &pk)
+        .expect("failed to build transaction");
+    let reattached = API::attach_to_tangle(genesis, genesis, Difficulty(1), vec![transfer.object.clone()])
+        .expect("failed to reattach")[0].clone();
+    assert!(reattached.hash != transfer.get_hash());
+    for object in [transfer.object.clone(), reattached.clone()].iter_mut() {
+        object.solid = true;
+        let mut hive = hive.lock().unwrap();
+        hive.storage_put(CFType::Transaction, &object.hash, &*object);
+        hive.put_approvee(genesis, object.hash);
+    }
+    let first = put_test_transaction(&hive, 2, transfer.get_hash(), genesis, 0, 0);
+    let second = put_test_transaction(&hive, 3, first, reattached.hash, 0, 0);
+
+    let (_, lv, _, _) = tip_selection_components(&hive, MilestoneObject::new(1, genesis));
+    let mut lv = lv.lock().unwrap();
+
+    // both attachments in one history
+    let diff = lv.get_latest_diff(&mut HashSet::new(), Some(second), 1, false)
+        .expect("failed to compute diff")
+        .expect("history is incomplete");
+    assert_eq!(diff.get(&sender), Some(&-4));
+    assert_eq!(diff.get(&recipient), Some(&4));
+
+    let mut state = HashMap::new();
+    state.insert(sender, 10);
+    let mut snapshot = Snapshot { state, index: 1 };
+    assert_eq!(lv.update_snapshot(&MilestoneObject::new(2, first), &mut snapshot), Ok(true));
+    assert_eq!(lv.update_snapshot(&MilestoneObject::new(3, second), &mut snapshot), Ok(true));
+    assert_eq!(snapshot.get_balance(&sender), Some(6));
+    assert_eq!(snapshot.get_balance(&recipient), Some(4));
+    assert_eq!(snapshot.index, 3);
+}
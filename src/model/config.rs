@@ -3,6 +3,9 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{BufRead, BufReader};
+use std::fmt;
+use std::str::FromStr;
+use model::transaction::{Address, ADDRESS_NULL};
 
 // TODO: remove
 pub const PORT: u16 = 44832;
@@ -20,6 +23,22 @@ pub enum ConfigurationValue {
     Bool(bool),
 }
 
+/// A setting the node can't start with, named as it is written in the config file
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigurationError {
+    Missing { key: &'static str, hint: &'static str },
+    Invalid { key: &'static str, hint: &'static str },
+}
+
+impl fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigurationError::Missing { key, hint } => write!(f, "missing param {}: {}", key, hint),
+            ConfigurationError::Invalid { key, hint } => write!(f, "invalid param {}: {}", key, hint),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum ConfigurationSettings {
     Config,
@@ -46,9 +65,23 @@ pub enum ConfigurationSettings {
     DNSResolutionEnabled,
     DNSRefresherEnabled,
     Coordinator,
+    CoordinatorEnabled,
+    CoordinatorKeys,
+    CoordinatorInterval,
+    MilestoneStartIndex,
+    NumKeysInMilestone,
+    AcceptAnyTestnetCoo,
     Revalidate,
     RescanDB,
     MinRandomWalks,
+    TipSelection,
+    TipSelectionAlpha,
+    TipSelectionThreads,
+    RandomSeed,
+    MaxTipAge,
+    MaxTipMilestoneLag,
+    MaxTipParentLag,
+    PersistentDB,
     MaxRandomWalks,
     MaxFindTransactions,
     MaxRequestsList,
@@ -56,6 +89,12 @@ pub enum ConfigurationSettings {
     MaxDepth,
     MainNetMWM,
     TestNetMWM,
+    MaxMWM,
+    MWMQueueThreshold,
+    MWMRateThreshold,
+    MWMRateWindow,
+    SignatureVerifierThreads,
+    SignatureCacheSize,
     QSizeNode,
     PDropCacheEntry,
     CacheSizeBytes,
@@ -126,6 +165,45 @@ impl Configuration {
         })
     }
 
+    /// Seed for reproducible runs, `None` when not configured
+    pub fn random_seed(&self) -> Option<u64> {
+        self.get_string(ConfigurationSettings::RandomSeed).and_then(|seed| {
+            match seed.parse::<u64>() {
+                Ok(0) => None,
+                Ok(seed) => Some(seed),
+                Err(_) => panic!("Invalid param {:?}", ConfigurationSettings::RandomSeed)
+            }
+        })
+    }
+
+    /// Address milestones have to come from. There is no default: only a testnet node accepting
+    /// any coordinator may run without one, and then takes milestones from anybody.
+    pub fn coordinator(&self) -> Result<Address, ConfigurationError> {
+        let testnet = self.get_bool(ConfigurationSettings::TestNet).unwrap_or(false);
+        let accept_any_testnet_coo = self.get_bool(ConfigurationSettings::AcceptAnyTestnetCoo).unwrap_or(false);
+
+        // on mainnet only the configured coordinator may confirm anything
+        if accept_any_testnet_coo && !testnet {
+            return Err(ConfigurationError::Invalid {
+                key: "accept_any_testnet_coo",
+                hint: "only allowed together with testnet=true",
+            });
+        }
+
+        match self.get_string(ConfigurationSettings::Coordinator) {
+            Some(ref coo) if !coo.is_empty() => Address::from_str(coo).map_err(|_| ConfigurationError::Invalid {
+                key: "coordinator",
+                hint: "expected the Merkle root address of the coordinator keys, e.g. P65DC4FEED4819C2910FA2DFC107399B7437ABAE2E7",
+            }),
+            _ if accept_any_testnet_coo => Ok(ADDRESS_NULL),
+            _ => Err(ConfigurationError::Missing {
+                key: "coordinator",
+                hint: "set it to the Merkle root address of the coordinator keys, or run a test network with \
+                       testnet=true and accept_any_testnet_coo=true",
+            })
+        }
+    }
+
     pub fn new() -> Self {
         let mut config = Configuration {
             params : HashMap::<u8, ConfigurationValue>::new()
@@ -139,6 +217,28 @@ impl Configuration {
         params_map.insert("debug".to_string(), ConfigurationSettings::Debug);
         params_map.insert("neighbors".to_string(), ConfigurationSettings::Neighbors);
         params_map.insert("max_peers".to_string(), ConfigurationSettings::MaxPeers);
+        params_map.insert("max_mwm".to_string(), ConfigurationSettings::MaxMWM);
+        params_map.insert("mwm_queue_threshold".to_string(), ConfigurationSettings::MWMQueueThreshold);
+        params_map.insert("mwm_rate_threshold".to_string(), ConfigurationSettings::MWMRateThreshold);
+        params_map.insert("mwm_rate_window".to_string(), ConfigurationSettings::MWMRateWindow);
+        params_map.insert("signature_verifier_threads".to_string(), ConfigurationSettings::SignatureVerifierThreads);
+        params_map.insert("signature_cache_size".to_string(), ConfigurationSettings::SignatureCacheSize);
+        params_map.insert("testnet".to_string(), ConfigurationSettings::TestNet);
+        params_map.insert("coordinator".to_string(), ConfigurationSettings::Coordinator);
+        params_map.insert("milestone_start_index".to_string(), ConfigurationSettings::MilestoneStartIndex);
+        params_map.insert("num_keys_in_milestone".to_string(), ConfigurationSettings::NumKeysInMilestone);
+        params_map.insert("accept_any_testnet_coo".to_string(), ConfigurationSettings::AcceptAnyTestnetCoo);
+        params_map.insert("coordinator_enabled".to_string(), ConfigurationSettings::CoordinatorEnabled);
+        params_map.insert("coordinator_keys".to_string(), ConfigurationSettings::CoordinatorKeys);
+        params_map.insert("coordinator_interval".to_string(), ConfigurationSettings::CoordinatorInterval);
+        params_map.insert("tip_selection".to_string(), ConfigurationSettings::TipSelection);
+        params_map.insert("tip_selection_alpha".to_string(), ConfigurationSettings::TipSelectionAlpha);
+        params_map.insert("tip_selection_threads".to_string(), ConfigurationSettings::TipSelectionThreads);
+        params_map.insert("random_seed".to_string(), ConfigurationSettings::RandomSeed);
+        params_map.insert("max_tip_age".to_string(), ConfigurationSettings::MaxTipAge);
+        params_map.insert("max_tip_milestone_lag".to_string(), ConfigurationSettings::MaxTipMilestoneLag);
+        params_map.insert("max_tip_parent_lag".to_string(), ConfigurationSettings::MaxTipParentLag);
+        params_map.insert("persistent_db".to_string(), ConfigurationSettings::PersistentDB);
 
         config.set_int(ConfigurationSettings::Port, 44832);
         config.set_string(ConfigurationSettings::ApiHost, "localhost");
@@ -165,12 +265,33 @@ impl Configuration {
         config.set_bool(ConfigurationSettings::DNSResolutionEnabled, true);
         config.set_bool(ConfigurationSettings::Revalidate, false);
         config.set_bool(ConfigurationSettings::RescanDB, false);
+        config.set_bool(ConfigurationSettings::PersistentDB, false); // keep the database between runs
         config.set_int(ConfigurationSettings::MainNetMWM, 8);
         config.set_int(ConfigurationSettings::TestNetMWM, 7);
+        config.set_int(ConfigurationSettings::MaxMWM, 20);
+        config.set_int(ConfigurationSettings::MWMQueueThreshold, 100); // txs in receive queue
+        config.set_int(ConfigurationSettings::MWMRateThreshold, 20); // API txs per window
+        config.set_int(ConfigurationSettings::MWMRateWindow, 10); // seconds
+        config.set_int(ConfigurationSettings::SignatureVerifierThreads, 4);
+        config.set_int(ConfigurationSettings::SignatureCacheSize, 10000);
+        config.set_string(ConfigurationSettings::Coordinator, ""); // Merkle root address of the coordinator keys, required
+        config.set_int(ConfigurationSettings::MilestoneStartIndex, 1);
+        config.set_int(ConfigurationSettings::NumKeysInMilestone, 22); // depth of the coordinator key tree
+        config.set_bool(ConfigurationSettings::AcceptAnyTestnetCoo, false);
+        config.set_bool(ConfigurationSettings::CoordinatorEnabled, false);
+        config.set_string(ConfigurationSettings::CoordinatorKeys, "coordinator.keys");
+        config.set_int(ConfigurationSettings::CoordinatorInterval, 60); // seconds
 
         config.set_int(ConfigurationSettings::MinRandomWalks, 5);
         config.set_int(ConfigurationSettings::MaxRandomWalks, 27);
         config.set_int(ConfigurationSettings::MaxDepth, 15);
+        config.set_string(ConfigurationSettings::TipSelection, "mcmc"); // mcmc, walk or uniform
+        config.set_float(ConfigurationSettings::TipSelectionAlpha, 0.001); // 0 makes the walk unweighted
+        config.set_int(ConfigurationSettings::TipSelectionThreads, 4); // parallel mcmc walks
+        config.set_string(ConfigurationSettings::RandomSeed, "0"); // u64, 0 seeds from the OS
+        config.set_int(ConfigurationSettings::MaxTipAge, 3600); // seconds before the latest solid milestone, 0 disables
+        config.set_int(ConfigurationSettings::MaxTipMilestoneLag, 5); // milestones, 0 disables
+        config.set_int(ConfigurationSettings::MaxTipParentLag, 1800); // seconds, 0 disables
 
         config.set_int(ConfigurationSettings::MaxFindTransactions, 100000);
         config.set_int(ConfigurationSettings::MaxRequestsList, 1000);
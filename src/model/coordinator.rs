@@ -0,0 +1,192 @@
+extern crate ntrumls;
+
+use std::collections::{HashMap, HashSet};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use self::ntrumls::{PrivateKey, PublicKey};
+use byteorder::{BigEndian, ByteOrder};
+
+use model::transaction::*;
+use model::{Milestone, TipsManager, TransactionBuilder, TransactionSource};
+use model::merkle::MerkleTree;
+use model::transaction_validator::TransactionError;
+use network::node::Node;
+use network::rpc;
+use storage::Hive;
+use utils::AM;
+
+/// Issues milestones for a private network: every `interval` it picks a tip with the tips manager,
+/// approves it together with the latest milestone and broadcasts the result. Milestone #n is
+/// signed with key #n of the coordinator tree and carries the Merkle path of that key, so the
+/// coordinator needs at least as many keys as milestones it is going to issue.
+pub struct Coordinator {
+    hive: AM<Hive>,
+    milestone: AM<Milestone>,
+    tips_manager: AM<TipsManager>,
+    node: AM<Node>,
+    keys: Vec<(PrivateKey, PublicKey)>,
+    tree: MerkleTree,
+    interval: Duration,
+    depth: u32,
+    num_walks: u32,
+    shutting_down: bool,
+    issuer_handle: Option<JoinHandle<()>>,
+}
+
+impl Coordinator {
+    pub fn new(hive: AM<Hive>,
+               milestone: AM<Milestone>,
+               tips_manager: AM<TipsManager>,
+               node: AM<Node>,
+               keys: Vec<(PrivateKey, PublicKey)>,
+               num_of_keys_in_milestone: u32,
+               interval: Duration,
+               depth: u32,
+               num_walks: u32) -> AM<Self> {
+        let tree = MerkleTree::new(&keys.iter().map(|&(_, ref pk)| pk.clone()).collect::<Vec<PublicKey>>(),
+                                   num_of_keys_in_milestone);
+        let coordinator = Coordinator {
+            hive,
+            milestone,
+            tips_manager,
+            node,
+            keys,
+            tree,
+            interval,
+            depth,
+            num_walks,
+            shutting_down: false,
+            issuer_handle: None,
+        };
+        make_am!(coordinator)
+    }
+
+    pub fn init(coo: AM<Coordinator>) {
+        let coo_clone = coo.clone();
+
+        let issuer_handle = thread::spawn(move || {
+            let interval = match coo_clone.lock() {
+                Ok(c) => c.interval,
+                Err(_) => panic!("broken coordinator mutex")
+            };
+
+            info!("coordinator started, issuing milestones every {:?}", interval);
+
+            let mut last_issued = Instant::now();
+            loop {
+                if let Ok(c) = coo_clone.lock() {
+                    if c.shutting_down {
+                        break;
+                    }
+                } else {
+                    panic!("broken coordinator mutex");
+                }
+
+                if last_issued.elapsed() >= interval {
+                    last_issued = Instant::now();
+                    if let Ok(c) = coo_clone.lock() {
+                        match c.issue_milestone() {
+                            Ok(Some((index, hash))) => info!("issued milestone #{} {:?}", index, hash),
+                            Ok(None) => warn!("no milestone to approve, skipping"),
+                            Err(e) => error!("failed to issue milestone: {} (code {})", e, e.code())
+                        }
+                    }
+                }
+
+                thread::sleep(Duration::from_millis(500));
+            }
+        });
+
+        if let Ok(mut c) = coo.lock() {
+            c.issuer_handle = Some(issuer_handle);
+        }
+    }
+
+    pub fn get_address(&self) -> Address {
+        Address::from_merkle_root(&self.tree.root())
+    }
+
+    /// Builds the next milestone and hands it to the node, returns its index and hash
+    pub fn issue_milestone(&self) -> Result<Option<(u32, Hash)>, TransactionError> {
+        let (mut trunk, mut index) = match self.milestone.lock() {
+            Ok(m) => (m.latest_milestone, m.latest_milestone_index),
+            Err(_) => panic!("broken milestone mutex")
+        };
+
+        // the tracker has not caught up yet, start from what is stored
+        if trunk == HASH_NULL {
+            match self.hive.lock() {
+                Ok(hive) => match hive.storage_latest_milestone() {
+                    Some(m) => {
+                        trunk = m.get_hash();
+                        index = m.index();
+                    }
+                    None => return Ok(None)
+                },
+                Err(_) => panic!("broken hive mutex")
+            }
+        }
+
+        let mut visited_hashes = HashSet::new();
+        let mut diff = HashMap::new();
+        let branch = match self.tips_manager.lock() {
+            Ok(tm) => tm.transaction_to_approve(&mut visited_hashes, &mut diff, None, None,
+                                                self.depth, self.num_walks, None, None)?,
+            Err(_) => panic!("broken tips manager mutex")
+        }.unwrap_or(trunk);
+
+        let index = index + 1;
+        let (sk, pk, proof) = match (self.keys.get(index as usize), self.tree.proof(index)) {
+            (Some(&(ref sk, ref pk)), Some(proof)) => (sk, pk, proof),
+            _ => {
+                error!("no coordinator key left for milestone #{}", index);
+                return Err(TransactionError::InvalidSignature);
+            }
+        };
+        let mwm = match self.node.lock() {
+            Ok(node) => node.required_min_weight_magnitude(&TransactionSource::Api),
+            Err(_) => panic!("broken node mutex")
+        };
+
+        // addressed to the coordinator, so the milestone tracker finds it among the address transactions
+        let transaction = TransactionBuilder::new()
+            .recipient(self.get_address())
+            .tag(Coordinator::milestone_tag(index))
+            .parents(trunk, branch)
+            .payload(proof.to_payload())
+            .min_weight_magnitude(mwm)
+            .build(sk, pk)?;
+        let hash = transaction.get_hash();
+
+        match self.node.lock() {
+            Ok(mut node) => node.on_api_broadcast_transaction_received(rpc::BroadcastTransaction {
+                transaction: transaction.object
+            }),
+            Err(_) => panic!("broken node mutex")
+        };
+
+        Ok(Some((index, hash)))
+    }
+
+    // same layout Milestone::get_index reads
+    pub fn milestone_tag(index: u32) -> Hash {
+        let mut tag = HASH_NULL;
+        BigEndian::write_u32(&mut tag[(HASH_SIZE - 4)..], index);
+        tag
+    }
+
+    // the issuer thread locks the coordinator, so it is joined without holding the lock
+    pub fn shutdown(coo: AM<Coordinator>) {
+        let handle = match coo.lock() {
+            Ok(mut c) => {
+                c.shutting_down = true;
+                c.issuer_handle.take()
+            }
+            Err(_) => panic!("broken coordinator mutex")
+        };
+        if let Some(jh) = handle {
+            jh.join();
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use model::transaction::Difficulty;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransactionSource {
+    Neighbor(IpAddr),
+    Api,
+    // sent by a neighbor as part of a milestone subhive during sync
+    Sync(IpAddr),
+}
+
+/// Congestion-aware minimum weight magnitude. The base MWM is raised by one bit each time the
+/// receive queue length or the recent rate of API submissions doubles past its threshold, so
+/// the PoW a spammer has to spend grows linearly with the load it causes. A neighbor relays the
+/// transactions of many senders, so its rate isn't charged to the transactions it relays.
+pub struct DifficultyPolicy {
+    base: Difficulty,
+    max: Difficulty,
+    queue_threshold: usize,
+    rate_threshold: usize,
+    window: Duration,
+    arrivals: HashMap<TransactionSource, VecDeque<Instant>>,
+}
+
+impl DifficultyPolicy {
+    pub fn new(base: Difficulty, max: Difficulty, queue_threshold: usize, rate_threshold: usize,
+               window: Duration) -> Self {
+        DifficultyPolicy {
+            base,
+            max,
+            queue_threshold,
+            rate_threshold,
+            window,
+            arrivals: HashMap::new(),
+        }
+    }
+
+    pub fn set_base(&mut self, base: Difficulty) {
+        self.base = base;
+    }
+
+    pub fn get_base(&self) -> Difficulty {
+        self.base
+    }
+
+    pub fn record(&mut self, source: TransactionSource, now: Instant) {
+        if !DifficultyPolicy::rate_limited(&source) {
+            return;
+        }
+
+        let window = self.window;
+        self.arrivals.retain(|_, times| {
+            while let Some(&t) = times.front() {
+                if now.duration_since(t) <= window {
+                    break;
+                }
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        self.arrivals.entry(source).or_insert_with(VecDeque::new).push_back(now);
+    }
+
+    pub fn recent_arrivals(&self, source: &TransactionSource, now: Instant) -> usize {
+        match self.arrivals.get(source) {
+            Some(times) => times.iter().filter(|t| now.duration_since(**t) <= self.window).count(),
+            None => 0
+        }
+    }
+
+    /// MWM advertised to everybody: base raised by the receive queue load only
+    pub fn current(&self, queue_len: usize) -> Difficulty {
+        self.cap(self.base.bits() + DifficultyPolicy::extra_bits(queue_len, self.queue_threshold))
+    }
+
+    /// MWM a transaction from `source` has to reach. Synced transactions were attached long ago
+    /// and only have to reach the base MWM.
+    pub fn required(&self, source: &TransactionSource, queue_len: usize, now: Instant) -> Difficulty {
+        if let TransactionSource::Sync(_) = *source {
+            return self.base;
+        }
+
+        let rate = if DifficultyPolicy::rate_limited(source) { self.recent_arrivals(source, now) } else { 0 };
+        self.cap(self.base.bits() + DifficultyPolicy::extra_bits(queue_len, self.queue_threshold)
+            + DifficultyPolicy::extra_bits(rate, self.rate_threshold))
+    }
+
+    fn rate_limited(source: &TransactionSource) -> bool {
+        *source == TransactionSource::Api
+    }
+
+    fn cap(&self, bits: u32) -> Difficulty {
+        let max = if self.max < self.base { self.base } else { self.max };
+        if bits > max.bits() { max } else { Difficulty::new(bits) }
+    }
+
+    fn extra_bits(load: usize, threshold: usize) -> u32 {
+        if threshold == 0 || load < threshold {
+            return 0;
+        }
+
+        let ratio = load / threshold;
+        (::std::mem::size_of::<usize>() * 8) as u32 - ratio.leading_zeros()
+    }
+}
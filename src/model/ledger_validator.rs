@@ -2,23 +2,55 @@ use storage::Hive;
 use model::{Milestone, MilestoneObject, TransactionRequester, Snapshot};
 use std::collections::{HashSet, HashMap, LinkedList};
 use model::transaction::*;
-use model::{StateDiff, StateDiffObject};
+use model::{StateDiff, StateDiffObject, TransactionStatus};
 use utils::*;
 use model::transaction_validator::TransactionError;
 use std::i64;
 
+// conflicts kept for getConflicts, the oldest are dropped first
+const MAX_CONFLICTS: usize = 1000;
+
+/// Address overdrawn by the transactions a milestone would confirm, together with the
+/// unconfirmed transactions spending from it
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct Conflict {
+    pub milestone_index: u32,
+    pub address: Address,
+    pub balance: i64,
+    pub transactions: Vec<Hash>,
+}
+
 pub struct LedgerValidator {
     hive: AM<Hive>,
     milestone: AM<Milestone>,
     transaction_requester: AM<TransactionRequester>,
     number_of_confirmed_transactions: usize,
+    conflicts: Vec<Conflict>,
+    // spends marked as conflicting, until the tips manager finds them confirmed or orphaned
+    marked_spends: HashSet<Hash>,
 }
 
 impl LedgerValidator {
     pub fn new(hive: AM<Hive>, milestone: AM<Milestone>, transaction_requester: AM<TransactionRequester>) -> Self {
         LedgerValidator {
             hive, milestone, transaction_requester,
-            number_of_confirmed_transactions: 0
+            number_of_confirmed_transactions: 0,
+            conflicts: Vec::new(),
+            marked_spends: HashSet::new(),
+        }
+    }
+
+    pub fn get_conflicts(&self) -> Vec<Conflict> {
+        self.conflicts.clone()
+    }
+
+    pub fn marked_spends(&self) -> Vec<Hash> {
+        self.marked_spends.iter().cloned().collect()
+    }
+
+    pub fn forget_marked_spends(&mut self, hashes: &[Hash]) {
+        for hash in hashes {
+            self.marked_spends.remove(hash);
         }
     }
 
@@ -29,6 +61,7 @@ impl LedgerValidator {
         let mut number_of_analyzed_transactions = 0;
         let mut counted_tx = HashSet::<Hash>::new();
         counted_tx.insert(HASH_NULL);
+        let mut counted_essences = HashSet::<Hash>::new();
 
         visited_non_milestone_subtangle_hashes.insert(HASH_NULL);
 
@@ -61,7 +94,8 @@ impl LedgerValidator {
                         }
                     } else {
                         // TODO: check balance
-                        if transaction.object.value != 0 && counted_tx.insert(transaction.get_hash()) {
+                        if transaction.object.value != 0 && counted_tx.insert(transaction.get_hash())
+                            && self.moves_value(&transaction, &mut counted_essences) {
                             let from_address = Address::from_public_key(&transaction.object.signature_pubkey);
                             let address = transaction.object.address;
 
@@ -132,6 +166,14 @@ impl LedgerValidator {
                             transaction.object.snapshot = index;
                             hive.update_transaction(&mut transaction);
                         }
+
+                        if transaction.object.value != 0 {
+                            hive.put_confirmed_essence(&transaction.object.essence_hash(), &na_hash);
+                        }
+
+                        let mut metadata = hive.storage_load_transaction_metadata(&na_hash);
+                        metadata.status = TransactionStatus::Confirmed(index);
+                        hive.put_transaction_metadata(&na_hash, &metadata);
                         info!("new solid tx: {:?}", transaction.get_hash());
 
                         non_analyzed_transactions.push_back(transaction.get_trunk_transaction_hash());
@@ -148,6 +190,49 @@ impl LedgerValidator {
         Ok(())
     }
 
+    // Every attachment of a signed transfer carries the same essence and signature, only the first
+    // one counted, or the one a milestone confirmed, moves the value
+    fn moves_value(&self, transaction: &Transaction, counted_essences: &mut HashSet<Hash>) -> bool {
+        let essence = transaction.object.essence_hash();
+        let confirmed = match self.hive.lock() {
+            Ok(hive) => hive.storage_load_confirmed_essence(&essence),
+            Err(_) => panic!("broken hive mutex")
+        };
+        match confirmed {
+            Some(hash) if hash != transaction.get_hash() => false,
+            _ => counted_essences.insert(essence)
+        }
+    }
+
+    /// Status as far as the ledger knows, `Orphaned` is up to the tip selection
+    pub fn get_transaction_status(&self, hash: &Hash) -> TransactionStatus {
+        if let Ok(hive) = self.hive.lock() {
+            let transaction = match hive.storage_load_transaction(hash) {
+                Some(t) => t,
+                None => return TransactionStatus::Unknown
+            };
+            if transaction.get_type() == TransactionType::HashOnly {
+                return TransactionStatus::Unknown;
+            }
+
+            match hive.storage_load_transaction_metadata(hash).status {
+                TransactionStatus::Unknown => {}
+                status => return status
+            };
+
+            // confirmed before the status was tracked, e.g. while the snapshot was being built
+            if transaction.object.snapshot != 0 {
+                TransactionStatus::Confirmed(transaction.object.snapshot)
+            } else if transaction.is_solid() {
+                TransactionStatus::Solid
+            } else {
+                TransactionStatus::Pending
+            }
+        } else {
+            panic!("broken hive mutex");
+        }
+    }
+
     pub fn init(&mut self) -> Result<(), TransactionError> {
         if let Some(latest_consistent_milestone) = self.build_snapshot()? {
             info!("Loaded consistent milestone: {}", latest_consistent_milestone.index);
@@ -199,8 +284,9 @@ impl LedgerValidator {
 
             milestone_latest_snapshot_index = /*milestone.*/latest_snapshot.index;
 
+            let mut visited_hashes = HashSet::new();
             let mut current_state;
-            match self.get_latest_diff(&mut HashSet::new(), Some(tail),
+            match self.get_latest_diff(&mut visited_hashes, Some(tail),
                                        milestone_latest_snapshot_index,true)? {
                 Some(cs) => {
                     current_state = cs;
@@ -209,9 +295,18 @@ impl LedgerValidator {
             };
 
             let mut patched = /*milestone.*/latest_snapshot.patched_diff(current_state.clone());
+            let overdrawn: HashMap<Address, i64> = patched.iter()
+                .filter(|&(_, v)| *v < 0)
+                .map(|(k, v)| (k.clone(), *v))
+                .collect();
             has_snapshot = Snapshot::is_consistent(&mut patched);
 
+            if !has_snapshot {
+                self.mark_conflicts(milestone_obj.index, &visited_hashes, &overdrawn);
+            }
+
             if has_snapshot {
+                self.clear_resolved_conflicts(milestone_obj.index);
                 self.update_snapshot_milestone(milestone_obj.get_hash(), milestone_obj.index)?;
                 let state_diff = StateDiff {
                     state_diff_object: StateDiffObject { state: current_state.clone() },
@@ -230,6 +325,95 @@ impl LedgerValidator {
         Ok(has_snapshot)
     }
 
+    // the milestone stays unconfirmed until the conflict is resolved, so the same conflict is
+    // found again on every attempt and recorded only once
+    fn mark_conflicts(&mut self, milestone_index: u32, visited_hashes: &HashSet<Hash>,
+                      overdrawn: &HashMap<Address, i64>) {
+        let mut spends = HashMap::<Address, Vec<(u64, Hash, u32)>>::new();
+        let mut spenders = HashMap::<Address, Vec<Hash>>::new();
+        let mut essences = HashSet::<Hash>::new();
+
+        if let Ok(mut hive) = self.hive.lock() {
+            for hash in visited_hashes {
+                let transaction = match hive.storage_load_transaction(hash) {
+                    Some(t) => t,
+                    None => continue
+                };
+                if transaction.object.value == 0 || transaction.object.snapshot != 0 {
+                    continue;
+                }
+                // other attachments of a spend don't spend again
+                let essence = transaction.object.essence_hash();
+                if hive.storage_load_confirmed_essence(&essence).is_some() || !essences.insert(essence) {
+                    continue;
+                }
+
+                let from_address = Address::from_public_key(&transaction.object.signature_pubkey);
+                if overdrawn.contains_key(&from_address) {
+                    spends.entry(from_address).or_insert_with(Vec::new)
+                        .push((attachment_time_ms(&transaction.object), hash.clone(), transaction.object.value));
+                }
+            }
+
+            for (address, spends) in spends {
+                for hash in LedgerValidator::conflicting_spends(spends, overdrawn[&address]) {
+                    let mut metadata = hive.storage_load_transaction_metadata(&hash);
+                    if !metadata.is_conflicting() {
+                        metadata.status = TransactionStatus::Conflicting;
+                        hive.put_transaction_metadata(&hash, &metadata);
+                    }
+                    self.marked_spends.insert(hash);
+                    spenders.entry(address.clone()).or_insert_with(Vec::new).push(hash);
+                }
+            }
+        } else {
+            panic!("broken hive mutex");
+        }
+
+        for (address, balance) in overdrawn {
+            if self.conflicts.iter().any(|c| c.milestone_index == milestone_index && c.address == *address) {
+                continue;
+            }
+
+            let transactions = spenders.remove(address).unwrap_or_default();
+            warn!("double spend in milestone #{}: address {:?} overdrawn to {} by {:?}",
+                  milestone_index, address, balance, transactions);
+            if self.conflicts.len() >= MAX_CONFLICTS {
+                self.conflicts.remove(0);
+            }
+            self.conflicts.push(Conflict {
+                milestone_index,
+                address: address.clone(),
+                balance: *balance,
+                transactions,
+            });
+        }
+    }
+
+    /// Spends `(attachment time, hash, value)` of an address overdrawn to `balance` that conflict.
+    /// Spends are covered in the order they were attached, only those the balance doesn't cover
+    /// any more are conflicting.
+    pub fn conflicting_spends(mut spends: Vec<(u64, Hash, u32)>, balance: i64) -> Vec<Hash> {
+        spends.sort_by_key(|&(attached, hash, _)| (attached, hash.0));
+        let spent: i64 = spends.iter().map(|&(_, _, value)| value as i64).sum();
+        let mut available = spent + balance;
+
+        let mut conflicting = Vec::new();
+        for (_, hash, value) in spends {
+            if value as i64 <= available {
+                available -= value as i64;
+            } else {
+                conflicting.push(hash);
+            }
+        }
+        conflicting
+    }
+
+    // a confirmed milestone resolves the conflicts found in it and in the milestones before it
+    fn clear_resolved_conflicts(&mut self, milestone_index: u32) {
+        self.conflicts.retain(|c| c.milestone_index > milestone_index);
+    }
+
     fn build_snapshot(&self) -> Result<Option<MilestoneObject>, TransactionError> {
         let mut consistent_milestone = None;
 
@@ -300,9 +484,7 @@ impl LedgerValidator {
                     Some(old_value) => *v as i64 + *old_value,
                     None => v.clone() as i64
                 };
-                if current_state.get(k).is_none() {
-                    current_state.insert(k.clone(), new_value);
-                }
+                current_state.insert(k.clone(), new_value);
             });
 
             is_consistent = Snapshot::is_consistent(&mut milestone.latest_snapshot.patched_diff(current_state.clone()));
@@ -0,0 +1,171 @@
+extern crate crypto;
+extern crate ntrumls;
+
+use self::crypto::digest::Digest;
+use self::crypto::sha3::Sha3;
+use self::ntrumls::PublicKey;
+use model::transaction::*;
+use network::packet::{Serializable, SerializedBuffer, calculate_object_size};
+
+// leaves and inner nodes are hashed with different prefixes, so an inner node can't pass as a key
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+pub fn leaf_hash(pk: &PublicKey) -> Hash {
+    let mut sha = Sha3::sha3_256();
+    sha.input(&[LEAF_PREFIX]);
+    sha.input(&pk.0);
+
+    let mut buf = [0u8; HASH_SIZE];
+    sha.result(&mut buf);
+    Hash(buf)
+}
+
+pub fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut sha = Sha3::sha3_256();
+    sha.input(&[NODE_PREFIX]);
+    sha.input(left);
+    sha.input(right);
+
+    let mut buf = [0u8; HASH_SIZE];
+    sha.result(&mut buf);
+    Hash(buf)
+}
+
+/// Merkle tree of `2^depth` coordinator public keys. Only the keys actually generated are
+/// stored, the remaining leaves are `HASH_NULL` and their subtrees are computed once per level.
+pub struct MerkleTree {
+    depth: u32,
+    levels: Vec<Vec<Hash>>,
+    empty: Vec<Hash>,
+}
+
+impl MerkleTree {
+    pub fn new(keys: &[PublicKey], depth: u32) -> Self {
+        assert!(depth >= 32 || keys.len() <= 1usize << depth, "too many keys for a tree of depth {}", depth);
+
+        let mut empty = vec![HASH_NULL];
+        for l in 0..depth as usize {
+            let e = node_hash(&empty[l], &empty[l]);
+            empty.push(e);
+        }
+
+        let mut levels = vec![keys.iter().map(|pk| leaf_hash(pk)).collect::<Vec<Hash>>()];
+        for l in 0..depth as usize {
+            let next = levels[l].chunks(2).map(|pair| {
+                node_hash(&pair[0], pair.get(1).unwrap_or(&empty[l]))
+            }).collect();
+            levels.push(next);
+        }
+
+        MerkleTree {
+            depth,
+            levels,
+            empty,
+        }
+    }
+
+    pub fn root(&self) -> Hash {
+        match self.levels[self.depth as usize].first() {
+            Some(root) => *root,
+            None => self.empty[self.depth as usize]
+        }
+    }
+
+    pub fn proof(&self, key_index: u32) -> Option<MerkleProof> {
+        if key_index as usize >= self.levels[0].len() {
+            return None;
+        }
+
+        let mut path = Vec::with_capacity(self.depth as usize);
+        let mut i = key_index as usize;
+        for l in 0..self.depth as usize {
+            path.push(self.levels[l].get(i ^ 1).cloned().unwrap_or(self.empty[l]));
+            i >>= 1;
+        }
+
+        Some(MerkleProof {
+            key_index,
+            path,
+        })
+    }
+}
+
+/// Position of the signing key in the coordinator tree and the sibling hashes up to the root.
+/// Milestones carry it as their payload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    pub key_index: u32,
+    pub path: Vec<Hash>,
+}
+
+impl MerkleProof {
+    pub fn root(&self, pk: &PublicKey) -> Hash {
+        let mut hash = leaf_hash(pk);
+        for (l, sibling) in self.path.iter().enumerate() {
+            hash = if l >= 32 || (self.key_index >> l) & 1 == 0 {
+                node_hash(&hash, sibling)
+            } else {
+                node_hash(sibling, &hash)
+            };
+        }
+        hash
+    }
+
+    pub fn verify(&self, pk: &PublicKey, root: &Hash) -> bool {
+        (self.path.len() >= 32 || (self.key_index >> self.path.len()) == 0) && self.root(pk) == *root
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut sb = SerializedBuffer::new_with_size(calculate_object_size(self));
+        self.serialize_to_stream(&mut sb);
+        sb.buffer
+    }
+
+    // the payload comes from the network, so lengths are checked before every read
+    pub fn from_payload(payload: &[u8]) -> Option<MerkleProof> {
+        let mut sb = SerializedBuffer::from_slice(payload);
+        if sb.remaining() < 8 {
+            return None;
+        }
+
+        let key_index = sb.read_u32();
+        let len = sb.read_u32() as usize;
+        if len.checked_mul(HASH_SIZE) != Some(sb.remaining()) {
+            return None;
+        }
+
+        let mut path = Vec::with_capacity(len);
+        for _ in 0..len {
+            let mut hash = HASH_NULL;
+            sb.read_bytes(&mut hash, HASH_SIZE);
+            path.push(hash);
+        }
+
+        Some(MerkleProof {
+            key_index,
+            path,
+        })
+    }
+}
+
+impl Serializable for MerkleProof {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_u32(self.key_index);
+        stream.write_u32(self.path.len() as u32);
+        for hash in &self.path {
+            stream.write_bytes(hash);
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        self.key_index = stream.read_u32();
+        let len = stream.read_u32();
+        self.path = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let mut hash = HASH_NULL;
+            stream.read_bytes(&mut hash, HASH_SIZE);
+            self.path.push(hash);
+        }
+    }
+}
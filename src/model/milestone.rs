@@ -4,21 +4,23 @@ use model::snapshot::Snapshot;
 use model::TransactionValidator;
 use network::packet::*;
 use model::transaction_validator::TransactionError;
+use model::merkle::MerkleProof;
+use model::signature_verifier::verify_signature;
 
-use std::collections::{HashSet, HashMap, LinkedList};
+use std::collections::{HashSet, HashMap, LinkedList, VecDeque};
 use utils::AM;
 use storage::Hive;
 use std::thread;
-use std::time::SystemTime;
-use std::cmp::max;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
 use std::io;
 use std::time::Duration;
 use std::thread::JoinHandle;
 
 pub const RESCAN_INTERVAL: i32 = 5000;
 
+#[derive(Debug, PartialEq)]
 pub enum Validity {
     Valid,
     Invalid,
@@ -101,6 +103,41 @@ pub struct Milestone {
 
     pub latest_milestone_tracker_thread: Option<JoinHandle<()>>,
     pub solid_milestone_tracker_thread: Option<JoinHandle<()>>,
+
+    candidate_tx: Sender<Hash>,
+    candidate_rx: Option<Receiver<Hash>>,
+    solid_tx: Sender<()>,
+    solid_rx: Option<Receiver<()>>,
+    // candidates stored before their trunk, by trunk
+    incomplete_candidates: AM<HashMap<Hash, Vec<Hash>>>,
+}
+
+/// Tells the milestone trackers about stored transactions: ones addressed to the coordinator
+/// are milestone candidates, and ones a milestone was waiting for may make it solid.
+#[derive(Clone)]
+pub struct MilestoneNotifier {
+    coordinator: Address,
+    candidate_tx: Sender<Hash>,
+    solid_tx: Sender<()>,
+    incomplete_candidates: AM<HashMap<Hash, Vec<Hash>>>,
+}
+
+impl MilestoneNotifier {
+    pub fn on_transaction_stored(&self, transaction: &Transaction, milestone_dependency: bool) {
+        if transaction.object.address == self.coordinator {
+            let _ = self.candidate_tx.send(transaction.get_hash());
+        }
+        let waiting = match self.incomplete_candidates.lock() {
+            Ok(mut incomplete_candidates) => incomplete_candidates.remove(&transaction.get_hash()),
+            Err(_) => panic!("broken incomplete candidates mutex")
+        };
+        for hash in waiting.unwrap_or(Vec::new()) {
+            let _ = self.candidate_tx.send(hash);
+        }
+        if milestone_dependency {
+            let _ = self.solid_tx.send(());
+        }
+    }
 }
 
 impl Milestone {
@@ -114,6 +151,8 @@ impl Milestone {
                accept_any_testnet_coo: bool) -> AM<Self> {
         let latest_milestone = HASH_NULL;
         let latest_solid_subhive_milestone = latest_milestone.clone();
+        let (candidate_tx, candidate_rx) = channel::<Hash>();
+        let (solid_tx, solid_rx) = channel::<()>();
 
         let milestone = Milestone {
             hive,
@@ -124,7 +163,7 @@ impl Milestone {
             testnet,
             num_of_keys_in_milestone,
             milestone_start_index,
-            accept_any_testnet_coo,
+            accept_any_testnet_coo: testnet && accept_any_testnet_coo,
             analyzed_milestone_candidates: HashSet::new(),
             latest_solid_subhive_milestone_index: 0u32,
             latest_milestone_index: 0u32,
@@ -133,14 +172,27 @@ impl Milestone {
             shutting_down: false,
             latest_milestone_tracker_thread: None,
             solid_milestone_tracker_thread: None,
+            candidate_tx,
+            candidate_rx: Some(candidate_rx),
+            solid_tx,
+            solid_rx: Some(solid_rx),
+            incomplete_candidates: make_am!(HashMap::new()),
         };
 
         make_am!(milestone)
     }
 
     pub fn init(milestone: AM<Milestone>, ledger_validator: AM<LedgerValidator>) {
+        let candidate_rx;
+        let solid_rx;
+        let solid_tx;
         if let Ok(mut milestone) = milestone.lock() {
             milestone.ledger_validator = Some(ledger_validator);
+            candidate_rx = milestone.candidate_rx.take().expect("milestone trackers already started");
+            solid_rx = milestone.solid_rx.take().expect("milestone trackers already started");
+            solid_tx = milestone.solid_tx.clone();
+        } else {
+            panic!("broken milestone mutex");
         }
 
         let ledger_validator_initialized: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
@@ -154,16 +206,12 @@ impl Milestone {
             let hive;
             let coordinator;
 
-//            if let Some(arc) = transaction_validator.upgrade() {
-                if let Ok(m) = milestone_clone_1.lock() {
-                    hive = m.hive.clone();
-                    coordinator = m.coordinator.clone(); //Address::from_public_key(&m.coordinator);
-                } else {
-                    panic!("broken transaction_validator mutex");
-                }
-//            } else {
-//                panic!("transaction_validator is null");
-//            }
+            if let Ok(m) = milestone_clone_1.lock() {
+                hive = m.hive.clone();
+                coordinator = m.coordinator.clone();
+            } else {
+                panic!("broken milestone mutex");
+            }
 
             info!("Waiting for Ledger Validator initialization...");
             while !ledger_initialized_clone_thread_1.load(Ordering::SeqCst) {
@@ -172,99 +220,49 @@ impl Milestone {
 
             info!("tracker started");
 
-            let mut shutting_down = false;
-
-            while !shutting_down {
-                let scan_time = SystemTime::now();
-                let mut previous_latest_milestone_index;
+            // candidates stored before the node started are scanned once, new ones are pushed
+            // by the receive pipeline
+            let stored = match hive.lock() {
+                Ok(hive) => hive.load_address_transactions(&coordinator).unwrap_or(Vec::new()),
+                Err(_) => panic!("broken hive mutex")
+            };
+            let mut candidates: VecDeque<Hash> = stored.into_iter().collect();
 
+            loop {
                 if let Ok(self_p) = milestone_clone_1.lock() {
-                    shutting_down = self_p.shutting_down;
-                    previous_latest_milestone_index = self_p.latest_milestone_index;
-                    if shutting_down {
+                    if self_p.shutting_down {
                         break;
                     }
                 } else {
                     panic!("broken milestone mutex");
                 }
 
-                let mut hashes: Vec<Hash>;
-                let mut hashes_is_ok = false;
-                // println!("hive lock 1");
-                if let Ok(hive) = hive.lock() {
-                    hashes = match hive.load_address_transactions(&coordinator){
-                        Some(h) => {
-                            hashes_is_ok = true;
-                            h
-                        }
-                        None => {
-                            hashes_is_ok = false;
-                            Vec::new()
-                        }
+                if candidates.is_empty() {
+                    match candidate_rx.recv_timeout(Duration::from_millis(RESCAN_INTERVAL as u64)) {
+                        Ok(hash) => candidates.push_back(hash),
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break
                     }
-                } else {
-                    panic!("broken hive mutex");
                 }
-                // println!("hive unlock 1");
-                // TODO: optimize
-                if hashes_is_ok {
-                    for hash in hashes {
-                        if let Ok(mut milestone) = milestone_clone_1.lock() {
-                            if milestone.analyzed_milestone_candidates.insert(hash) {
-                                let mut t;
-                                // println!("hive lock 2");
-                                if let Ok(hive) = hive.lock() {
-                                    t = hive.storage_load_transaction(&hash);
-                                } else {
-                                    panic!("broken hive mutex");
-                                }
-                                // println!("hive unlock 2");
-                                if let Some(t) = t {
-                                    let valid = milestone.validate_milestone(&t, milestone.get_index(&t));
-                                    match valid {
-                                        Validity::Valid => {
-                                            // println!("hive lock 3");
-                                            if let Ok(hive) = hive.lock() {
-                                                match hive.storage_latest_milestone() {
-                                                    Some(milestone_manager) => {
-                                                        if milestone_manager.index() > milestone.latest_milestone_index {
-                                                            milestone.latest_milestone = milestone_manager.get_hash();
-                                                            milestone.latest_milestone_index = milestone_manager.index();
-                                                        }
-                                                    }
-                                                    None => {
-                                                        // println!("hive unlock 3");
-                                                        continue;
-                                                    }
-                                                }
-                                            }
-                                            // println!("hive unlock 3");
-                                        }
-
-                                        Validity::Incomplete => {
-                                            milestone.analyzed_milestone_candidates.remove(&t.get_hash());
-                                        }
-
-                                        Validity::Invalid => {
-                                            //nothing to do
-                                        }
-                                    };
-                                }
-                            }
-                        }
-                    }
+                while let Ok(hash) = candidate_rx.try_recv() {
+                    candidates.push_back(hash);
                 }
 
-                if let Ok(milestone) = milestone_clone_1.lock() {
+                if let Ok(mut milestone) = milestone_clone_1.lock() {
+                    let previous_latest_milestone_index = milestone.latest_milestone_index;
+                    while let Some(hash) = candidates.pop_front() {
+                        milestone.analyze_candidate(hash);
+                    }
+
                     if previous_latest_milestone_index != milestone.latest_milestone_index {
                         info!("Latest milestone has changed from #{} to #{}",
                                  previous_latest_milestone_index,
                                  milestone.latest_milestone_index);
+                        let _ = solid_tx.send(());
                     }
+                } else {
+                    panic!("broken milestone mutex");
                 }
-
-                let now = SystemTime::now();
-                thread::sleep_ms(max(1i32, RESCAN_INTERVAL - (now.duration_since(scan_time).unwrap().as_secs() * 1000 + (now.duration_since(scan_time).unwrap().subsec_nanos() / 1000) as u64) as i32) as u32);
             }
         });
 
@@ -288,17 +286,13 @@ impl Milestone {
             }
 
             info!("tracker #2 started");
-            let mut shutting_down = false;
-            while !shutting_down {
+            loop {
                 if let Ok(self_p) = milestone_clone_2.lock() {
-                    shutting_down = self_p.shutting_down;
-                    if shutting_down {
+                    if self_p.shutting_down {
                         break;
                     }
                 }
 
-                let scan_time = SystemTime::now();
-
                 if let Ok(mut milestone) = milestone_clone_2.lock() {
                     let previous_solid_subhive_latest_milestone_index = milestone.latest_solid_subhive_milestone_index;
                     if milestone.latest_solid_subhive_milestone_index < milestone.latest_milestone_index {
@@ -317,8 +311,12 @@ impl Milestone {
                     panic!("broken milestone mutex");
                 }
 
-                let now = SystemTime::now();
-                thread::sleep_ms(max(1i32, RESCAN_INTERVAL - (now.duration_since(scan_time).unwrap().as_secs() * 1000 + (now.duration_since(scan_time).unwrap().subsec_nanos() / 1000) as u64) as i32) as u32);
+                // woken up by a new milestone or an arrived milestone dependency, the timeout
+                // only lets the thread notice shutdown
+                match solid_rx.recv_timeout(Duration::from_millis(RESCAN_INTERVAL as u64)) {
+                    Err(RecvTimeoutError::Disconnected) => break,
+                    _ => while let Ok(_) = solid_rx.try_recv() {}
+                }
             }
         });
 
@@ -328,6 +326,70 @@ impl Milestone {
         }
     }
 
+    /// Handle for the receive pipeline to wake the trackers up
+    pub fn notifier(&self) -> MilestoneNotifier {
+        MilestoneNotifier {
+            coordinator: self.coordinator,
+            candidate_tx: self.candidate_tx.clone(),
+            solid_tx: self.solid_tx.clone(),
+            incomplete_candidates: self.incomplete_candidates.clone(),
+        }
+    }
+
+    /// Candidates waiting for the trackers, for a node that hasn't started them
+    pub fn take_queued_candidates(&mut self) -> Vec<Hash> {
+        match self.candidate_rx {
+            Some(ref candidate_rx) => candidate_rx.try_iter().collect(),
+            None => Vec::new()
+        }
+    }
+
+    pub fn analyze_candidate(&mut self, hash: Hash) {
+        if !self.analyzed_milestone_candidates.insert(hash) {
+            return;
+        }
+
+        let t = match self.hive.lock() {
+            Ok(hive) => hive.storage_load_transaction(&hash),
+            Err(_) => panic!("broken hive mutex")
+        };
+
+        if let Some(t) = t {
+            let index = self.get_index(&t);
+            // held across the check, so a trunk stored meanwhile finds the candidate waiting for it
+            let mut incomplete_candidates = match self.incomplete_candidates.lock() {
+                Ok(incomplete_candidates) => incomplete_candidates,
+                Err(_) => panic!("broken incomplete candidates mutex")
+            };
+            match self.validate_milestone(&t, index) {
+                Validity::Valid => {
+                    if let Ok(hive) = self.hive.lock() {
+                        if let Some(latest) = hive.storage_latest_milestone() {
+                            if latest.index() > self.latest_milestone_index {
+                                self.latest_milestone = latest.get_hash();
+                                self.latest_milestone_index = latest.index();
+                            }
+                        }
+                    } else {
+                        panic!("broken hive mutex");
+                    }
+                }
+
+                // analyzed again once the trunk is stored
+                Validity::Incomplete => {
+                    self.analyzed_milestone_candidates.remove(&t.get_hash());
+                    incomplete_candidates.entry(t.get_trunk_transaction_hash())
+                        .or_insert(Vec::new())
+                        .push(t.get_hash());
+                }
+
+                Validity::Invalid => {
+                    //nothing to do
+                }
+            };
+        }
+    }
+
     fn update_latest_solid_subhive_milestone(&mut self) -> Result<(), TransactionError> {
         let latest;
         let mut closest_milestone;
@@ -390,27 +452,47 @@ impl Milestone {
                 return Validity::Valid;
             }
 
-            if let Some(tx2) = hive.storage_load_transaction(&transaction.get_trunk_transaction_hash()) {
-                if tx2.get_type() == TransactionType::Full {
-                    if self.testnet && self.accept_any_testnet_coo ||
-                        Address::from_public_key(&tx2.object.signature_pubkey) == self.coordinator {
-                            hive.put_milestone(&MilestoneObject {
-                                index,
-                                hash: transaction.get_hash()
-                            });
-                        // println!("hive unlock 5");
-                        return Validity::Valid;
-                    } else {
-                        // println!("hive unlock 5");
-                        return Validity::Invalid;
-                    }
+            // checked first, so only genuine milestones wait for their trunk
+            if !(self.testnet && self.accept_any_testnet_coo || self.is_signed_by_coordinator(transaction, index)) {
+                // println!("hive unlock 5");
+                return Validity::Invalid;
+            }
+
+            match hive.storage_load_transaction(&transaction.get_trunk_transaction_hash()) {
+                Some(ref tx2) if tx2.get_type() == TransactionType::Full => {
+                    hive.put_milestone(&MilestoneObject {
+                        index,
+                        hash: transaction.get_hash()
+                    });
+                    // println!("hive unlock 5");
+                    return Validity::Valid;
                 }
+                _ => return Validity::Incomplete
             }
         }
         // println!("hive unlock 5");
         return Validity::Invalid;
     }
 
+    // The milestone itself has to be signed with key #index of the coordinator tree, and the
+    // Merkle path in its payload has to lead from that key to the coordinator address
+    pub fn is_signed_by_coordinator(&self, transaction: &Transaction, index: u32) -> bool {
+        let proof = match MerkleProof::from_payload(&transaction.object.payload) {
+            Some(proof) => proof,
+            None => return false
+        };
+
+        if proof.key_index != index || proof.path.len() != self.num_of_keys_in_milestone as usize {
+            return false;
+        }
+
+        if !verify_signature(&transaction.object) {
+            return false;
+        }
+
+        Address::from_merkle_root(&proof.root(&transaction.object.signature_pubkey)) == self.coordinator
+    }
+
     fn get_index(&self, tx: &Transaction) -> u32 {
         use byteorder::{BigEndian, ByteOrder};
         BigEndian::read_u32(&tx.object.tag[(HASH_SIZE - 4)..])
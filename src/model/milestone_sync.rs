@@ -0,0 +1,166 @@
+use std::cmp::{max, min};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+pub const SYNC_REQUEST_TIMEOUT: u64 = 10; // seconds
+pub const SYNC_TARGET_TTL: u64 = 120; // seconds
+pub const SYNC_PROBE_INTERVAL: u64 = 30; // seconds
+
+struct SyncRequest {
+    index: u32,
+    offset: u32,
+    peer: IpAddr,
+    sent: Instant,
+}
+
+/// State of the milestone sync protocol. At most one `RequestMilestone` is in flight, and only a
+/// subhive answering it counts as synced data, anything else a peer sends is gossip.
+pub struct MilestoneSync {
+    request: Option<SyncRequest>,
+    // milestone index and offset of the next page of a truncated subhive
+    continuation: Option<(u32, u32)>,
+    // milestone whose subhive was last received whole, and when; it isn't asked for again before
+    // `backoff` has passed, which doubles while it stays the next one to sync
+    completed: Option<(u32, Instant)>,
+    backoff: Duration,
+    // latest milestone index claimed by a peer, not validated so it expires unless confirmed
+    target: Option<(u32, Instant)>,
+    last_probe: Option<Instant>,
+    request_timeout: Duration,
+    target_ttl: Duration,
+    probe_interval: Duration,
+    pub syncing: bool,
+}
+
+impl MilestoneSync {
+    pub fn new() -> Self {
+        MilestoneSync::with_timeouts(Duration::from_secs(SYNC_REQUEST_TIMEOUT),
+                                     Duration::from_secs(SYNC_TARGET_TTL),
+                                     Duration::from_secs(SYNC_PROBE_INTERVAL))
+    }
+
+    pub fn with_timeouts(request_timeout: Duration, target_ttl: Duration, probe_interval: Duration) -> Self {
+        MilestoneSync {
+            request: None,
+            continuation: None,
+            completed: None,
+            backoff: request_timeout,
+            target: None,
+            last_probe: None,
+            request_timeout,
+            target_ttl,
+            probe_interval,
+            syncing: false,
+        }
+    }
+
+    pub fn target_index(&self) -> Option<u32> {
+        self.target.map(|(index, _)| index)
+    }
+
+    pub fn in_flight(&self) -> Option<(u32, u32, IpAddr)> {
+        self.request.as_ref().map(|r| (r.index, r.offset, r.peer))
+    }
+
+    /// Milestone index and subhive offset to request next. `None` while a request is in flight,
+    /// while backing off a milestone just received whole, or when the node has caught up and it
+    /// isn't time to probe the network's latest index yet.
+    pub fn next_request(&mut self, latest_milestone_index: u32, latest_solid_index: u32, now: Instant)
+        -> Option<(u32, u32)> {
+        if let Some(ref r) = self.request {
+            if now.duration_since(r.sent) < self.request_timeout {
+                return None;
+            }
+            debug!("request of milestone #{} at {} from {:?} timed out", r.index, r.offset, r.peer);
+        }
+        self.request = None;
+
+        if let Some((_, confirmed)) = self.target {
+            if now.duration_since(confirmed) >= self.target_ttl {
+                self.target = None;
+            }
+        }
+
+        let next = latest_solid_index + 1;
+        let target = max(latest_milestone_index, self.target_index().unwrap_or(0));
+        let syncing = next <= target;
+
+        if syncing != self.syncing {
+            if syncing {
+                info!("Syncing from milestone #{} to #{}", next, target);
+            } else {
+                info!("Synced up to milestone #{}", latest_solid_index);
+            }
+            self.syncing = syncing;
+        }
+
+        match self.continuation {
+            Some((index, offset)) if index == next => return Some((index, offset)),
+            Some(_) => self.continuation = None,
+            None => {}
+        }
+
+        match self.completed {
+            Some((index, received)) if index == next => if now.duration_since(received) < self.backoff {
+                return None;
+            },
+            Some(_) => {
+                self.completed = None;
+                self.backoff = self.request_timeout;
+            }
+            None => {}
+        }
+
+        if syncing {
+            return Some((next, 0));
+        }
+
+        let probe = self.last_probe.map(|t| now.duration_since(t) >= self.probe_interval).unwrap_or(true);
+        if probe {
+            self.last_probe = Some(now);
+            return Some((next, 0));
+        }
+
+        None
+    }
+
+    pub fn on_request_sent(&mut self, index: u32, offset: u32, peer: IpAddr, now: Instant) {
+        self.request = Some(SyncRequest { index, offset, peer, sent: now });
+    }
+
+    /// Whether a subhive answers the request in flight, which it then completes. `count` is the number
+    /// of transactions it carries, `complete` is false when the sender truncated it.
+    pub fn on_subhive(&mut self, index: u32, offset: u32, peer: IpAddr, latest_milestone_index: u32,
+                      count: u32, complete: bool, now: Instant) -> bool {
+        match self.request {
+            Some(ref r) if r.index == index && r.offset == offset && r.peer == peer => {}
+            _ => return false
+        }
+        self.request = None;
+
+        if count == 0 && offset == 0 {
+            // the peer doesn't have the milestone it may have claimed to be at
+            if self.target_index().map(|target| index <= target).unwrap_or(false) {
+                self.target = None;
+            }
+        } else if latest_milestone_index >= self.target_index().unwrap_or(0) {
+            self.target = Some((latest_milestone_index, now));
+        }
+
+        self.continuation = if !complete && count > 0 {
+            Some((index, offset + count))
+        } else {
+            None
+        };
+
+        if self.continuation.is_none() {
+            self.backoff = match self.completed {
+                Some((completed, _)) if completed == index => min(self.backoff * 2, self.target_ttl),
+                _ => self.request_timeout
+            };
+            self.completed = Some((index, now));
+        }
+
+        true
+    }
+}
@@ -9,13 +9,30 @@ pub mod transaction_validator;
 pub mod ledger_validator;
 pub mod snapshot;
 pub mod state_diff;
+pub mod difficulty_policy;
+pub mod signature_verifier;
+pub mod transaction_builder;
+pub mod coordinator;
+pub mod merkle;
+pub mod transaction_metadata;
+pub mod tip_selection;
+pub mod walker;
+pub mod milestone_sync;
 
 pub use self::transaction::{Transaction, TransactionObject, TransactionType};
 pub use self::tips_view_model::TipsViewModel;
 pub use self::transaction_validator::TransactionValidator;
 pub use self::transaction_requester::TransactionRequester;
-pub use self::ledger_validator::LedgerValidator;
-pub use self::milestone::{Milestone, MilestoneObject};
+pub use self::ledger_validator::{LedgerValidator, Conflict};
+pub use self::milestone::{Milestone, MilestoneObject, MilestoneNotifier};
+pub use self::milestone_sync::MilestoneSync;
 pub use self::snapshot::Snapshot;
 pub use self::state_diff::{StateDiff, StateDiffObject};
-pub use self::tips_manager::TipsManager;
\ No newline at end of file
+pub use self::tips_manager::TipsManager;
+pub use self::difficulty_policy::{DifficultyPolicy, TransactionSource};
+pub use self::signature_verifier::SignatureVerifier;
+pub use self::transaction_builder::TransactionBuilder;
+pub use self::coordinator::Coordinator;
+pub use self::merkle::{MerkleTree, MerkleProof};
+pub use self::transaction_metadata::{TransactionMetadata, TransactionStatus};
+pub use self::tip_selection::TipSelector;
\ No newline at end of file
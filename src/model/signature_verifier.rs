@@ -0,0 +1,112 @@
+extern crate ntrumls;
+extern crate threadpool;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::channel;
+use self::ntrumls::{NTRUMLS, PQParamSetID, Signature, PublicKey};
+use self::threadpool::ThreadPool;
+use model::{Transaction, TransactionObject};
+use model::transaction::Hash;
+
+thread_local! {
+    // NTRUMLS::with_param_set is expensive, so every thread keeps its own instance
+    static NTRUMLS_INSTANCE: NTRUMLS = NTRUMLS::with_param_set(PQParamSetID::Security269Bit);
+}
+
+pub fn verify_signature(transaction: &TransactionObject) -> bool {
+    NTRUMLS_INSTANCE.with(|ntrumls| {
+        ntrumls.verify(&transaction.essence_hash(), &transaction.signature, &transaction.signature_pubkey)
+    })
+}
+
+/// Verifies signatures on a worker pool and remembers successful verifications by hash, so
+/// gossip duplicates are not verified again. Failures are not cached: otherwise a forged copy
+/// arriving first would get the genuine transaction rejected.
+pub struct SignatureVerifier {
+    pool: ThreadPool,
+    cache: HashMap<Hash, (Signature, PublicKey)>,
+    cache_order: VecDeque<Hash>,
+    cache_size: usize,
+}
+
+impl SignatureVerifier {
+    pub fn new(threads: usize, cache_size: usize) -> Self {
+        SignatureVerifier {
+            pool: ThreadPool::new(if threads == 0 { 1 } else { threads }),
+            cache: HashMap::new(),
+            cache_order: VecDeque::new(),
+            cache_size,
+        }
+    }
+
+    fn is_cached(&self, transaction: &TransactionObject) -> bool {
+        match self.cache.get(&transaction.hash) {
+            Some(&(ref signature, ref pk)) => *signature == transaction.signature &&
+                *pk == transaction.signature_pubkey,
+            None => false
+        }
+    }
+
+    fn cache_verified(&mut self, transaction: &TransactionObject) {
+        if self.cache_size == 0 {
+            return;
+        }
+
+        if self.cache.insert(transaction.hash, (transaction.signature.clone(),
+                                                 transaction.signature_pubkey.clone())).is_none() {
+            self.cache_order.push_back(transaction.hash);
+        }
+
+        while self.cache_order.len() > self.cache_size {
+            if let Some(hash) = self.cache_order.pop_front() {
+                self.cache.remove(&hash);
+            }
+        }
+    }
+
+    pub fn verify(&mut self, transaction: &TransactionObject) -> bool {
+        if self.is_cached(transaction) {
+            return true;
+        }
+
+        let valid = verify_signature(transaction);
+        if valid {
+            self.cache_verified(transaction);
+        }
+        valid
+    }
+
+    // Returns the verification result for every transaction, in the same order
+    pub fn verify_batch(&mut self, transactions: Vec<Transaction>) -> Vec<(Transaction, bool)> {
+        let mut results: Vec<Option<bool>> = transactions.iter()
+            .map(|t| if self.is_cached(&t.object) { Some(true) } else { None })
+            .collect();
+
+        let (tx, rx) = channel::<(usize, bool)>();
+        for (i, t) in transactions.iter().enumerate() {
+            if results[i].is_none() {
+                let tx = tx.clone();
+                let object = t.object.clone();
+                self.pool.execute(move || {
+                    let _ = tx.send((i, verify_signature(&object)));
+                });
+            }
+        }
+        drop(tx);
+
+        // ends when every job has either answered or died
+        for (i, valid) in rx.iter() {
+            results[i] = Some(valid);
+        }
+
+        let mut verified = Vec::with_capacity(transactions.len());
+        for (t, valid) in transactions.into_iter().zip(results.into_iter()) {
+            let valid = valid.unwrap_or(false);
+            if valid {
+                self.cache_verified(&t.object);
+            }
+            verified.push((t, valid));
+        }
+        verified
+    }
+}
@@ -0,0 +1,354 @@
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+
+use model::transaction::*;
+use model::tips_manager::TipsManager;
+use model::transaction_validator::TransactionError;
+use model::walker::MilestoneClock;
+
+pub const DEFAULT_ALPHA: f64 = 0.001;
+// unconfirmed ancestors looked at when searching the newest milestone a tip approves
+pub const MAX_TIP_SCAN: usize = 1000;
+
+/// Everything `TipsManager::transaction_to_approve` has prepared for a strategy: the entry point
+/// is already checked against the ledger and `visited_hashes`/`diff` include its history
+pub struct SelectionContext<'a> {
+    pub visited_hashes: &'a HashSet<Hash>,
+    pub diff: &'a HashMap<Address, i64>,
+    pub entry_point: Hash,
+    pub extra_tip: Option<Hash>,
+    pub iterations: u32,
+    pub max_depth: u32,
+    pub max_depth_ok: &'a mut HashSet<Hash>,
+    // the tip heuristics of every walk measure against this milestone
+    pub clock: MilestoneClock,
+    // collects the walks when diagnostics were asked for
+    pub trace: Option<&'a mut SelectionTrace>,
+}
+
+/// Picks the transaction to approve, given a prepared selection context
+pub trait TipSelector: Send {
+    fn name(&self) -> &'static str;
+
+    /// Whether the selected transaction approves the entry point, a reference needs that
+    fn walks_from_entry_point(&self) -> bool {
+        true
+    }
+
+    fn select(&self, tips_manager: &TipsManager, context: &mut SelectionContext) -> Result<Option<Hash>, TransactionError>;
+}
+
+/// Any solid tip known to the node, ignoring the entry point. Every tip it draws is traced as a
+/// walk of one step, with no tail when the tip was refused.
+pub struct UniformRandom;
+
+impl TipSelector for UniformRandom {
+    fn name(&self) -> &'static str {
+        "uniform"
+    }
+
+    fn walks_from_entry_point(&self) -> bool {
+        false
+    }
+
+    fn select(&self, tips_manager: &TipsManager, context: &mut SelectionContext) -> Result<Option<Hash>, TransactionError> {
+        for _ in 0..max(context.iterations, 1) {
+            let tip = match tips_manager.get_random_solid_tip() {
+                Some(tip) => tip,
+                None => return Ok(None)
+            };
+
+            let selectable = Some(tip) != context.extra_tip && tips_manager.is_selectable(context, tip)?;
+            if let Some(ref mut trace) = context.trace {
+                let mut walk_trace = WalkTrace::new();
+                walk_trace.path.push(WalkStep {
+                    hash: tip,
+                    approvers: Vec::new(),
+                    ratings: Vec::new(),
+                    excluded: Vec::new(),
+                });
+                if selectable {
+                    walk_trace.tail = Some(tip);
+                    walk_trace.stop_reason = Some(StopReason::Tip);
+                } else if Some(tip) == context.extra_tip {
+                    walk_trace.stop_reason = Some(StopReason::ExtraTip);
+                }
+                trace.walks.push(walk_trace);
+            }
+
+            if selectable {
+                return Ok(Some(tip));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A single walk from the entry point choosing every approver with the same probability
+pub struct UnweightedWalk;
+
+impl TipSelector for UnweightedWalk {
+    fn name(&self) -> &'static str {
+        "walk"
+    }
+
+    fn select(&self, tips_manager: &TipsManager, context: &mut SelectionContext) -> Result<Option<Hash>, TransactionError> {
+        let mut walk_trace = context.trace.as_ref().map(|_| WalkTrace::new());
+        let tail = tips_manager.random_walk(context.visited_hashes, context.diff, Some(context.entry_point),
+                                            context.extra_tip, context.max_depth, context.max_depth_ok, context.clock, 0.0,
+                                            walk_trace.as_mut())?;
+        if let (Some(trace), Some(walk_trace)) = (context.trace.as_mut(), walk_trace) {
+            trace.walks.push(walk_trace);
+        }
+        Ok(tail)
+    }
+}
+
+/// `iterations` walks weighted by the cumulative weights, the most frequent tail wins
+pub struct Mcmc {
+    pub alpha: f64,
+}
+
+impl TipSelector for Mcmc {
+    fn name(&self) -> &'static str {
+        "mcmc"
+    }
+
+    fn select(&self, tips_manager: &TipsManager, context: &mut SelectionContext) -> Result<Option<Hash>, TransactionError> {
+        tips_manager.markov_chain_monte_carlo(context.visited_hashes, context.diff, context.entry_point,
+                                              context.extra_tip, context.iterations,
+                                              context.max_depth, context.max_depth_ok, context.clock, self.alpha,
+                                              context.trace.as_mut().map(|t| &mut **t))
+    }
+}
+
+/// Why a walk stopped where it did
+#[derive(Debug, Clone, Copy, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum StopReason {
+    // nothing approves the transaction yet
+    Tip,
+    Conflicting,
+    // only the hash is known
+    Missing,
+    NotSolid,
+    BelowMaxDepth,
+    // approving it would overdraw an address
+    InconsistentLedger,
+    // it is the other tip of the pair
+    ExtraTip,
+    // no approver had a usable weight
+    NoApproverWeight,
+    // every approver is a lazy or parasitic tip
+    ExcludedTips,
+}
+
+impl StopReason {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StopReason::Tip => "tip",
+            StopReason::Conflicting => "conflicting",
+            StopReason::Missing => "missing",
+            StopReason::NotSolid => "not solid",
+            StopReason::BelowMaxDepth => "below max depth",
+            StopReason::InconsistentLedger => "inconsistent ledger",
+            StopReason::ExtraTip => "extra tip",
+            StopReason::NoApproverWeight => "no approver weight",
+            StopReason::ExcludedTips => "excluded tips",
+        }
+    }
+
+    pub fn code(&self) -> i32 {
+        *self as i32
+    }
+
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(StopReason::Tip),
+            1 => Some(StopReason::Conflicting),
+            2 => Some(StopReason::Missing),
+            3 => Some(StopReason::NotSolid),
+            4 => Some(StopReason::BelowMaxDepth),
+            5 => Some(StopReason::InconsistentLedger),
+            6 => Some(StopReason::ExtraTip),
+            7 => Some(StopReason::NoApproverWeight),
+            8 => Some(StopReason::ExcludedTips),
+            _ => None
+        }
+    }
+}
+
+/// A transaction a walk stood on, with the approvers it could step to and their cumulative weights
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct WalkStep {
+    pub hash: Hash,
+    pub approvers: Vec<Hash>,
+    pub ratings: Vec<i64>,
+    // approvers it couldn't step to, being lazy or parasitic tips
+    pub excluded: Vec<(Hash, TipExclusion)>,
+}
+
+/// Path of one walk, the tail is the last step it didn't stop on
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct WalkTrace {
+    pub path: Vec<WalkStep>,
+    pub tail: Option<Hash>,
+    pub stop_reason: Option<StopReason>,
+}
+
+impl WalkTrace {
+    pub fn new() -> Self {
+        WalkTrace {
+            path: Vec::new(),
+            tail: None,
+            stop_reason: None,
+        }
+    }
+}
+
+/// Everything one `TipsManager::transaction_to_approve` call did
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct SelectionTrace {
+    pub strategy: String,
+    pub entry_point: Hash,
+    // false if the entry point itself failed the ledger check, no walk was made then
+    pub entry_point_consistent: bool,
+    pub walks: Vec<WalkTrace>,
+}
+
+impl SelectionTrace {
+    pub fn new() -> Self {
+        SelectionTrace {
+            strategy: String::new(),
+            entry_point: HASH_NULL,
+            entry_point_consistent: false,
+            walks: Vec::new(),
+        }
+    }
+}
+
+/// Transition weights `exp(-alpha * (w_current - w_approver))` of a walk step. The exponents are
+/// shifted by their maximum before `exp`, so the heaviest approver always weighs 1 and large rating
+/// gaps can't overflow or underflow every weight to zero. The shift cancels `w_current`, hence it
+/// isn't a parameter.
+pub fn transition_weights(approver_ratings: &[i64], alpha: f64) -> Vec<f64> {
+    let max_rating = match approver_ratings.iter().max() {
+        Some(r) => *r,
+        None => return vec![]
+    };
+
+    approver_ratings.iter()
+        .map(|r| (alpha * (*r as f64 - max_rating as f64)).exp())
+        .collect()
+}
+
+/// Index of the approver a walk steps on, `sample` is uniform in [0, 1)
+pub fn walk_approvers(approver_ratings: &[i64], alpha: f64, sample: f64) -> Option<usize> {
+    let weights = transition_weights(approver_ratings, alpha);
+    let total: f64 = weights.iter().sum();
+    if weights.is_empty() || !(total > 0.0) {
+        return None;
+    }
+
+    let mut target = sample * total;
+    for (i, w) in weights.iter().enumerate() {
+        target -= *w;
+        if target < 0.0 {
+            return Some(i);
+        }
+    }
+    // rounding left a tiny remainder
+    Some(weights.len() - 1)
+}
+
+/// Index of the milestone the walks start from, `depth` milestones below the latest solid one
+pub fn entry_point_index(latest_solid_index: u32, depth: u32) -> u32 {
+    latest_solid_index.saturating_sub(depth)
+}
+
+/// Walks stop on transactions approving something confirmed below this index
+pub fn min_walk_index(latest_solid_index: u32, depth: u32) -> u32 {
+    latest_solid_index.saturating_sub(depth.saturating_mul(2))
+}
+
+/// Depth the walks start at, capped to `max_depth`. Depth 0 starts at the latest solid milestone
+/// and leaves the walk nowhere to go, it is only accepted for a walk forced through a reference.
+pub fn check_depth(depth: u32, max_depth: u32, has_reference: bool) -> Result<u32, TransactionError> {
+    if depth == 0 && !has_reference {
+        return Err(TransactionError::InvalidDepth { depth, max_depth });
+    }
+    Ok(min(depth, max_depth))
+}
+
+/// Why tip selection refuses to approve a tip
+#[derive(Debug, Clone, Copy, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum TipExclusion {
+    // old itself, or approving something much older than itself
+    Lazy,
+    // recent, but grown on a part of the subhive that ignores recent milestones
+    Parasitic,
+}
+
+impl TipExclusion {
+    pub fn code(&self) -> i32 {
+        *self as i32
+    }
+
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(TipExclusion::Lazy),
+            1 => Some(TipExclusion::Parasitic),
+            _ => None
+        }
+    }
+}
+
+/// What the lazy and parasitic heuristics know about a tip
+#[derive(Debug, Clone, PartialEq)]
+pub struct TipScore {
+    // how long before the latest solid milestone the tip was attached, 0 for newer tips
+    pub age_ms: u64,
+    // latest solid milestone index minus the newest milestone confirming something the tip approves
+    pub milestone_lag: u32,
+    // how much older the older parent is than the tip, or than the latest solid milestone for
+    // tips attached after it
+    pub parent_lag_ms: u64,
+}
+
+/// Thresholds of the heuristics, 0 disables a check
+#[derive(Debug, Clone, PartialEq)]
+pub struct TipHeuristics {
+    pub max_age_ms: u64,
+    pub max_milestone_lag: u32,
+    pub max_parent_lag_ms: u64,
+}
+
+impl TipHeuristics {
+    pub fn disabled() -> Self {
+        TipHeuristics {
+            max_age_ms: 0,
+            max_milestone_lag: 0,
+            max_parent_lag_ms: 0,
+        }
+    }
+
+    pub fn classify(&self, score: &TipScore) -> Option<TipExclusion> {
+        if self.max_age_ms != 0 && score.age_ms > self.max_age_ms
+            || self.max_parent_lag_ms != 0 && score.parent_lag_ms > self.max_parent_lag_ms {
+            return Some(TipExclusion::Lazy);
+        }
+        if self.max_milestone_lag != 0 && score.milestone_lag > self.max_milestone_lag {
+            return Some(TipExclusion::Parasitic);
+        }
+        None
+    }
+}
+
+/// Strategy for a name as used in the config and in API requests
+pub fn from_name(name: &str, alpha: f64) -> Option<Box<TipSelector>> {
+    match name {
+        "uniform" => Some(Box::new(UniformRandom)),
+        "walk" => Some(Box::new(UnweightedWalk)),
+        "mcmc" => Some(Box::new(Mcmc { alpha })),
+        _ => None
+    }
+}
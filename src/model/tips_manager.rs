@@ -1,7 +1,9 @@
-use std::collections::{HashMap, HashSet, LinkedList};
+extern crate threadpool;
+
+use std::collections::{HashMap, HashSet};
 use std::iter::Iterator;
-use std::cmp::max;
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
 
 use model::transaction::*;
 use storage::hive::Hive;
@@ -9,11 +11,17 @@ use model::milestone::{Milestone, MilestoneObject};
 use utils::defines::AM;
 use model::tips_view_model::TipsViewModel;
 use model::ledger_validator::LedgerValidator;
+use model::transaction_metadata::TransactionStatus;
 use model::transaction_validator::TransactionValidator;
 use model::transaction_validator::TransactionError;
+use model::tip_selection::{self, TipSelector, SelectionContext};
+use model::tip_selection::{TipExclusion, TipHeuristics, SelectionTrace, WalkTrace};
+use model::tip_selection::{check_depth, entry_point_index, min_walk_index};
+use model::walker::{Walker, MilestoneClock};
+use self::threadpool::ThreadPool;
 use std::thread;
 
-use rand::Rng;
+use rand::{Rng, SeedableRng, StdRng};
 use std::time::Duration;
 use std::thread::JoinHandle;
 
@@ -32,6 +40,11 @@ pub struct TipsManager {
     ledger_validator: AM<LedgerValidator>,
     transaction_validator: AM<TransactionValidator>,
     testnet: bool,
+    tip_selector: Box<TipSelector>,
+    alpha: f64,
+    rng: AM<StdRng>,
+    walker: Walker,
+    walk_pool: ThreadPool,
     //private int RATING_THRESHOLD = 75; // Must be in [0..100] range
     shutting_down: bool,
     solidity_rescan_handle: Option<JoinHandle<()>>
@@ -46,8 +59,15 @@ impl TipsManager {
                tips_view_model: AM<TipsViewModel>,
                max_depth: u32,
                testnet: bool,
-               milestone_start_index: u32) -> AM<Self> {
-
+               milestone_start_index: u32,
+               tip_selector: Box<TipSelector>,
+               alpha: f64,
+               rng: StdRng,
+               tip_heuristics: TipHeuristics,
+               walk_threads: usize) -> AM<Self> {
+
+        let walker = Walker::new(hive.clone(), milestone.clone(), ledger_validator.clone(),
+                                 transaction_validator.clone(), tip_heuristics);
         let tips_manager = TipsManager {
             hive,
             max_depth,
@@ -57,6 +77,11 @@ impl TipsManager {
             ledger_validator,
             transaction_validator,
             testnet,
+            tip_selector,
+            alpha,
+            rng: make_am!(rng),
+            walker,
+            walk_pool: ThreadPool::new(if walk_threads == 0 { 1 } else { walk_threads }),
             shutting_down: false,
             solidity_rescan_handle: None
         };
@@ -71,6 +96,7 @@ impl TipsManager {
             while !shutting_down {
                 if let Ok(s_am) = tm_clone.lock(){
                     s_am.scan_tips_for_solidity();
+                    s_am.clear_orphaned_conflicts();
                 }
                 thread::sleep_ms(RESCAN_TX_TO_REQUEST_INTERVAL);
 
@@ -89,6 +115,53 @@ impl TipsManager {
         self.max_depth
     }
 
+    /// Strategy for a per-request name, weighted with the configured alpha
+    pub fn tip_selector(&self, name: &str) -> Option<Box<TipSelector>> {
+        tip_selection::from_name(name, self.alpha)
+    }
+
+    pub fn get_random_solid_tip(&self) -> Option<Hash> {
+        match self.tips_view_model.lock() {
+            Ok(mut tvm) => tvm.get_random_solid_tip(),
+            Err(_) => panic!("broken tips view model mutex")
+        }
+    }
+
+    /// Same checks a walk does before stepping on a transaction
+    pub fn is_selectable(&self, context: &mut SelectionContext, tip: Hash) -> Result<bool, TransactionError> {
+        match self.hive.lock() {
+            Ok(hive) => if hive.is_conflicting(&tip) {
+                return Ok(false);
+            },
+            Err(_) => panic!("hive mutex is broken")
+        };
+
+        if self.walker.below_max_depth(tip, context.max_depth, context.max_depth_ok) {
+            return Ok(false);
+        }
+
+        if self.walker.tip_exclusion(&tip, context.clock).is_some() {
+            return Ok(false);
+        }
+
+        let mut approved_hashes = context.visited_hashes.clone();
+        let mut diff = context.diff.clone();
+        match self.ledger_validator.lock() {
+            Ok(mut lv) => lv.update_diff(&mut approved_hashes, &mut diff, tip),
+            Err(_) => panic!("broken ledger validator mutex")
+        }
+    }
+
+    /// How many times tips were refused as lazy and as parasitic since the start
+    pub fn excluded_tips(&self) -> (usize, usize) {
+        self.walker.excluded_tips()
+    }
+
+    /// Lazy or parasitic verdict for a tip, `None` if it may be approved
+    pub fn tip_exclusion(&self, hash: &Hash) -> Option<TipExclusion> {
+        self.walker.tip_exclusion(hash, self.walker.milestone_clock())
+    }
+
     fn scan_tips_for_solidity(&self) -> Result<(), TransactionError> {
         if let Ok(mut t_v_m) = self.tips_view_model.lock() {
             let mut size = t_v_m.get_non_solid_tips_count();
@@ -122,16 +195,29 @@ impl TipsManager {
         Ok(())
     }
 
+    /// Selects a transaction to approve. Without a reference, or with `extra_tip` (the second walk
+    /// of a pair), the walks start at the milestone `depth` below the latest solid one. A reference
+    /// makes the first walk start at it, so the result approves the reference.
     pub fn transaction_to_approve(&self,
                                   visited_hashes: &mut HashSet<Hash>,
                                   diff: &mut HashMap<Address, i64>,
                                   reference: Option<Hash>,
                                   extra_tip: Option<Hash>,
-                                  mut depth: u32,
-                                  iterations: u32) -> Result<Option<Hash>, TransactionError> {
-        if depth > self.max_depth {
-            depth = self.max_depth;
+                                  depth: u32,
+                                  iterations: u32,
+                                  selector: Option<&TipSelector>,
+                                  mut trace: Option<&mut SelectionTrace>) -> Result<Option<Hash>, TransactionError> {
+        let depth = check_depth(depth, self.max_depth, reference.is_some())?;
+        let selector = selector.unwrap_or(&*self.tip_selector);
+        if let Some(r) = reference {
+            if !selector.walks_from_entry_point() {
+                return Err(TransactionError::InvalidReference(r, "ignored by the uniform strategy"));
+            }
         }
+        if let Some(ref mut t) = trace {
+            t.strategy = selector.name().to_string();
+        }
+
         let latest_solid_subhive_milestone_index;
         let latest_solid_subhive_milestone;
 
@@ -143,21 +229,19 @@ impl TipsManager {
         }
 
         if latest_solid_subhive_milestone_index > self.milestone_start_index || latest_solid_subhive_milestone == self.milestone_start_index {
-            let mut ratings: HashMap<Hash, i64> = HashMap::new();
-            let mut analyzed_tips: HashSet<Hash> = HashSet::new();
             let mut max_depth_ok: HashSet<Hash> = HashSet::new();
 
+            if let (Some(r), None) = (reference, extra_tip) {
+                match self.hive.lock() {
+                    Ok(hive) => TipsManager::check_reference(&hive, &r, entry_point_index(latest_solid_subhive_milestone_index, depth))?,
+                    Err(_) => panic!("broken hive mutex")
+                };
+            }
+
             let tip = self.entry_point(reference,
                                        extra_tip.clone(),
                                        depth);
 
-            self.serial_update_ratings(visited_hashes,
-                                       tip,
-                                       &mut ratings,
-                                       &mut analyzed_tips,
-                                       extra_tip.clone());
-            analyzed_tips.clear();
-
             let update_diff_is_ok;
 
             if let Ok(mut lv) = self.ledger_validator.lock() {
@@ -166,16 +250,24 @@ impl TipsManager {
                 panic!("ledger validator is broken");
             }
 
+            if let Some(ref mut t) = trace {
+                t.entry_point = tip;
+                t.entry_point_consistent = update_diff_is_ok;
+            }
+
             if update_diff_is_ok {
-                return Ok(self.markov_chain_monte_carlo(visited_hashes,
-                                                          diff,
-                                                          tip,
-                                                          extra_tip,
-                                                          &mut ratings,
-                                                          iterations,
-                                                          latest_solid_subhive_milestone_index -
-                                                              depth * 2,
-                                                          &mut max_depth_ok)?);
+                debug!("selecting tip with {}", selector.name());
+                return selector.select(self, &mut SelectionContext {
+                    visited_hashes,
+                    diff,
+                    entry_point: tip,
+                    extra_tip,
+                    iterations,
+                    max_depth: min_walk_index(latest_solid_subhive_milestone_index, depth),
+                    max_depth_ok: &mut max_depth_ok,
+                    clock: self.walker.milestone_clock(),
+                    trace,
+                });
             } else {
                 error!("starting tip failed consistency check");
                 return Err(TransactionError::InvalidHash);
@@ -184,29 +276,48 @@ impl TipsManager {
         return Ok(None);
     }
 
+    /// A reference a walk is forced through must be stored, solid, not conflicting and not confirmed
+    /// below the entry point
+    pub fn check_reference(hive: &Hive, reference: &Hash, entry_point_index: u32) -> Result<(), TransactionError> {
+        let transaction = match hive.storage_load_transaction(reference) {
+            Some(t) => t,
+            None => return Err(TransactionError::InvalidHash)
+        };
+        if transaction.get_type() != TransactionType::Full {
+            return Err(TransactionError::InvalidHash);
+        }
+
+        if !transaction.is_solid() {
+            return Err(TransactionError::InvalidReference(*reference, "not solid"));
+        }
+        if hive.is_conflicting(reference) {
+            return Err(TransactionError::InvalidReference(*reference, "conflicting"));
+        }
+
+        let snapshot_index = transaction.object.get_snapshot_index();
+        if snapshot_index != 0 && snapshot_index < entry_point_index {
+            return Err(TransactionError::InvalidReference(*reference, "confirmed below depth"));
+        }
+        Ok(())
+    }
+
     fn entry_point(&self, reference: Option<Hash>, extra_tip: Option<Hash>, depth: u32) -> Hash {
+        //trunk
         if extra_tip.is_none() {
-            //trunk
             if let Some(r) = reference {
                 return r;
-            } else {
-                if let Ok(mlstn) = self.milestone.lock() {
-                    return mlstn.latest_solid_subhive_milestone;
-                } else {
-                    panic!("broken milestone mutex");
-                }
             }
         }
 
         if let Ok(milestone) = self.milestone.lock() {
-            //branch (extraTip)
-            let milestone_index = match milestone.latest_solid_subhive_milestone_index - 1 > depth {
-                true => milestone.latest_solid_subhive_milestone_index - depth - 1,
-                false => 0
-            };
+            let index = entry_point_index(milestone.latest_solid_subhive_milestone_index, depth);
+            if index == milestone.latest_solid_subhive_milestone_index {
+                return milestone.latest_solid_subhive_milestone;
+            }
 
             if let Ok(hive) = self.hive.lock() {
-                if let Some(milestone) = hive.find_closest_next_milestone(milestone_index, self.testnet, self.milestone_start_index) {
+                // the milestone right after `index - 1`, i.e. `index` unless it is missing
+                if let Some(milestone) = hive.find_closest_next_milestone(index.saturating_sub(1), self.testnet, self.milestone_start_index) {
                     let hash = milestone.get_hash();
                     if hash != HASH_NULL {
                         return hash;
@@ -221,169 +332,97 @@ impl TipsManager {
         }
     }
 
+    /// Walks with the given weighting from the entry point, the current rng drives the steps
     pub fn random_walk(&self,
                        visited_hashes: &HashSet<Hash>,
                        diff: &HashMap<Address, i64>,
                        start: Option<Hash>,
                        extra_tip: Option<Hash>,
-                       ratings: &mut HashMap<Hash, i64>,
                        max_depth: u32,
-                       max_depth_ok: &mut HashSet<Hash>) -> Result<Option<Hash>, TransactionError> {
-        let mut rnd = rand::thread_rng();
-        let mut tip = start.clone();
-        let mut tail = tip.clone();
-        let mut tips: Vec<Hash>;
-        let mut tip_set: HashSet<Hash>;
-        let mut analyzed_tips: HashSet<Hash> = HashSet::new();
-        let mut traversed_tails = 0;
-        let mut transaction_obj; // = Transaction::new();
-        let mut approver_index: usize;
-        let mut rating_weight: f64;
-        let mut walk_ratings: Vec<f64>;
-        let mut my_diff = diff.clone();
-        let mut my_approved_hashes = visited_hashes.clone();
-
-        while let Some(tip_hash) = tip {
-            transaction_obj = match self.hive.lock() {
-                Ok(hive) => hive.storage_load_transaction(&tip_hash).expect("tip is null"),
-                Err(_) => {
-                    panic!("hive mutex is broken");
-                }
-            };
-
-            tip_set = transaction_obj.get_approvers(&self.hive);
-
-            if transaction_obj.get_type() == TransactionType::HashOnly {
-                debug!("Reason to stop: transactionViewModel == null");
-                break;
-            }
-
-            let check_solidity_is_ok = match self.transaction_validator.lock() {
-                Ok(tv) => tv.check_solidity(transaction_obj.get_hash(), false)?,
-                Err(_) => panic!("broken transaction validator mutex")
-            };
-
-            if !check_solidity_is_ok {
-                debug!("Reason to stop: !checkSolidity");
-                break;
-            }
-
-
-            if self.below_max_depth(transaction_obj.get_hash(), max_depth, max_depth_ok) {
-                debug!("Reason to stop: !LedgerValidator");
-                break;
-            }
-
-            let update_diff_is_ok = match self.ledger_validator.lock() {
-                Ok(mut lv) => lv.update_diff(&mut my_approved_hashes, &mut my_diff,
-                                             transaction_obj.get_hash())?,
-                Err(_) => panic!("broken ledger validator mutex")
-            };
-
-            if !update_diff_is_ok {
-                debug!("Reason to stop: belowMaxDepth");
-                break;
-            }
-
-            if extra_tip.is_some() && transaction_obj.get_hash() == extra_tip.unwrap() {
-                debug!("Reason to stop: transactionViewModel==extraTip");
-                break;
-            }
-
-            tail = Some(tip_hash.clone());
-            traversed_tails += 1;
-
-            if tip_set.len() == 0 {
-                debug!("Reason to stop: TransactionViewModel is a tip");
-                break;
-            } else if tip_set.len() == 1 {
-                tip = tip_set.iter().next().cloned();
-            } else {
-                tips = TipsManager::set_to_vec(&tip_set);
-                if !ratings.contains_key(&tip_hash) {
-                    self.serial_update_ratings(
-                        &my_approved_hashes,
-                        tip_hash,
-                        ratings,
-                        &mut analyzed_tips,
-                        extra_tip);
-                    analyzed_tips.clear();
-                }
-
-                walk_ratings = Vec::with_capacity(tips.len());
-                let mut max_rating: f64 = 0.0;
-                let mut tip_rating: i64 = match ratings.get(&tip_hash) {
-                    Some(x) => *x,
-                    None => {
-                        debug!("no rating");
-                        0
-                    }
-                };
-
-                for i in 0..tips.len() {
-                    let v = ((tip_rating - TipsManager::get_or_default(ratings, tips[i], 0i64))
-                        as f32).powf(-3 as f32) as f64;
-                    walk_ratings.push(v);
-                    max_rating += v;
-                }
-
-                rating_weight = rnd.gen::<f64>() * max_rating;
-
-                approver_index = tips.len();
-                loop {
-                    approver_index -= 1;
-                    if approver_index > 1 {
-                        rating_weight -= walk_ratings[approver_index];
-                        if rating_weight <= 0f64 {
-                            break;
-                        }
-                    } else {
-                        break;
-                    }
-                }
-
-                tip = tips.get(approver_index as usize).cloned();
-                if let Some(h) = tip {
-                    if transaction_obj.get_hash() == h {
-                        break;
-                    }
-                }
-            }
-        }
-
-        return Ok(tail);
+                       max_depth_ok: &mut HashSet<Hash>,
+                       clock: MilestoneClock,
+                       alpha: f64,
+                       trace: Option<&mut WalkTrace>) -> Result<Option<Hash>, TransactionError> {
+        let mut rng = match self.rng.lock() {
+            Ok(rng) => rng,
+            Err(_) => panic!("broken rng mutex")
+        };
+        self.walker.random_walk(visited_hashes, diff, start, extra_tip, max_depth, max_depth_ok, clock,
+                                alpha, &mut *rng, trace)
     }
 
+    /// Runs the walks on the walk pool and returns the tail most of them ended on
     pub fn markov_chain_monte_carlo(&self,
                                     visited_hashes: &HashSet<Hash>,
                                     diff: &HashMap<Address, i64>,
                                     tip: Hash,
                                     extra_tip: Option<Hash>,
-                                    ratings: &mut HashMap<Hash, i64>,
                                     iterations: u32,
                                     max_depth: u32,
                                     max_depth_ok: &mut HashSet<Hash>,
-                                    /*Random seed*/) -> Result<Option<Hash>, TransactionError> {
-        let mut rnd = rand::thread_rng();
+                                    clock: MilestoneClock,
+                                    alpha: f64,
+                                    mut trace: Option<&mut SelectionTrace>) -> Result<Option<Hash>, TransactionError> {
+        // shared by the walks, each transaction they reach is read once
+        let snapshot = Arc::new(self.walker.snapshot(visited_hashes, diff, tip, extra_tip, max_depth, max_depth_ok,
+                                                        clock));
+        let tracing = trace.is_some();
+
+        // drawn up front, so a seeded node ends up on the same tails however the walks are scheduled
+        let seeds = match self.rng.lock() {
+            Ok(mut rng) => (0..iterations).map(|_| rng.gen::<usize>()).collect::<Vec<usize>>(),
+            Err(_) => panic!("broken rng mutex")
+        };
+
+        let (tx, rx) = channel();
+        for (i, seed) in seeds.into_iter().enumerate() {
+            let tx = tx.clone();
+            let snapshot = snapshot.clone();
+            self.walk_pool.execute(move || {
+                let mut rng: StdRng = SeedableRng::from_seed(&[seed, i][..]);
+                let mut walk_trace = if tracing { Some(WalkTrace::new()) } else { None };
+                let tail = Walker::snapshot_walk(&snapshot, alpha, &mut rng, walk_trace.as_mut());
+                let _ = tx.send((i, tail, walk_trace));
+            });
+        }
+        drop(tx);
+
+        // ends when every walk has either answered or died
+        let mut walks = (0..iterations).map(|_| None).collect::<Vec<_>>();
+        for (i, tail, walk_trace) in rx.iter() {
+            walks[i] = Some((tail, walk_trace));
+        }
+        debug!("walks read {} transactions", snapshot.len());
+        max_depth_ok.extend(snapshot.max_depth_ok());
+
+        // merged in walk order, not in the order the walks finished
         let mut monte_carlo_integrations = HashMap::<Hash, i32>::new();
-        let mut tail: Hash;
-//        println!("visited_hashes={:?}", visited_hashes);
-//        println!("diff={:?}", diff);
-        for _ in 0..iterations {
-            if let Some(tail) = self.random_walk(visited_hashes, diff, Some(tip), extra_tip,
-                                                ratings, max_depth,
-                                    max_depth_ok)? {
-                // TODO: make binding
-                if monte_carlo_integrations.contains_key(&tail) {
-                    let v = monte_carlo_integrations.get(&tail).cloned().unwrap();
-                    monte_carlo_integrations.insert(tail.clone(), v + 1);
-                } else {
-                    monte_carlo_integrations.insert(tail.clone(), 1);
+        for walk in walks.into_iter() {
+            let (tail, walk_trace) = match walk {
+                Some(w) => w,
+                None => {
+                    error!("random walk died");
+                    continue;
                 }
+            };
+
+            if let (Some(t), Some(walk_trace)) = (trace.as_mut(), walk_trace) {
+                t.walks.push(walk_trace);
+            }
+            if let Some(tail) = tail? {
+                *monte_carlo_integrations.entry(tail).or_insert(0) += 1;
             }
         }
 //        println!("monte_carlo_integrations={:?}", monte_carlo_integrations);
-        let (reduced, _) = monte_carlo_integrations.into_iter().fold((HASH_NULL, 0), |(a, a_v), (b,
+        // HashMap order differs between runs, ties must be broken in a fixed order to be reproducible
+        let mut integrations = monte_carlo_integrations.into_iter().collect::<Vec<(Hash, i32)>>();
+        integrations.sort_by(|a, b| a.0 .0.cmp(&b.0 .0));
+
+        let mut rnd = match self.rng.lock() {
+            Ok(rng) => rng,
+            Err(_) => panic!("broken rng mutex")
+        };
+        let (reduced, _) = integrations.into_iter().fold((HASH_NULL, 0), |(a, a_v), (b,
             b_v)| {
             if a_v > b_v {
                 return (a, a_v);
@@ -403,168 +442,54 @@ impl TipsManager {
         }
     }
 
-    fn set_to_vec(set: &HashSet<Hash>) -> Vec<Hash> {
-        let mut hash_iterator = set.iter();
-        let mut result: Vec<Hash> = Vec::with_capacity(set.len());
-        if !set.is_empty() {
-            loop {
-                match hash_iterator.next() {
-                    Some(hash) => result.push(hash.clone()),
-                    None => break
-                }
-            }
-        }
-        return result;
-    }
-
-    fn serial_update_ratings(&self,
-                             visited_hashes: &HashSet<Hash>,
-                             tx_hash: Hash,
-                             ratings: &mut HashMap<Hash, i64>,
-                             analyzed_tips: &mut HashSet<Hash>,
-                             extra_tip: Option<Hash>) {
-        let mut hashes_to_rate = Vec::<Hash>::new();
-        hashes_to_rate.push(tx_hash);
-        let mut current_hash: Hash;
-        let mut added_back;
-
-        while !hashes_to_rate.is_empty() {
-            current_hash = match hashes_to_rate.pop() {
-                Some(hash) => hash,
-                None => {
-                    continue;
-                }
-            };
-
-            let mut transaction = match self.hive.lock() {
-                Ok(hive) => hive.storage_load_transaction(&current_hash).expect("no such transaction"),
-                Err(_) => {
-                    panic!("hive mutex is broken");
-                }
-            };
-
-            added_back = false;
-            let mut approvers = transaction.get_approvers(&self.hive).clone();
-            for approver in &approvers {
-                if ratings.get(approver).is_none() && *approver != current_hash {
-                    if !added_back {
-                        added_back = true;
-                        hashes_to_rate.push(current_hash);
-                    }
-                    hashes_to_rate.push(*approver);
-                }
-            }
-            if !added_back && analyzed_tips.insert(current_hash) {
-                let rating = TipsManager::rating_calc(extra_tip, &visited_hashes, current_hash, &approvers, ratings);
-                ratings.insert(current_hash.clone(), rating);
-            }
-        }
-    }
-
-    fn rating_calc(extra_tip: Option<Hash>, visited_hashes: &HashSet<Hash>, current_hash: Hash,
-                   approvers: &HashSet<Hash>, ratings: &HashMap<Hash, i64>) -> i64 {
-        let mut result: i64;
-        result = match extra_tip.is_some() && visited_hashes.contains(&current_hash) {
-            true => 0,
-            false => 1
+    /// Whether an unconfirmed transaction is too deep for a walk to reach it, using the same
+    /// threshold as `transaction_to_approve`
+    pub fn is_orphaned(&self, hash: Hash) -> bool {
+        let latest_solid_subhive_milestone_index = match self.milestone.lock() {
+            Ok(m) => m.latest_solid_subhive_milestone_index,
+            Err(_) => panic!("broken milestone mutex")
         };
-
-        // TODO: cap_sum -> overflow_add
-        result += approvers.iter().
-            map(|x| ratings.get(x)).
-            filter(|x| x.is_some()).
-            fold(0i64, |a, b| TipsManager::cap_sum(a, *b.unwrap(), (<i64>::max_value() / 2)));
-        return result;
+        let depth = min_walk_index(latest_solid_subhive_milestone_index, self.max_depth);
+        self.walker.below_max_depth(hash, depth, &mut HashSet::new())
     }
 
-    fn get_or_default(map: &HashMap<Hash, i64>, key: Hash, default_value: i64) -> i64 {
-        let result = match map.get(&key) {
-            Some(x) => *x,
-            None => default_value
+    /// Drops the conflicting mark of spends that are orphaned, their status then reads as orphaned
+    /// like that of any transaction the walks can't reach. Spends confirmed after all stop being
+    /// watched.
+    pub fn clear_orphaned_conflicts(&self) {
+        let marked_spends = match self.ledger_validator.lock() {
+            Ok(lv) => lv.marked_spends(),
+            Err(_) => panic!("broken ledger validator mutex")
         };
-        result
-    }
-
-    fn below_max_depth(&self, tip: Hash, depth: u32, max_depth_ok: &mut HashSet<Hash>) -> bool {
-        //if tip is confirmed stop
-
-//         println!("hive lock 19");
-        let mut transaction = match self.hive.lock() {
-            Ok(hive) => hive.storage_load_transaction(&tip).expect("can't find transaction"),
-            Err(_) => {
-                panic!("hive mutex is broken");
-            }
-        };
-//         println!("hive unlock 19");
-
-        if transaction.object.get_snapshot_index() >= depth {
-            return false;
+        if marked_spends.is_empty() {
+            return;
         }
 
-        //if tip unconfirmed, check if any referenced tx is confirmed below maxDepth
-        let mut non_analyzed_transactions = LinkedList::new();
-        non_analyzed_transactions.push_back(tip);
-        let mut analyzed_transactions: HashSet<Hash> = HashSet::new();
-
-        while let Some(hash) = non_analyzed_transactions.pop_front() {
-            if analyzed_transactions.insert(hash) {
-//                 println!("hive lock 20");
-                let mut transaction = match self.hive.lock() {
-                    Ok(hive) => hive.storage_load_transaction(&hash).expect("can't load \
-                    transaction"),
-                    Err(_) => {
-                        panic!("hive mutex is broken");
+        let mut settled = Vec::new();
+        for hash in marked_spends {
+            let conflicting = match self.hive.lock() {
+                Ok(hive) => hive.is_conflicting(&hash),
+                Err(_) => panic!("broken hive mutex")
+            };
+            if !conflicting {
+                settled.push(hash);
+            } else if self.is_orphaned(hash) {
+                match self.hive.lock() {
+                    Ok(mut hive) => {
+                        let mut metadata = hive.storage_load_transaction_metadata(&hash);
+                        metadata.status = TransactionStatus::Unknown;
+                        hive.put_transaction_metadata(&hash, &metadata);
                     }
+                    Err(_) => panic!("broken hive mutex")
                 };
-                // println!("hive unlock 20");
-
-                if transaction.object.get_snapshot_index() != 0 && transaction.object.get_snapshot_index() < depth {
-                    return true;
-                }
-                if transaction.object.get_snapshot_index() == 0 {
-                    if !max_depth_ok.contains(&hash) {
-                        non_analyzed_transactions.push_back(transaction.get_trunk_transaction_hash());
-                        non_analyzed_transactions.push_back(transaction.get_branch_transaction_hash());
-                    }
-                }
+                settled.push(hash);
             }
         }
-        max_depth_ok.insert(tip);
-        return false;
-    }
 
-    pub fn recursive_update_ratings(&self,
-                                    tx_hash: Hash,
-                                    ratings: &mut HashMap<Hash, i64>,
-                                    analyzed_tips: &mut HashSet<Hash>) -> i64 {
-        let mut rating = 1;
-        if analyzed_tips.insert(tx_hash) {
-            // println!("hive lock 21");
-            let mut transaction = match self.hive.lock() {
-                Ok(hive) => hive.storage_load_transaction(&tx_hash).expect("tip is null"),
-                Err(_) => {
-                    panic!("hive mutex is broken");
-                }
-            };
-            // println!("hive unlock 21");
-
-            let mut approver_hashes = transaction.get_approvers(&self.hive);
-            for approver in approver_hashes.iter() {
-                rating = TipsManager::cap_sum(rating, self.recursive_update_ratings(approver.clone(), ratings, analyzed_tips), (<i64>::max_value() / 2));
-            }
-            ratings.insert(tx_hash.clone(), rating);
-        } else {
-            // TODO: optimize
-            if ratings.contains_key(&tx_hash) {
-                rating = match ratings.get(&tx_hash) {
-                    Some(x) => *x,
-                    None => 0,
-                };
-            } else {
-                rating = 0;
-            }
-        }
-        return rating;
+        match self.ledger_validator.lock() {
+            Ok(mut lv) => lv.forget_marked_spends(&settled),
+            Err(_) => panic!("broken ledger validator mutex")
+        };
     }
 
     pub fn shutdown(&mut self) {
@@ -573,11 +498,4 @@ impl TipsManager {
             jh.join();
         };
     }
-
-    fn cap_sum(a: i64, b: i64, max: i64) -> i64 {
-        if a + b < 0 || a + b > max {
-            return max;
-        }
-        return a + b;
-    }
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 use std::collections::HashSet;
 use model::transaction::Hash;
-use rand::{Rng, thread_rng};
+use rand::{Rng, StdRng};
 
 extern crate linked_hash_set;
 use self::linked_hash_set::LinkedHashSet;
@@ -10,13 +10,15 @@ pub const MAX_TIPS: u32 = 5000;
 pub struct TipsViewModel {
     pub tips: LinkedHashSet<Hash>,
     pub solid_tips: LinkedHashSet<Hash>,
+    rng: StdRng,
 }
 
 impl TipsViewModel {
-    pub fn new() -> Self {
+    pub fn new(rng: StdRng) -> Self {
         TipsViewModel {
             tips: LinkedHashSet::new(),
             solid_tips: LinkedHashSet::new(),
+            rng,
         }
     }
 
@@ -29,6 +31,16 @@ impl TipsViewModel {
         self.tips.insert(hash);
     }
 
+    /// Refills the sets from `(hash, solid)` pairs, e.g. `Hive::storage_load_tips` after a restart
+    pub fn load_tips(&mut self, tips: &[(Hash, bool)]) {
+        for &(hash, solid) in tips {
+            self.add_tip(hash);
+            if solid {
+                self.set_solid(&hash);
+            }
+        }
+    }
+
     pub fn remove_tip(&mut self, hash: &Hash) {
         if !self.tips.remove(hash) {
             self.solid_tips.remove(hash);
@@ -66,7 +78,7 @@ impl TipsViewModel {
             return None;
         }
 
-        let index = thread_rng().gen_range(0, len);
+        let index = self.rng.gen_range(0, len);
 
         let hash = self.tips.iter().skip(index).next().cloned();
         hash
@@ -78,7 +90,7 @@ impl TipsViewModel {
             return self.get_random_tip();
         }
 
-        let index = thread_rng().gen_range(0, len);
+        let index = self.rng.gen_range(0, len);
 
         let hash = self.solid_tips.iter().skip(index).next().cloned();
         hash
@@ -13,36 +13,86 @@ use std::collections::HashSet;
 use std::clone::Clone;
 use self::ntrumls::{NTRUMLS, Signature, PrivateKey, PublicKey, PQParamSetID};
 use utils::defines::AM;
+use model::transaction_validator::TransactionError;
+use model::signature_verifier::verify_signature;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use self::rustc_serialize::{
     hex::{FromHex, ToHex},
     Encodable, Decodable, Encoder, Decoder
 };
 pub const HASH_SIZE: usize = 20;
 pub const ADDRESS_SIZE: usize = 21;
-pub const TRANSACTION_SIZE: usize = 173 + 4; // HASH_SIZE + 1 (checksum byte)
+pub const TRANSACTION_SIZE: usize = 173 + 4 + 4; // HASH_SIZE + 1 (checksum byte), 4 for the length byte and padding of the payload array
+pub const MAX_PAYLOAD_SIZE: usize = 2048;
 
 pub const HASH_NULL: Hash = Hash([0u8; HASH_SIZE]);
 pub const ADDRESS_NULL: Address = Address([0u8; ADDRESS_SIZE]);
 
-#[derive(PartialEq, Clone, Copy, Eq, Hash)]
-pub struct Hash(pub [u8; HASH_SIZE]);
+/// Proof-of-work difficulty: the number of leading zero bits of `sha3(hash || nonce)`. Used both as the required minimum weight magnitude and as
+/// the weight actually reached by a transaction.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct Difficulty(pub u32);
 
-impl Hash {
-    pub fn trailing_zeros(&self) -> u16 {
-        let mut zeros = 0u16;
-        for i in 0..HASH_SIZE as usize {
-            let x = self.0[i / 8];
-            let y = (0b10000000 >> (i % 8)) as u8;
+impl Difficulty {
+    pub const MAX: u32 = 256;
 
-            if x & y != 0 {
-                break;
-            }
-            zeros += 1u16;
+    pub fn new(bits: u32) -> Self {
+        Difficulty(if bits > Difficulty::MAX { Difficulty::MAX } else { bits })
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn is_met_by(&self, weight: Difficulty) -> bool {
+        weight >= *self
+    }
+}
+
+fn pow_input(hash: &Hash, nonce: u64) -> SerializedBuffer {
+    let mut in_buf = SerializedBuffer::new_with_size(HASH_SIZE + 8);
+    in_buf.write_bytes(hash);
+    in_buf.write_u64(nonce);
+    in_buf
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for b in bytes {
+        zeros += b.leading_zeros();
+        if *b != 0 {
+            break;
         }
-        zeros
     }
+    zeros
+}
 
+/// The only proof-of-work function: weight reached by `nonce` for the transaction `hash`. The hash
+/// covers the essence, both parents and the attachment time, so a nonce can't be reused elsewhere.
+pub fn pow_weight(hash: &Hash, nonce: u64) -> Difficulty {
+    let in_buf = pow_input(hash, nonce);
+    let mut sha = Sha3::sha3_256();
+    let mut buf = [0u8; 32];
+    sha.input(&in_buf.buffer);
+    sha.result(&mut buf);
+    Difficulty(leading_zero_bits(&buf))
+}
+
+/// Attachment time in milliseconds, falling back to the issuing timestamp for transactions
+/// attached without one
+pub fn attachment_time_ms(object: &TransactionObject) -> u64 {
+    if object.attachment_timestamp != 0 {
+        object.attachment_timestamp
+    } else {
+        object.timestamp.saturating_mul(1000)
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Eq, Hash)]
+pub struct Hash(pub [u8; HASH_SIZE]);
+
+impl Hash {
     pub fn is_null(&self) -> bool {
         *self == HASH_NULL
     }
@@ -233,6 +283,14 @@ impl Address {
         addr[20] = checksum_byte;
         addr
     }
+
+    // coordinator address, commits to the whole tree of milestone keys
+    pub fn from_merkle_root(root: &Hash) -> Self {
+        let mut addr = ADDRESS_NULL;
+        addr[..HASH_SIZE].copy_from_slice(&root.0);
+        addr[HASH_SIZE] = Address::calculate_checksum(&root.0);
+        addr
+    }
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -302,14 +360,17 @@ pub struct TransactionObject {
     pub snapshot: u32,
     pub solid: bool,
     pub height: u64,
+    pub payload: Vec<u8>,
 }
 
 #[derive(Clone)]
 pub struct Transaction {
     pub object: TransactionObject,
     pub bytes: SerializedBuffer,
-    pub weight_magnitude: u16,
+    pub weight_magnitude: Difficulty,
     pub approvers: Option<Approvee>,
+    // set when a byte array of the transaction couldn't be read
+    pub malformed: bool,
 }
 
 impl Transaction {
@@ -358,10 +419,11 @@ impl Transaction {
         let mut bytes = SerializedBuffer::new_with_size(TRANSACTION_SIZE);
         transaction.serialize_to_stream(&mut bytes);
         Transaction {
-            weight_magnitude: transaction.hash.trailing_zeros(),
+            weight_magnitude: transaction.pow_weight(),
             object: transaction,
             bytes,
             approvers: None,
+            malformed: false,
         }
     }
 
@@ -375,17 +437,36 @@ impl Transaction {
 
     pub fn from_bytes(mut bytes: SerializedBuffer) -> Self {
         let mut transaction = TransactionObject::new();
-        transaction.read_params(&mut bytes);
+        let malformed = !Transaction::read_object(&mut transaction, &mut bytes);
 //        transaction.data_type = TransactionType::Full;
 
         Transaction {
-            weight_magnitude: transaction.hash.trailing_zeros(),
+            weight_magnitude: transaction.pow_weight(),
             object: transaction,
             bytes,
             approvers: None,
+            malformed,
         }
     }
 
+    // Transaction sent by a peer, `malformed` when it couldn't be read completely
+    pub fn read_from_stream(stream: &mut SerializedBuffer) -> Self {
+        let mut object = TransactionObject::new();
+        let complete = Transaction::read_object(&mut object, stream);
+        let mut transaction = Transaction::from_object(object);
+        transaction.malformed = !complete;
+        transaction
+    }
+
+    // A byte array that can't be read is left empty without consuming the stream, so less is read
+    // than the object serializes to
+    pub fn read_object(object: &mut TransactionObject, stream: &mut SerializedBuffer) -> bool {
+        use network::packet::calculate_object_size;
+        let start = stream.position();
+        object.read_params(stream);
+        stream.position() - start + 4 == calculate_object_size(object)
+    }
+
     pub fn from_object(mut transaction: TransactionObject) -> Self {
         use network::packet::calculate_object_size;
         let transaction_size = calculate_object_size(&transaction);
@@ -393,10 +474,11 @@ impl Transaction {
         transaction.serialize_to_stream(&mut bytes);
 
         Transaction {
-            weight_magnitude: transaction.hash.trailing_zeros(),
+            weight_magnitude: transaction.pow_weight(),
             object: transaction,
             bytes,
             approvers: None,
+            malformed: false,
         }
     }
 
@@ -406,10 +488,11 @@ impl Transaction {
         transaction.serialize_to_stream(&mut bytes);
 
         Transaction {
-            weight_magnitude: transaction.hash.trailing_zeros(),
+            weight_magnitude: transaction.pow_weight(),
             object: transaction,
             bytes,
             approvers: None,
+            malformed: false,
         }
     }
 
@@ -417,7 +500,7 @@ impl Transaction {
         let ntrumls = NTRUMLS::with_param_set(PQParamSetID::Security269Bit);
         debug!("signing {:?}", self.object.hash);
 //        println!("signing {:?} {:?} {:?}", self.object.hash, sk, pk);
-        ntrumls.sign(&self.object.hash, sk, pk)
+        ntrumls.sign(&self.object.essence_hash(), sk, pk)
     }
 
     pub fn new_random() -> Self {
@@ -426,10 +509,11 @@ impl Transaction {
         transaction.serialize_to_stream(&mut bytes);
 
         Transaction {
-            weight_magnitude: transaction.hash.trailing_zeros(),
+            weight_magnitude: transaction.pow_weight(),
             object: transaction,
             bytes,
             approvers: None,
+            malformed: false,
         }
     }
 
@@ -438,11 +522,13 @@ impl Transaction {
             self.object.serialize_to_stream(&mut self.bytes);
         }
 
-        let mut sb = SerializedBuffer::new_with_size(ADDRESS_SIZE + 4 + 8 + HASH_SIZE);
-        sb.write_bytes(&self.object.address);
-        sb.write_u32(self.object.value);
-        sb.write_u64(self.object.timestamp);
-        sb.write_bytes(&self.object.tag);
+        // the attachment is part of the identity, so every attachment of the same signed essence
+        // is a transaction of its own
+        let mut sb = SerializedBuffer::new_with_size(HASH_SIZE * 3 + 8);
+        sb.write_bytes(&self.object.essence_hash());
+        sb.write_bytes(&self.object.trunk_transaction);
+        sb.write_bytes(&self.object.branch_transaction);
+        sb.write_u64(self.object.attachment_timestamp);
 
         let mut sha = Sha3::sha3_256();
         sha.input(&sb.buffer);
@@ -452,35 +538,43 @@ impl Transaction {
         Hash(buf)
     }
 
-    pub fn find_nonce(&self, mwm: u32) -> u64 {
+    pub fn find_nonce(&self, mwm: Difficulty) -> u64 {
+        let interrupted = AtomicBool::new(false);
+        self.find_nonce_interruptible(mwm, &interrupted).unwrap_or(0)
+    }
+
+    // The hash must already be set. Returns None if `interrupted` was set before a suitable nonce
+    // was found
+    pub fn find_nonce_interruptible(&self, mwm: Difficulty, interrupted: &AtomicBool) -> Option<u64> {
         let mut nonce = 0;
         let mut sha = Sha3::sha3_256();
         let mut buf = [0u8; 32];
 
-        let mut in_buf = SerializedBuffer::new_with_size(HASH_SIZE * 2 + 8);
-        in_buf.write_bytes(&self.object.branch_transaction);
-        in_buf.write_bytes(&self.object.trunk_transaction);
-        in_buf.write_u64(nonce);
+        let mut in_buf = pow_input(&self.object.hash, nonce);
+
+        loop {
+            if nonce & 0x3ff == 0 && interrupted.load(Ordering::Relaxed) {
+                return None;
+            }
 
-        'nonce_loop: loop {
             sha.input(&in_buf.buffer);
             sha.result(&mut buf);
 
-            for i in 0..mwm as usize {
-                let x = buf[i / 8];
-                let y = (0b10000000 >> (i % 8)) as u8;
-
-                if x & y != 0 {
-                    sha.reset();
-                    nonce += 1;
-                    in_buf.set_position(40);
-                    in_buf.write_u64(nonce);
-                    continue 'nonce_loop;
-                }
+            if mwm.is_met_by(Difficulty(leading_zero_bits(&buf))) {
+                break;
             }
-            break;
+
+            sha.reset();
+            nonce += 1;
+            in_buf.set_position(HASH_SIZE);
+            in_buf.write_u64(nonce);
         }
-        nonce
+        Some(nonce)
+    }
+
+    pub fn calculate_weight_magnitude(&mut self) -> Difficulty {
+        self.weight_magnitude = self.object.pow_weight();
+        self.weight_magnitude
     }
 
     pub fn update_solidity(&mut self, solid: bool) -> bool {
@@ -502,7 +596,9 @@ impl Transaction {
 }
 
 impl TransactionObject {
-    pub const SVUID: i32 = 342631123;
+    // changed from 342631123 when the length-prefixed payload was appended: peers on the old layout
+    // are dropped as an unknown SVUID, and storage written before it has to be synced again
+    pub const SVUID: i32 = 518264937;
 
     pub fn new() -> Self {
         TransactionObject::from_hash(HASH_NULL)
@@ -526,7 +622,8 @@ impl TransactionObject {
             signature_pubkey: PublicKey(vec![]),
             snapshot: 0u32,
             solid: false,
-            height: 0
+            height: 0,
+            payload: vec![]
         }
     }
 
@@ -574,13 +671,38 @@ impl TransactionObject {
             data_type: TransactionType::Full,
             snapshot,
             solid: false,
-            height: 0
+            height: 0,
+            payload: vec![]
         }
     }
 
     pub fn get_snapshot_index(&self) -> u32{
         return self.snapshot;
     }
+
+    pub fn pow_weight(&self) -> Difficulty {
+        pow_weight(&self.hash, self.nonce)
+    }
+
+    /// Hash of what the signature covers. Attaching doesn't change it, so signed transactions can
+    /// be attached again without the key, the ledger moves the value of an essence only once.
+    pub fn essence_hash(&self) -> Hash {
+        // the payload goes last and raw, an empty one adds no bytes, so a transaction without payload
+        // has the essence hash it had before payloads existed
+        let mut sb = SerializedBuffer::new_with_size(ADDRESS_SIZE + 4 + 8 + HASH_SIZE + self.payload.len());
+        sb.write_bytes(&self.address);
+        sb.write_u32(self.value);
+        sb.write_u64(self.timestamp);
+        sb.write_bytes(&self.tag);
+        sb.write_bytes(&self.payload);
+
+        let mut sha = Sha3::sha3_256();
+        sha.input(&sb.buffer);
+
+        let mut buf = [0u8; HASH_SIZE];
+        sha.result(&mut buf);
+        Hash(buf)
+    }
 }
 
 impl Serializable for TransactionObject {
@@ -607,6 +729,7 @@ impl Serializable for TransactionObject {
         stream.write_u32(self.snapshot);
         stream.write_bool(self.solid);
         stream.write_u64(self.height);
+        stream.write_byte_array(&self.payload);
     }
 
     fn read_params(&mut self, stream: &mut SerializedBuffer) {
@@ -630,6 +753,7 @@ impl Serializable for TransactionObject {
         self.snapshot = stream.read_u32();
         self.solid = stream.read_bool();
         self.height = stream.read_u64();
+        self.payload = stream.read_byte_array().unwrap_or(vec![]);
     }
 }
 
@@ -639,50 +763,34 @@ impl Serializable for Transaction {
     }
 
     fn read_params(&mut self, stream: &mut SerializedBuffer) {
-        self.object.read_params(stream);
+        self.malformed = !Transaction::read_object(&mut self.object, stream);
+    }
+}
+
+pub fn validate_transaction(transaction: &mut Transaction, mwm: Difficulty) -> Result<(), TransactionError> {
+    validate_hash_and_weight(transaction, mwm)?;
+
+    // check signature
+    if !verify_signature(&transaction.object) {
+        return Err(TransactionError::InvalidSignature);
     }
+
+    Ok(())
 }
 
-// TODO: return Result
-pub fn validate_transaction(transaction: &mut Transaction, mwm: u32) -> bool {
+// Everything but the signature, which is the expensive part
+pub fn validate_hash_and_weight(transaction: &mut Transaction, mwm: Difficulty) -> Result<(), TransactionError> {
     // check hash
     let calculated_hash = transaction.calculate_hash();
     if transaction.object.hash != calculated_hash {
-        error!("wrong hash {:?}", calculated_hash);
-        return false;
+        return Err(TransactionError::HashMismatch(calculated_hash));
     }
 
     // check nonce
-    let mut nonce = 0;
-    let mut sha = Sha3::sha3_256();
-    let mut buf = [0u8; 32];
-
-    let mut in_buf = SerializedBuffer::new_with_size(HASH_SIZE * 2 + 8);
-    in_buf.write_bytes(&transaction.object.branch_transaction);
-    in_buf.write_bytes(&transaction.object.trunk_transaction);
-    in_buf.write_u64(transaction.object.nonce);
-
-    sha.input(&in_buf.buffer);
-    sha.result(&mut buf);
-
-//    for i in 0..MIN_WEIGHT_MAGNITUDE as usize {
-    for i in 0..mwm as usize {
-        let x = buf[i / 8];
-        let y = (0b10000000 >> (i % 8)) as u8;
-
-        if x & y != 0 {
-            error!("wrong mwm {:?}", buf);
-            return false;
-        }
+    let weight = transaction.calculate_weight_magnitude();
+    if !mwm.is_met_by(weight) {
+        return Err(TransactionError::InsufficientWeight { required: mwm, actual: weight });
     }
 
-    // check signature
-    let sign = &transaction.object.signature;
-    let pk = &transaction.object.signature_pubkey;
-    let ntrumls = NTRUMLS::with_param_set(PQParamSetID::Security269Bit);
-//    let pk = PublicKey(address_from.0.to_vec());
-//    println!("{:?}", pk);
-//    println!("{:?}", transaction.object.hash);
-//    println!("{:?}", transaction.object.signature);
-    ntrumls.verify(&transaction.object.hash, sign, &pk)
-}
\ No newline at end of file
+    Ok(())
+}
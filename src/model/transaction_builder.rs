@@ -0,0 +1,122 @@
+extern crate ntrumls;
+
+use std::sync::Arc;
+use self::ntrumls::{Signature, PrivateKey, PublicKey};
+use model::{Transaction, TransactionObject, TransactionType};
+use model::transaction::*;
+use model::transaction_validator::{self, TransactionError, MAINNET_MIN_WEIGHT_MAGNITUDE};
+use utils::{Clock, SystemClock};
+
+/// Builds a complete, signed transaction. `build` fills the timestamps, nonce, hash, signature and
+/// public key in the right order and checks the result with the same rules the node uses.
+pub struct TransactionBuilder {
+    recipient: Address,
+    value: u32,
+    tag: Hash,
+    trunk: Hash,
+    branch: Hash,
+    payload: Vec<u8>,
+    mwm: Difficulty,
+    snapshot_timestamp_ms: u64,
+    clock: Arc<Clock>,
+}
+
+impl TransactionBuilder {
+    pub fn new() -> Self {
+        TransactionBuilder {
+            recipient: ADDRESS_NULL,
+            value: 0,
+            tag: HASH_NULL,
+            trunk: HASH_NULL,
+            branch: HASH_NULL,
+            payload: vec![],
+            mwm: Difficulty(MAINNET_MIN_WEIGHT_MAGNITUDE),
+            snapshot_timestamp_ms: 0,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn recipient(mut self, address: Address) -> Self {
+        self.recipient = address;
+        self
+    }
+
+    pub fn value(mut self, value: u32) -> Self {
+        self.value = value;
+        self
+    }
+
+    pub fn tag(mut self, tag: Hash) -> Self {
+        self.tag = tag;
+        self
+    }
+
+    pub fn parents(mut self, trunk: Hash, branch: Hash) -> Self {
+        self.trunk = trunk;
+        self.branch = branch;
+        self
+    }
+
+    pub fn payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    pub fn min_weight_magnitude(mut self, mwm: Difficulty) -> Self {
+        self.mwm = mwm;
+        self
+    }
+
+    pub fn snapshot_timestamp(mut self, snapshot_timestamp_ms: u64) -> Self {
+        self.snapshot_timestamp_ms = snapshot_timestamp_ms;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn build(self, sk: &PrivateKey, pk: &PublicKey) -> Result<Transaction, TransactionError> {
+        if self.payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(TransactionError::InvalidData);
+        }
+
+        let now_ms = self.clock.now_ms();
+
+        let object = TransactionObject {
+            address: self.recipient,
+            attachment_timestamp: now_ms,
+            attachment_timestamp_lower_bound: 0u64,
+            attachment_timestamp_upper_bound: ::std::u64::MAX,
+            branch_transaction: self.branch,
+            trunk_transaction: self.trunk,
+            hash: HASH_NULL,
+            nonce: 0,
+            tag: self.tag,
+            timestamp: now_ms / 1000,
+            value: self.value,
+            data_type: TransactionType::Full,
+            signature: Signature(vec![]),
+            signature_pubkey: PublicKey(vec![]),
+            snapshot: 0,
+            solid: false,
+            height: 0,
+            payload: self.payload,
+        };
+
+        let mut transaction = Transaction::from_object(object);
+        transaction.object.hash = transaction.calculate_hash();
+        transaction.object.nonce = transaction.find_nonce(self.mwm);
+        transaction.object.signature = match transaction.calculate_signature(sk, pk) {
+            Some(signature) => signature,
+            None => return Err(TransactionError::InvalidSignature)
+        };
+        transaction.object.signature_pubkey = pk.clone();
+
+        let mut transaction = Transaction::from_object(transaction.object);
+        transaction_validator::validate(&mut transaction, self.mwm, self.snapshot_timestamp_ms, now_ms)?;
+
+        Ok(transaction)
+    }
+}
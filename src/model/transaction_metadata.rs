@@ -0,0 +1,102 @@
+use network::packet::*;
+
+/// What the node knows about a transaction. Only `Confirmed` and `Conflicting` are stored, any
+/// other status is stored as `Unknown` and derived from the transaction itself when asked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionStatus {
+    Unknown,
+    // stored, but some of its history is missing
+    Pending,
+    Solid,
+    // confirmed by the milestone with that index
+    Confirmed(u32),
+    // spends funds that a confirmed transaction already spent
+    Conflicting,
+    // unconfirmed and too deep below the latest milestone to be picked by tip selection
+    Orphaned,
+}
+
+impl TransactionStatus {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TransactionStatus::Unknown => "unknown",
+            TransactionStatus::Pending => "pending",
+            TransactionStatus::Solid => "solid",
+            TransactionStatus::Confirmed(_) => "confirmed",
+            TransactionStatus::Conflicting => "conflicting",
+            TransactionStatus::Orphaned => "orphaned",
+        }
+    }
+
+    pub fn milestone_index(&self) -> u32 {
+        match *self {
+            TransactionStatus::Confirmed(index) => index,
+            _ => 0
+        }
+    }
+
+    fn code(&self) -> i32 {
+        match *self {
+            TransactionStatus::Confirmed(_) => 3,
+            TransactionStatus::Conflicting => 4,
+            _ => 0,
+        }
+    }
+
+    fn from_code(code: i32, milestone_index: u32) -> Self {
+        match code {
+            3 => TransactionStatus::Confirmed(milestone_index),
+            4 => TransactionStatus::Conflicting,
+            _ => TransactionStatus::Unknown
+        }
+    }
+}
+
+/// Node-local state of a transaction, stored apart from the transaction itself in the
+/// "transaction-metadata" column family
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionMetadata {
+    pub status: TransactionStatus,
+    // number of stored transactions approving this one, directly or not. Approximate: a new
+    // transaction only adds itself to its first MAX_WEIGHT_PROPAGATION unconfirmed ancestors, so
+    // deeper ones stay below their real count. Tip selection only walks the recent subhive, which
+    // is within the limit.
+    pub approvers_count: u32,
+}
+
+impl TransactionMetadata {
+    pub const SVUID: i32 = 61427735;
+
+    pub fn new() -> Self {
+        TransactionMetadata {
+            status: TransactionStatus::Unknown,
+            approvers_count: 0,
+        }
+    }
+
+    pub fn is_conflicting(&self) -> bool {
+        self.status == TransactionStatus::Conflicting
+    }
+
+    /// The transaction itself plus its approvers
+    pub fn cumulative_weight(&self) -> i64 {
+        1 + self.approvers_count as i64
+    }
+}
+
+impl Serializable for TransactionMetadata {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(TransactionMetadata::SVUID);
+
+        stream.write_i32(self.status.code());
+        stream.write_u32(self.status.milestone_index());
+        stream.write_u32(self.approvers_count);
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        let code = stream.read_i32();
+        let milestone_index = stream.read_u32();
+        self.status = TransactionStatus::from_code(code, milestone_index);
+        self.approvers_count = stream.read_u32();
+    }
+}
@@ -4,7 +4,7 @@ use self::linked_hash_set::LinkedHashSet;
 use model::transaction::*;
 use std::time::{Duration, SystemTime};
 use std::time;
-use rand::{Rng, thread_rng};
+use rand::{Rng, StdRng};
 use storage::Hive;
 use utils::*;
 
@@ -16,16 +16,18 @@ pub struct TransactionRequester {
     last_time: SystemTime,
     p_remove_request: f64,
     hive: AM<Hive>,
+    rng: StdRng,
 }
 
 impl TransactionRequester {
-    pub fn new(hive: AM<Hive>, p_remove_request: f64) -> Self {
+    pub fn new(hive: AM<Hive>, p_remove_request: f64, rng: StdRng) -> Self {
         TransactionRequester {
             milestone_transactions_to_request: LinkedHashSet::new(),
             transactions_to_request: LinkedHashSet::new(),
             last_time: SystemTime::now(),
             p_remove_request,
             hive,
+            rng,
         }
     }
 
@@ -44,11 +46,15 @@ impl TransactionRequester {
 
     pub fn clear_transaction_request(&mut self, hash: Hash) -> bool {
         let milestone = self.milestone_transactions_to_request.remove(&hash);
-        let normal = self.milestone_transactions_to_request.remove(&hash);
+        let normal = self.transactions_to_request.remove(&hash);
 
         milestone || normal
     }
 
+    pub fn is_milestone_request(&self, hash: &Hash) -> bool {
+        self.milestone_transactions_to_request.contains(hash)
+    }
+
     pub fn request_transaction(&mut self, hash: Hash, milestone: bool) {
         debug!("Added tx to request {:?}", hash);
         if let Ok(hive) = self.hive.lock() {
@@ -113,7 +119,7 @@ impl TransactionRequester {
             }
         }
 
-        if !milestone && thread_rng().gen_range(0.0, 1.0) < self.p_remove_request {
+        if !milestone && self.rng.gen_range(0.0, 1.0) < self.p_remove_request {
             if let Some(ref hash) = hash {
                 self.transactions_to_request.remove(hash);
             }
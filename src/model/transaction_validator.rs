@@ -1,6 +1,6 @@
 extern crate linked_hash_set;
 
-use utils::{AM, AWM};
+use utils::{AM, AWM, Clock, SystemClock};
 use storage::Hive;
 use model::{TipsViewModel, Transaction};
 use model::transaction::*;
@@ -8,74 +8,161 @@ use std::time::Duration;
 use std::thread::JoinHandle;
 use std::thread;
 use std::time;
+use std::fmt;
 use std::sync::{Arc, Mutex};
-use std::collections::{HashSet, LinkedList};
+use std::collections::{HashMap, HashSet, LinkedList, VecDeque};
 use std::sync::atomic::{AtomicBool, Ordering};
 use self::linked_hash_set::LinkedHashSet;
 use model::TransactionRequester;
+use model::TransactionStatus;
+use model::signature_verifier::verify_signature;
 
-// TODO: make Mutex
-pub static mut SNAPSHOT_TIMESTAMP: u64 = 0; //Duration = Duration::from_secs(0);
-pub static mut SNAPSHOT_TIMESTAMP_MS: u64 = 0; //Duration = Duration::from_secs(0);
 pub const MAX_TIMESTAMP_FUTURE: u64 = 2 * 60 * 60; //Duration = Duration::from_secs(2 * 60 * 60);
 pub const MAX_TIMESTAMP_FUTURE_MS: u64 = MAX_TIMESTAMP_FUTURE * 1000;
+pub const MAINNET_MIN_WEIGHT_MAGNITUDE: u32 = 9;
+// ancestors whose weight a new transaction updates at most, see `TransactionMetadata::approvers_count`
+pub const MAX_WEIGHT_PROPAGATION: usize = 5000;
 
 pub struct TransactionValidator {
     hive: AM<Hive>,
     tips_view_model: AM<TipsViewModel>,
-    min_weight_magnitude: u32,
+    min_weight_magnitude: Difficulty,
+    snapshot_timestamp_ms: u64,
+    clock: Arc<Clock>,
     propagation_thread: Option<JoinHandle<()>>,
     use_first: Arc<AtomicBool>,
     running: Arc<AtomicBool>,
     new_solid_transaction_list_one: AM<LinkedHashSet<Hash>>,
     new_solid_transaction_list_two: AM<LinkedHashSet<Hash>>,
+    // stored transactions whose weight isn't propagated yet, in the order they were stored
+    weight_queue: AM<VecDeque<Hash>>,
     transaction_requester: AM<TransactionRequester>,
 }
 
-pub fn has_invalid_timestamp(transaction: &Transaction) -> bool {
-    // TODO: make with millis
-//    let max_future_ts: Duration = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap() +
-//        MAX_TIMESTAMP_FUTURE;
-//
-//    if transaction.object.attachment_timestamp == 0 {
-//        unsafe {
-//            return transaction.object.timestamp < SNAPSHOT_TIMESTAMP.as_secs() && transaction.get_hash() ==
-//                HASH_NULL || transaction.object.timestamp > max_future_ts.as_secs();
-//        }
-//    }
-//    unsafe {
-//        return transaction.object.attachment_timestamp < (SNAPSHOT_TIMESTAMP.as_secs()*1000u64 +
-//            SNAPSHOT_TIMESTAMP.subsec_millis() as u64) || transaction.object.attachment_timestamp >
-//            max_future_ts.as_secs()*1000u64 + max_future_ts.subsec_millis() as u64;
-//    }
-
-    unsafe {
-        return transaction.object.timestamp < SNAPSHOT_TIMESTAMP && transaction.get_hash() ==
-            HASH_NULL || transaction.object.timestamp > MAX_TIMESTAMP_FUTURE;
+// `timestamp` is in seconds, attachment timestamps are in milliseconds
+pub fn check_timestamp(transaction: &Transaction, snapshot_timestamp_ms: u64, now_ms: u64) -> Result<(),
+    TransactionError> {
+    let max_future_ms = now_ms + MAX_TIMESTAMP_FUTURE_MS;
+    let object = &transaction.object;
+
+    if object.attachment_timestamp == 0 {
+        let timestamp_ms = object.timestamp.saturating_mul(1000);
+        if timestamp_ms < snapshot_timestamp_ms && transaction.get_hash() != HASH_NULL
+            || timestamp_ms > max_future_ms {
+            return Err(TransactionError::InvalidTimestamp);
+        }
+        return Ok(());
     }
+
+    if object.attachment_timestamp < snapshot_timestamp_ms || object.attachment_timestamp > max_future_ms {
+        return Err(TransactionError::InvalidTimestamp);
+    }
+
+    if object.attachment_timestamp < object.attachment_timestamp_lower_bound
+        || object.attachment_timestamp > object.attachment_timestamp_upper_bound {
+        return Err(TransactionError::InvalidAttachmentTimestamp);
+    }
+
+    Ok(())
 }
 
-#[derive(Debug)]
+pub fn has_invalid_timestamp(transaction: &Transaction, snapshot_timestamp_ms: u64, now_ms: u64) -> bool {
+    check_timestamp(transaction, snapshot_timestamp_ms, now_ms).is_err()
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum TransactionError {
     InvalidTimestamp,
+    InvalidAttachmentTimestamp,
     InvalidHash,
+    HashMismatch(Hash),
+    InsufficientWeight { required: Difficulty, actual: Difficulty },
+    InvalidSignature,
     InvalidAddress,
+    InsufficientBalance { address: Address, balance: i64, value: u32 },
+    UnknownParent(Hash),
+    Duplicate,
+    NotPromotable(TransactionStatus),
+    NotReplayable(TransactionStatus),
+    InvalidDepth { depth: u32, max_depth: u32 },
+    InvalidReference(Hash, &'static str),
     InvalidData
 }
 
-pub fn validate(transaction: &mut Transaction, mwm: u32) -> Result<(), TransactionError> {
-    if has_invalid_timestamp(transaction) {
-        return Err(TransactionError::InvalidTimestamp);
+impl TransactionError {
+    // Stable numeric codes reported to API clients
+    pub fn code(&self) -> i32 {
+        match *self {
+            TransactionError::InvalidData => 100,
+            TransactionError::InvalidHash => 101,
+            TransactionError::HashMismatch(_) => 102,
+            TransactionError::InsufficientWeight { .. } => 103,
+            TransactionError::InvalidSignature => 104,
+            TransactionError::InvalidTimestamp => 105,
+            TransactionError::InvalidAttachmentTimestamp => 106,
+            TransactionError::InvalidAddress => 107,
+            TransactionError::InsufficientBalance { .. } => 108,
+            TransactionError::UnknownParent(_) => 109,
+            TransactionError::Duplicate => 110,
+            TransactionError::NotPromotable(_) => 111,
+            TransactionError::InvalidDepth { .. } => 112,
+            TransactionError::InvalidReference(..) => 113,
+            TransactionError::NotReplayable(_) => 114,
+        }
     }
+}
 
-    if !validate_transaction(transaction, mwm) {
-        return Err(TransactionError::InvalidData);
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TransactionError::InvalidData => write!(f, "invalid transaction data"),
+            TransactionError::InvalidHash => write!(f, "unknown transaction hash"),
+            TransactionError::HashMismatch(ref calculated) =>
+                write!(f, "hash does not match transaction data, expected {:?}", calculated),
+            TransactionError::InsufficientWeight { required, actual } =>
+                write!(f, "insufficient proof of work: weight {} < {}", actual.bits(), required.bits()),
+            TransactionError::InvalidSignature => write!(f, "invalid signature"),
+            TransactionError::InvalidTimestamp => write!(f, "timestamp is before snapshot or too far in the future"),
+            TransactionError::InvalidAttachmentTimestamp =>
+                write!(f, "attachment timestamp is out of its lower/upper bounds"),
+            TransactionError::InvalidAddress => write!(f, "invalid address"),
+            TransactionError::InsufficientBalance { ref address, balance, value } =>
+                write!(f, "insufficient balance of {:?}: {} < {}", address, balance, value),
+            TransactionError::UnknownParent(ref hash) => write!(f, "unknown parent transaction {:?}", hash),
+            TransactionError::Duplicate => write!(f, "transaction already exists"),
+            TransactionError::NotPromotable(status) =>
+                write!(f, "transaction is {}, only solid unconfirmed transactions can be promoted", status.name()),
+            TransactionError::InvalidDepth { depth, max_depth } =>
+                write!(f, "invalid depth {}, 0 is only accepted with a reference (max depth {})", depth, max_depth),
+            TransactionError::InvalidReference(ref hash, reason) => write!(f, "reference {:?} is {}", hash, reason),
+            TransactionError::NotReplayable(status) =>
+                write!(f, "transaction is {}, confirmed and conflicting transactions can't be replayed", status.name()),
+        }
+    }
+}
+
+pub fn validate(transaction: &mut Transaction, mwm: Difficulty, snapshot_timestamp_ms: u64, now_ms: u64)
+    -> Result<(), TransactionError> {
+    validate_without_signature(transaction, mwm, snapshot_timestamp_ms, now_ms)?;
+
+    if !verify_signature(&transaction.object) {
+        return Err(TransactionError::InvalidSignature);
     }
 
-    if (transaction.weight_magnitude as u32) < mwm {
-        return Err(TransactionError::InvalidHash);
+    Ok(())
+}
+
+// Signatures are checked separately, in batches, by `SignatureVerifier`
+pub fn validate_without_signature(transaction: &mut Transaction, mwm: Difficulty, snapshot_timestamp_ms: u64,
+                                  now_ms: u64) -> Result<(), TransactionError> {
+    if transaction.malformed || transaction.object.payload.len() > MAX_PAYLOAD_SIZE {
+        return Err(TransactionError::InvalidData);
     }
 
+    check_timestamp(transaction, snapshot_timestamp_ms, now_ms)?;
+
+    validate_hash_and_weight(transaction, mwm)?;
+
     if !transaction.object.address.verify() || (transaction.object.value != 0 && transaction.object.address.is_null()) {
         return Err(TransactionError::InvalidAddress);
     }
@@ -86,22 +173,25 @@ pub fn validate(transaction: &mut Transaction, mwm: u32) -> Result<(), Transacti
 impl TransactionValidator {
     pub fn new(hive: AM<Hive>, tips_view_model: AM<TipsViewModel>, snapshot_timestamp:
     u64, transaction_requester: AM<TransactionRequester>) -> AM<Self> {
-        unsafe {
-            SNAPSHOT_TIMESTAMP = snapshot_timestamp;
-            SNAPSHOT_TIMESTAMP_MS = snapshot_timestamp * 1000;
-        }
+        TransactionValidator::new_with_clock(hive, tips_view_model, snapshot_timestamp,
+                                             transaction_requester, Arc::new(SystemClock))
+    }
 
+    pub fn new_with_clock(hive: AM<Hive>, tips_view_model: AM<TipsViewModel>, snapshot_timestamp:
+    u64, transaction_requester: AM<TransactionRequester>, clock: Arc<Clock>) -> AM<Self> {
         let tv = TransactionValidator {
             hive,
             tips_view_model,
-//            snapshot_timestamp,
+            snapshot_timestamp_ms: snapshot_timestamp * 1000,
+            clock,
             transaction_requester,
-            min_weight_magnitude: 9,
+            min_weight_magnitude: Difficulty(MAINNET_MIN_WEIGHT_MAGNITUDE),
             propagation_thread: None,
             use_first: Arc::new(AtomicBool::new(true)),
             running: Arc::new(AtomicBool::new(true)),
             new_solid_transaction_list_one: make_am!(LinkedHashSet::new()),
             new_solid_transaction_list_two: make_am!(LinkedHashSet::new()),
+            weight_queue: make_am!(VecDeque::new()),
         };
 
         let transaction_validator: AM<TransactionValidator> = make_am!(tv);
@@ -115,14 +205,23 @@ impl TransactionValidator {
         transaction_validator
     }
 
-    pub fn init(&mut self, testnet: bool, mwm: u32) {
+    pub fn init(&mut self, testnet: bool, mwm: Difficulty) {
         self.min_weight_magnitude = mwm;
 
-        if !testnet && self.min_weight_magnitude < 9 {
-            self.min_weight_magnitude = 9;
+        if !testnet && self.min_weight_magnitude < Difficulty(MAINNET_MIN_WEIGHT_MAGNITUDE) {
+            self.min_weight_magnitude = Difficulty(MAINNET_MIN_WEIGHT_MAGNITUDE);
         }
     }
 
+    pub fn validate(&self, transaction: &mut Transaction, mwm: Difficulty) -> Result<(), TransactionError> {
+        validate(transaction, mwm, self.snapshot_timestamp_ms, self.clock.now_ms())
+    }
+
+    pub fn validate_without_signature(&self, transaction: &mut Transaction, mwm: Difficulty) -> Result<(),
+        TransactionError> {
+        validate_without_signature(transaction, mwm, self.snapshot_timestamp_ms, self.clock.now_ms())
+    }
+
     pub fn shutdown(&mut self) {
         self.running.store(false, Ordering::SeqCst);
 
@@ -212,6 +311,7 @@ impl TransactionValidator {
         let use_first;
         let solid_transaction_list_one;
         let solid_transaction_list_two;
+        let weight_queue;
 
         if let Some(arc) = transaction_validator.upgrade() {
             if let Ok(tv) = arc.lock() {
@@ -220,6 +320,7 @@ impl TransactionValidator {
                 use_first = tv.use_first.clone();
                 solid_transaction_list_one = tv.new_solid_transaction_list_one.clone();
                 solid_transaction_list_two = tv.new_solid_transaction_list_two.clone();
+                weight_queue = tv.weight_queue.clone();
             } else {
                 panic!("broken transaction_validator mutex");
             }
@@ -311,6 +412,8 @@ impl TransactionValidator {
                 if let Ok(mut list) = solid_transaction_list_one.lock() { list.clear(); }
             }
 
+            TransactionValidator::update_cumulative_weights(&hive, &weight_queue);
+
             thread::sleep(Duration::from_millis(500));
         }
     }
@@ -377,9 +480,12 @@ impl TransactionValidator {
         Ok(false)
     }
 
-    pub fn update_status(&mut self, transaction: &mut Transaction) -> Result<(),
+    // Returns true if a milestone was waiting for this transaction to become solid
+    pub fn update_status(&mut self, transaction: &mut Transaction) -> Result<bool,
         TransactionError> {
+        let milestone_dependency;
         if let Ok(mut tr) = self.transaction_requester.lock() {
+            milestone_dependency = tr.is_milestone_request(&transaction.get_hash());
             tr.clear_transaction_request(transaction.get_hash());
         } else {
             panic!("broken transaction_requester mutex");
@@ -399,12 +505,117 @@ impl TransactionValidator {
         }
         // println!("hive unlock 31");
 
+        match self.weight_queue.lock() {
+            Ok(mut queue) => queue.push_back(transaction.get_hash()),
+            Err(_) => panic!("broken weight queue mutex")
+        };
+
 //        println!(3);
         if self.quick_set_solid(transaction)? {
             self.add_solid_transaction(transaction.get_hash());
         }
 
-        Ok(())
+        Ok(milestone_dependency)
+    }
+
+    // Every ancestor gains one approver and a transaction stored after some of its approvers starts
+    // with those. Confirmed ancestors are not walked through and walks are capped, so the weights
+    // are an approximation that is exact for the recent subhive, which is what tip selection walks
+    // on. Runs on the propagation thread, the hive is locked once per batch.
+    pub fn update_cumulative_weights(hive: &AM<Hive>, queue: &AM<VecDeque<Hash>>) {
+        let batch: Vec<Hash> = match queue.lock() {
+            Ok(mut queue) => queue.drain(..).collect(),
+            Err(_) => panic!("broken weight queue mutex")
+        };
+        if batch.is_empty() {
+            return;
+        }
+
+        let mut hive = match hive.lock() {
+            Ok(hive) => hive,
+            Err(_) => panic!("broken hive mutex")
+        };
+
+        // the batch adds its own weight while walking, earlier approvers couldn't walk through a
+        // transaction that wasn't stored yet
+        let in_batch: HashSet<Hash> = batch.iter().cloned().collect();
+        let mut seeded = HashMap::<Hash, u32>::new();
+        let mut increments = HashMap::<Hash, u32>::new();
+
+        for hash in &batch {
+            seeded.insert(*hash, TransactionValidator::stored_approvers(&hive, hash, &in_batch));
+
+            let transaction = match hive.storage_load_transaction(hash) {
+                Some(t) => t,
+                None => continue
+            };
+
+            let mut visited = HashSet::<Hash>::new();
+            let mut non_analyzed_transactions = LinkedList::<Hash>::new();
+            non_analyzed_transactions.push_back(transaction.get_trunk_transaction_hash());
+            non_analyzed_transactions.push_back(transaction.get_branch_transaction_hash());
+
+            while let Some(hash) = non_analyzed_transactions.pop_front() {
+                if visited.len() >= MAX_WEIGHT_PROPAGATION {
+                    debug!("weight propagation of {:?} capped", transaction.get_hash());
+                    break;
+                }
+                if hash == HASH_NULL || !visited.insert(hash) {
+                    continue;
+                }
+
+                let approvee = match hive.storage_load_transaction(&hash) {
+                    Some(t) => t,
+                    None => continue
+                };
+                if approvee.get_type() == TransactionType::HashOnly {
+                    continue;
+                }
+
+                *increments.entry(hash).or_insert(0) += 1;
+
+                if approvee.object.snapshot == 0 {
+                    non_analyzed_transactions.push_back(approvee.get_trunk_transaction_hash());
+                    non_analyzed_transactions.push_back(approvee.get_branch_transaction_hash());
+                }
+            }
+        }
+
+        let updated: HashSet<Hash> = seeded.keys().chain(increments.keys()).cloned().collect();
+        for hash in updated {
+            let mut metadata = hive.storage_load_transaction_metadata(&hash);
+            let approvers_count = seeded.get(&hash).cloned().unwrap_or(metadata.approvers_count);
+            metadata.approvers_count = approvers_count.saturating_add(increments.get(&hash).cloned().unwrap_or(0));
+            hive.put_transaction_metadata(&hash, &metadata);
+        }
+    }
+
+    // Stored transactions approving `hash` directly or not, those in `excluded` are walked through
+    // but not counted
+    fn stored_approvers(hive: &Hive, hash: &Hash, excluded: &HashSet<Hash>) -> u32 {
+        let mut visited = HashSet::<Hash>::new();
+        let mut count = 0u32;
+        let mut non_analyzed_transactions = LinkedList::<Hash>::new();
+        non_analyzed_transactions.push_back(*hash);
+
+        while let Some(hash) = non_analyzed_transactions.pop_front() {
+            let approvers = hive.storage_load_approvee(&hash).unwrap_or_default();
+
+            for approver in approvers {
+                if visited.len() >= MAX_WEIGHT_PROPAGATION {
+                    return count;
+                }
+                if !visited.insert(approver) {
+                    continue;
+                }
+                if !excluded.contains(&approver) {
+                    count += 1;
+                }
+                non_analyzed_transactions.push_back(approver);
+            }
+        }
+
+        count
     }
 
     fn check_approvee(&mut self, approvee: &Transaction) -> bool {
@@ -423,7 +634,7 @@ impl TransactionValidator {
         return approvee.is_solid();
     }
 
-    pub fn get_min_weight_magnitude(&self) -> u32 {
+    pub fn get_min_weight_magnitude(&self) -> Difficulty {
         self.min_weight_magnitude
     }
 }
\ No newline at end of file
@@ -0,0 +1,514 @@
+use std::collections::{HashMap, HashSet, LinkedList};
+use std::cmp::{max, min};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use model::transaction::*;
+use storage::hive::Hive;
+use model::milestone::Milestone;
+use utils::defines::AM;
+use model::ledger_validator::LedgerValidator;
+use model::transaction_validator::{TransactionValidator, TransactionError};
+use model::tip_selection::{walk_approvers, TipExclusion, TipHeuristics, TipScore, MAX_TIP_SCAN};
+use model::tip_selection::{WalkTrace, WalkStep, StopReason};
+
+use rand::{Rng, StdRng};
+
+/// What a walk standing on a transaction needs to know about it
+#[derive(Debug, Clone, PartialEq)]
+pub enum WalkEntry {
+    // the walk can't stand on the transaction
+    Stop(StopReason),
+    // approvers it may step to with their cumulative weights, and those refused as tips
+    Step { approvers: Vec<Hash>, ratings: Vec<i64>, excluded: Vec<(Hash, TipExclusion)> },
+}
+
+/// The latest solid milestone as the tip heuristics measure against it, read once per selection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MilestoneClock {
+    pub index: u32,
+    // attachment time of the milestone, 0 when it isn't known
+    pub ms: u64,
+}
+
+/// The subhive above an entry point as the walks of one selection see it. An entry is read the
+/// first time a walk reaches its transaction and kept for the rest of the selection, so the walks
+/// see the same state however they are scheduled and nothing off their paths is read.
+pub struct WalkSnapshot {
+    walker: Walker,
+    start: Hash,
+    visited_hashes: HashSet<Hash>,
+    diff: HashMap<Address, i64>,
+    extra_tip: Option<Hash>,
+    max_depth: u32,
+    clock: MilestoneClock,
+    // also held while an entry is read, so each one is read only once
+    max_depth_ok: Mutex<HashSet<Hash>>,
+    entries: RwLock<HashMap<Hash, WalkEntry>>,
+}
+
+impl WalkSnapshot {
+    /// How many transactions the walks have read so far
+    pub fn len(&self) -> usize {
+        match self.entries.read() {
+            Ok(entries) => entries.len(),
+            Err(_) => panic!("broken walk snapshot lock")
+        }
+    }
+
+    pub fn max_depth_ok(&self) -> HashSet<Hash> {
+        match self.max_depth_ok.lock() {
+            Ok(max_depth_ok) => max_depth_ok.clone(),
+            Err(_) => panic!("broken walk snapshot mutex")
+        }
+    }
+
+    fn memoised(&self, hash: &Hash) -> Option<WalkEntry> {
+        match self.entries.read() {
+            Ok(entries) => entries.get(hash).cloned(),
+            Err(_) => panic!("broken walk snapshot lock")
+        }
+    }
+
+    // The ledger check of each entry is made against the history of `start` only, which gives the
+    // walk's verdict as the history of a transaction contains the history of everything a walk
+    // passed before it.
+    fn entry(&self, hash: Hash) -> Result<WalkEntry, TransactionError> {
+        if let Some(entry) = self.memoised(&hash) {
+            return Ok(entry);
+        }
+
+        let mut max_depth_ok = match self.max_depth_ok.lock() {
+            Ok(max_depth_ok) => max_depth_ok,
+            Err(_) => panic!("broken walk snapshot mutex")
+        };
+        // another walk may have read it in the meantime
+        if let Some(entry) = self.memoised(&hash) {
+            return Ok(entry);
+        }
+        let entry = self.walker.walk_entry(hash, &mut self.visited_hashes.clone(), &mut self.diff.clone(),
+                                           self.extra_tip, self.max_depth, &mut max_depth_ok, self.clock)?;
+
+        match self.entries.write() {
+            Ok(mut entries) => entries.insert(hash, entry.clone()),
+            Err(_) => panic!("broken walk snapshot lock")
+        };
+        Ok(entry)
+    }
+}
+
+/// The part of `TipsManager` a random walk needs. Clones share the storage and the exclusion
+/// counters.
+#[derive(Clone)]
+pub struct Walker {
+    hive: AM<Hive>,
+    milestone: AM<Milestone>,
+    ledger_validator: AM<LedgerValidator>,
+    transaction_validator: AM<TransactionValidator>,
+    tip_heuristics: TipHeuristics,
+    lazy_tips_excluded: Arc<AtomicUsize>,
+    parasitic_tips_excluded: Arc<AtomicUsize>,
+}
+
+impl Walker {
+    pub fn new(hive: AM<Hive>,
+               milestone: AM<Milestone>,
+               ledger_validator: AM<LedgerValidator>,
+               transaction_validator: AM<TransactionValidator>,
+               tip_heuristics: TipHeuristics) -> Self {
+        Walker {
+            hive,
+            milestone,
+            ledger_validator,
+            transaction_validator,
+            tip_heuristics,
+            lazy_tips_excluded: Arc::new(AtomicUsize::new(0)),
+            parasitic_tips_excluded: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// How many times tips were refused as lazy and as parasitic since the start
+    pub fn excluded_tips(&self) -> (usize, usize) {
+        (self.lazy_tips_excluded.load(Ordering::Relaxed), self.parasitic_tips_excluded.load(Ordering::Relaxed))
+    }
+
+    pub fn milestone_clock(&self) -> MilestoneClock {
+        let (latest_solid_subhive_milestone_index, latest_solid_subhive_milestone) = match self.milestone.lock() {
+            Ok(m) => (m.latest_solid_subhive_milestone_index, m.latest_solid_subhive_milestone),
+            Err(_) => panic!("broken milestone mutex")
+        };
+        let milestone_ms = match self.hive.lock() {
+            Ok(hive) => hive.storage_load_transaction(&latest_solid_subhive_milestone)
+                .and_then(|t| if t.get_type() == TransactionType::Full { Some(attachment_time_ms(&t.object)) } else { None })
+                .unwrap_or(0),
+            Err(_) => panic!("broken hive mutex")
+        };
+        MilestoneClock { index: latest_solid_subhive_milestone_index, ms: milestone_ms }
+    }
+
+    /// Lazy or parasitic verdict for a tip, `None` if it may be approved. A refusal is counted.
+    pub fn tip_exclusion(&self, hash: &Hash, clock: MilestoneClock) -> Option<TipExclusion> {
+        let exclusion = self.tip_verdict(hash, clock)?;
+        self.count_exclusion(&exclusion);
+        Some(exclusion)
+    }
+
+    fn count_exclusion(&self, exclusion: &TipExclusion) {
+        match *exclusion {
+            TipExclusion::Lazy => self.lazy_tips_excluded.fetch_add(1, Ordering::Relaxed),
+            TipExclusion::Parasitic => self.parasitic_tips_excluded.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    fn tip_verdict(&self, hash: &Hash, clock: MilestoneClock) -> Option<TipExclusion> {
+        if self.tip_heuristics == TipHeuristics::disabled() {
+            return None;
+        }
+
+        let score = self.tip_score(hash, clock.index, clock.ms)?;
+
+        let exclusion = self.tip_heuristics.classify(&score);
+        if exclusion.is_none() {
+            return None;
+        }
+        debug!("excluding {:?} tip {:?}: {:?}", exclusion, hash, score);
+        exclusion
+    }
+
+    /// Scores an unconfirmed transaction for the lazy and parasitic heuristics, `None` for
+    /// confirmed or missing ones. Times are measured against `milestone_ms`, the attachment time of
+    /// the latest solid milestone, so a quiet network or a stalled coordinator doesn't age every
+    /// tip; 0 when it isn't known, which leaves age and parent lag at 0.
+    pub fn tip_score(&self, hash: &Hash, latest_solid_subhive_milestone_index: u32, milestone_ms: u64) -> Option<TipScore> {
+        let hive = match self.hive.lock() {
+            Ok(hive) => hive,
+            Err(_) => panic!("broken hive mutex")
+        };
+
+        let transaction = hive.storage_load_transaction(hash)?;
+        if transaction.get_type() != TransactionType::Full || transaction.object.get_snapshot_index() != 0 {
+            return None;
+        }
+        let attached = attachment_time_ms(&transaction.object);
+
+        let mut oldest_parent = attached;
+        let parents = [transaction.get_trunk_transaction_hash(), transaction.get_branch_transaction_hash()];
+        for parent in parents.iter() {
+            if let Some(p) = hive.storage_load_transaction(parent) {
+                if p.get_type() == TransactionType::Full {
+                    oldest_parent = min(oldest_parent, attachment_time_ms(&p.object));
+                }
+            }
+        }
+
+        // newest milestone confirming something on the border of the unconfirmed past
+        let mut newest_milestone = 0;
+        let mut non_analyzed_transactions = LinkedList::new();
+        non_analyzed_transactions.extend(parents.iter().cloned());
+        let mut analyzed_transactions: HashSet<Hash> = HashSet::new();
+        analyzed_transactions.insert(*hash);
+
+        while let Some(h) = non_analyzed_transactions.pop_front() {
+            if !analyzed_transactions.insert(h) {
+                continue;
+            }
+            if analyzed_transactions.len() > MAX_TIP_SCAN {
+                // too much unconfirmed history to tell
+                newest_milestone = latest_solid_subhive_milestone_index;
+                break;
+            }
+
+            let t = match hive.storage_load_transaction(&h) {
+                Some(t) => t,
+                None => continue
+            };
+            if t.get_type() != TransactionType::Full {
+                continue;
+            }
+
+            let index = t.object.get_snapshot_index();
+            if index != 0 {
+                newest_milestone = max(newest_milestone, index);
+            } else {
+                non_analyzed_transactions.push_back(t.get_trunk_transaction_hash());
+                non_analyzed_transactions.push_back(t.get_branch_transaction_hash());
+            }
+        }
+
+        let milestone_lag = match newest_milestone {
+            0 => 0,
+            n => latest_solid_subhive_milestone_index.saturating_sub(n)
+        };
+
+        let (age_ms, parent_lag_ms) = match milestone_ms {
+            0 => (0, 0),
+            m => (m.saturating_sub(attached), min(attached, m).saturating_sub(oldest_parent))
+        };
+
+        Some(TipScore {
+            age_ms,
+            milestone_lag,
+            parent_lag_ms,
+        })
+    }
+
+    fn excluded_tip(&self, hash: &Hash, clock: MilestoneClock) -> Option<TipExclusion> {
+        let is_tip = match self.hive.lock() {
+            Ok(hive) => hive.storage_load_approvee(hash).map(|a| a.is_empty()).unwrap_or(true),
+            Err(_) => panic!("hive mutex is broken")
+        };
+        if is_tip { self.tip_verdict(hash, clock) } else { None }
+    }
+
+    /// Everything a walk checks on a transaction before stepping on one of its approvers.
+    /// `approved_hashes` and `diff` are the history the walk approves so far, they grow by the
+    /// transaction's history when it is consistent.
+    pub fn walk_entry(&self,
+                      hash: Hash,
+                      approved_hashes: &mut HashSet<Hash>,
+                      diff: &mut HashMap<Address, i64>,
+                      extra_tip: Option<Hash>,
+                      max_depth: u32,
+                      max_depth_ok: &mut HashSet<Hash>,
+                      clock: MilestoneClock) -> Result<WalkEntry, TransactionError> {
+        let (mut transaction_obj, is_conflicting) = match self.hive.lock() {
+            Ok(hive) => (hive.storage_load_transaction(&hash).expect("tip is null"), hive.is_conflicting(&hash)),
+            Err(_) => panic!("hive mutex is broken")
+        };
+
+        if is_conflicting {
+            return Ok(WalkEntry::Stop(StopReason::Conflicting));
+        }
+
+        if transaction_obj.get_type() == TransactionType::HashOnly {
+            return Ok(WalkEntry::Stop(StopReason::Missing));
+        }
+
+        let check_solidity_is_ok = match self.transaction_validator.lock() {
+            Ok(tv) => tv.check_solidity(hash, false)?,
+            Err(_) => panic!("broken transaction validator mutex")
+        };
+        if !check_solidity_is_ok {
+            return Ok(WalkEntry::Stop(StopReason::NotSolid));
+        }
+
+        if self.below_max_depth(hash, max_depth, max_depth_ok) {
+            return Ok(WalkEntry::Stop(StopReason::BelowMaxDepth));
+        }
+
+        let update_diff_is_ok = match self.ledger_validator.lock() {
+            Ok(mut lv) => lv.update_diff(approved_hashes, diff, hash)?,
+            Err(_) => panic!("broken ledger validator mutex")
+        };
+        if !update_diff_is_ok {
+            return Ok(WalkEntry::Stop(StopReason::InconsistentLedger));
+        }
+
+        if extra_tip == Some(hash) {
+            return Ok(WalkEntry::Stop(StopReason::ExtraTip));
+        }
+
+        let mut tip_set = transaction_obj.get_approvers(&self.hive);
+        match self.hive.lock() {
+            Ok(hive) => tip_set.retain(|h| !hive.is_conflicting(h)),
+            Err(_) => panic!("hive mutex is broken")
+        };
+        // only tips are judged, a walk may pass through anything
+        let mut excluded = Vec::new();
+        tip_set.retain(|h| match self.excluded_tip(h, clock) {
+            Some(exclusion) => {
+                excluded.push((*h, exclusion));
+                false
+            }
+            None => true
+        });
+
+        let approvers = Walker::set_to_vec(&tip_set);
+        let ratings = match self.hive.lock() {
+            Ok(hive) => approvers.iter().map(|h| hive.cumulative_weight(h)).collect::<Vec<i64>>(),
+            Err(_) => panic!("hive mutex is broken")
+        };
+        excluded.sort_by(|a, b| (a.0).0.cmp(&(b.0).0));
+
+        Ok(WalkEntry::Step { approvers, ratings, excluded })
+    }
+
+    /// Walks from `start` on the live subhive, the current storage state decides every step
+    pub fn random_walk(&self,
+                       visited_hashes: &HashSet<Hash>,
+                       diff: &HashMap<Address, i64>,
+                       start: Option<Hash>,
+                       extra_tip: Option<Hash>,
+                       max_depth: u32,
+                       max_depth_ok: &mut HashSet<Hash>,
+                       clock: MilestoneClock,
+                       alpha: f64,
+                       rng: &mut StdRng,
+                       trace: Option<&mut WalkTrace>) -> Result<Option<Hash>, TransactionError> {
+        let start = match start {
+            Some(start) => start,
+            None => return Ok(None)
+        };
+        let mut my_diff = diff.clone();
+        let mut my_approved_hashes = visited_hashes.clone();
+
+        self.walk(|hash| self.walk_entry(hash, &mut my_approved_hashes, &mut my_diff, extra_tip, max_depth,
+                                         max_depth_ok, clock),
+                  start, alpha, rng, trace)
+    }
+
+    /// A snapshot of the subhive above `start` for the walks of one selection, empty until they
+    /// read it
+    pub fn snapshot(&self,
+                    visited_hashes: &HashSet<Hash>,
+                    diff: &HashMap<Address, i64>,
+                    start: Hash,
+                    extra_tip: Option<Hash>,
+                    max_depth: u32,
+                    max_depth_ok: &HashSet<Hash>,
+                    clock: MilestoneClock) -> WalkSnapshot {
+        WalkSnapshot {
+            walker: self.clone(),
+            start,
+            visited_hashes: visited_hashes.clone(),
+            diff: diff.clone(),
+            extra_tip,
+            max_depth,
+            clock,
+            max_depth_ok: Mutex::new(max_depth_ok.clone()),
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Walks on a snapshot, the storage is only read for transactions no walk has reached yet
+    pub fn snapshot_walk(snapshot: &WalkSnapshot, alpha: f64, rng: &mut StdRng, trace: Option<&mut WalkTrace>)
+        -> Result<Option<Hash>, TransactionError> {
+        snapshot.walker.walk(|hash| snapshot.entry(hash), snapshot.start, alpha, rng, trace)
+    }
+
+    // exclusions are counted when a walk stands next to them
+    fn walk<F>(&self, mut entry: F, start: Hash, alpha: f64, rng: &mut StdRng, trace: Option<&mut WalkTrace>)
+        -> Result<Option<Hash>, TransactionError> where F: FnMut(Hash) -> Result<WalkEntry, TransactionError> {
+        let mut tip = Some(start);
+        let mut tail = None;
+        let mut stop_reason = None;
+        let mut trace = trace;
+
+        while let Some(tip_hash) = tip {
+            let (approvers, ratings, excluded) = match entry(tip_hash)? {
+                WalkEntry::Stop(reason) => {
+                    stop_reason = Some(reason);
+                    break;
+                }
+                WalkEntry::Step { approvers, ratings, excluded } => (approvers, ratings, excluded)
+            };
+
+            tail = Some(tip_hash);
+            for &(_, ref exclusion) in excluded.iter() {
+                self.count_exclusion(exclusion);
+            }
+
+            if let Some(ref mut t) = trace {
+                t.path.push(WalkStep {
+                    hash: tip_hash,
+                    approvers: approvers.clone(),
+                    ratings: ratings.clone(),
+                    excluded: excluded.clone(),
+                });
+            }
+
+            if approvers.len() == 0 {
+                stop_reason = Some(if excluded.is_empty() { StopReason::Tip } else { StopReason::ExcludedTips });
+                break;
+            } else if approvers.len() == 1 {
+                tip = approvers.first().cloned();
+            } else {
+                // an unweighted walk does not need the weights
+                let approver_ratings = if alpha == 0.0 { vec![0i64; approvers.len()] } else { ratings };
+
+                let approver_index = match walk_approvers(&approver_ratings, alpha, rng.gen::<f64>()) {
+                    Some(i) => i,
+                    None => {
+                        stop_reason = Some(StopReason::NoApproverWeight);
+                        break;
+                    }
+                };
+                tip = approvers.get(approver_index).cloned();
+            }
+
+            if tip == Some(tip_hash) {
+                break;
+            }
+        }
+
+        if let Some(reason) = stop_reason {
+            debug!("Reason to stop: {}", reason.name());
+        }
+        if let Some(t) = trace {
+            t.tail = tail;
+            t.stop_reason = stop_reason;
+        }
+
+        return Ok(tail);
+    }
+
+    pub fn below_max_depth(&self, tip: Hash, depth: u32, max_depth_ok: &mut HashSet<Hash>) -> bool {
+        //if tip is confirmed stop
+
+        let mut transaction = match self.hive.lock() {
+            Ok(hive) => hive.storage_load_transaction(&tip).expect("can't find transaction"),
+            Err(_) => {
+                panic!("hive mutex is broken");
+            }
+        };
+
+        if transaction.object.get_snapshot_index() >= depth {
+            return false;
+        }
+
+        //if tip unconfirmed, check if any referenced tx is confirmed below maxDepth
+        let mut non_analyzed_transactions = LinkedList::new();
+        non_analyzed_transactions.push_back(tip);
+        let mut analyzed_transactions: HashSet<Hash> = HashSet::new();
+
+        while let Some(hash) = non_analyzed_transactions.pop_front() {
+            if analyzed_transactions.insert(hash) {
+                let mut transaction = match self.hive.lock() {
+                    Ok(hive) => hive.storage_load_transaction(&hash).expect("can't load \
+                    transaction"),
+                    Err(_) => {
+                        panic!("hive mutex is broken");
+                    }
+                };
+
+                if transaction.object.get_snapshot_index() != 0 && transaction.object.get_snapshot_index() < depth {
+                    return true;
+                }
+                if transaction.object.get_snapshot_index() == 0 {
+                    if !max_depth_ok.contains(&hash) {
+                        non_analyzed_transactions.push_back(transaction.get_trunk_transaction_hash());
+                        non_analyzed_transactions.push_back(transaction.get_branch_transaction_hash());
+                    }
+                }
+            }
+        }
+        max_depth_ok.insert(tip);
+        return false;
+    }
+
+    // sorted, so a seeded walk takes the same steps on the same subhive
+    fn set_to_vec(set: &HashSet<Hash>) -> Vec<Hash> {
+        let mut hash_iterator = set.iter();
+        let mut result: Vec<Hash> = Vec::with_capacity(set.len());
+        if !set.is_empty() {
+            loop {
+                match hash_iterator.next() {
+                    Some(hash) => result.push(hash.clone()),
+                    None => break
+                }
+            }
+        }
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        return result;
+    }
+}
@@ -1,6 +1,7 @@
 use rustc_serialize::json;
 use rustc_serialize::json::Json;
 extern crate base64;
+extern crate ntrumls;
 
 use iron;
 use iron::{Iron, Request, Response, IronResult, AfterMiddleware, Chain, Listening};
@@ -8,7 +9,7 @@ use iron::prelude::*;
 use iron::status;
 use network::Node;
 use network::paymoncoin::PaymonCoin;
-use utils::{AM, AWM};
+use utils::{AM, AWM, Clock, SystemClock};
 use std;
 use std::io::Read;
 use network::rpc;
@@ -20,6 +21,8 @@ use model::transaction::*;
 use model::*;
 use std::collections::{HashMap, HashSet};
 use model::transaction_validator::TransactionError;
+use model::tip_selection::SelectionTrace;
+use self::ntrumls::{PrivateKey, PublicKey};
 
 #[macro_export]
 macro_rules! format_success_response {
@@ -38,12 +41,42 @@ impl AfterMiddleware for DefaultContentType {
 }
 
 static mut PMNC: Option<AM<PaymonCoin>> = None;
+lazy_static! {
+    // one flag per attachToTangle in progress, interruptAttachingToTangle sets all of them
+    static ref ATTACHES_IN_PROGRESS: Mutex<Vec<Arc<AtomicBool>>> = Mutex::new(Vec::new());
+}
+
+// Registers an attach for interruptAttachingToTangle and unregisters it when dropped, so every
+// attach has its own flag and starting one can't clear an interrupt meant for another
+struct AttachToken(Arc<AtomicBool>);
+
+impl AttachToken {
+    fn register() -> Self {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        match ATTACHES_IN_PROGRESS.lock() {
+            Ok(mut attaches) => attaches.push(interrupted.clone()),
+            Err(_) => panic!("broken attaches mutex")
+        };
+        AttachToken(interrupted)
+    }
+}
+
+impl Drop for AttachToken {
+    fn drop(&mut self) {
+        if let Ok(mut attaches) = ATTACHES_IN_PROGRESS.lock() {
+            attaches.retain(|a| !Arc::ptr_eq(a, &self.0));
+        }
+    }
+}
 const MILESTONE_START_INDEX: u32 = 0;
 const MIN_RANDOM_WALKS: u32 = 5;
 const MAX_RANDOM_WALKS: u32 = 27;
 const MAX_DEPTH: u32 = 15;
 const MAX_FIND_TXS: usize = 100;
 const MAX_GET_TX_DATA: usize = 100;
+const MAX_ATTACH_TXS: usize = 100;
+const MAX_GET_TX_STATUS: usize = 100;
+const MAX_CHECK_CONSISTENCY: usize = 100;
 
 pub struct API {
     listener: Listening,
@@ -56,6 +89,12 @@ pub struct APIRequest<T: Serializable> {
     pub object: T,
 }
 
+#[derive(RustcEncodable)]
+struct ErrorResponse {
+    error: String,
+    code: i32,
+}
+
 #[derive(Debug)]
 pub enum APIError{
     InvalidStringParametr,
@@ -65,7 +104,15 @@ pub enum APIError{
     InvalidRequest,
     NoneParameter,
     IncorrectJsonParsing,
-    Overflow
+    Overflow,
+    Interrupted,
+    Transaction(TransactionError)
+}
+
+impl From<TransactionError> for APIError {
+    fn from(e: TransactionError) -> Self {
+        APIError::Transaction(e)
+    }
 }
 
 impl API {
@@ -100,52 +147,111 @@ impl API {
         Response::with((iron::status::Ok, format!("{{\"error\":\"{}\"}}\n", err.to_string())))
     }
 
-    fn get_transactions_to_approve(pmnc: &mut PaymonCoin, mut depth: u32, reference: Option<Hash>, mut num_walks: u32) ->
-    Result<Option<(Hash, Hash)>, TransactionError> {
-        if num_walks > MAX_RANDOM_WALKS || num_walks == 0 {
-            num_walks = MAX_RANDOM_WALKS;
-        }
+    fn format_transaction_error(err: &TransactionError) -> Response {
+        let result = ErrorResponse {
+            error: err.to_string(),
+            code: err.code(),
+        };
+        Response::with((iron::status::Ok, json::encode(&result).unwrap()))
+    }
 
-        let max_depth = match pmnc.tips_manager.lock() {
-            Ok(tm) => tm.get_max_depth(),
-            Err(_) => panic!("broken tips manager mutex")
+    // Runs the same checks as the node before queueing, so the client learns why a transaction
+    // is rejected. PMNC is only locked to reach the components, the checks and the signature
+    // verification run without it.
+    fn broadcast_transaction(arc: &AM<PaymonCoin>, bt: rpc::BroadcastTransaction) -> Result<(), TransactionError> {
+        let (node, transaction_validator, signature_verifier, hive, milestone) = match arc.lock() {
+            Ok(pmnc) => (pmnc.node.clone(), pmnc.transaction_validator.clone(), pmnc.signature_verifier.clone(),
+                         pmnc.hive.clone(), pmnc.milestone.clone()),
+            Err(_) => panic!("broken pmnc mutex")
         };
-        if depth > max_depth {
-            depth = max_depth;
-        }
+        let mut transaction = Transaction::from_object(bt.transaction.clone());
 
-        let mut visited_hashes = HashSet::new();
-        let mut diff = HashMap::new();
-        let mut h0: Option<Hash>;
-        let mut h1: Option<Hash>;
+        let mwm = match node.lock() {
+            Ok(node) => node.required_min_weight_magnitude(&TransactionSource::Api),
+            Err(_) => panic!("broken node mutex")
+        };
 
-        if let Some(hash) = reference {
-            let hive = match pmnc.hive.lock() {
-                Ok(h) => h,
-                _ => panic!("broken hive mutex")
-            };
-            if !hive.exists_transaction(hash) {
-                return Err(TransactionError::InvalidHash);
-            } else {
-                let milestone_latest_solid_subhive_milestone_index = match pmnc.milestone.lock() {
-                    Ok(l) => l.latest_solid_subhive_milestone_index,
-                    _ => panic!("broken milestone mutex")
-                };
+        match transaction_validator.lock() {
+            Ok(tv) => tv.validate_without_signature(&mut transaction, mwm)?,
+            Err(_) => panic!("broken transaction validator mutex")
+        };
 
-                let tx = hive.storage_load_transaction(&hash).unwrap();
+        // cached on success, so the receive pipeline does not verify it again
+        match signature_verifier.lock() {
+            Ok(mut verifier) => if !verifier.verify(&transaction.object) {
+                return Err(TransactionError::InvalidSignature);
+            },
+            Err(_) => panic!("broken signature verifier mutex")
+        };
 
-                if tx.object.snapshot != 0 && tx.object.snapshot < milestone_latest_solid_subhive_milestone_index - depth {
-                    return Err(TransactionError::InvalidTimestamp);
+        if let Ok(hive) = hive.lock() {
+            if let Some(t) = hive.storage_load_transaction(&transaction.get_hash()) {
+                if t.get_type() == TransactionType::Full {
+                    return Err(TransactionError::Duplicate);
                 }
             }
+
+            for parent in &[transaction.get_trunk_transaction_hash(), transaction.get_branch_transaction_hash()] {
+                if *parent != HASH_NULL && !hive.exists_transaction(*parent) {
+                    return Err(TransactionError::UnknownParent(*parent));
+                }
+            }
+        } else {
+            panic!("broken hive mutex");
+        }
+
+        if transaction.object.value != 0 {
+            let address = Address::from_public_key(&transaction.object.signature_pubkey);
+            let balance = match milestone.lock() {
+                Ok(milestone) => milestone.latest_snapshot.get_balance(&address).unwrap_or(0),
+                Err(_) => panic!("broken milestone mutex")
+            };
+
+            if balance < transaction.object.value as i64 {
+                return Err(TransactionError::InsufficientBalance {
+                    address,
+                    balance,
+                    value: transaction.object.value
+                });
+            }
+        }
+
+        match arc.lock() {
+            Ok(pmnc) => match pmnc.node.lock() {
+                Ok(mut node) => node.on_api_broadcast_transaction_received(bt),
+                Err(_) => panic!("broken node mutex")
+            },
+            Err(_) => panic!("broken pmnc mutex")
+        };
+
+        Ok(())
+    }
+
+    // depth and reference are checked by `TipsManager::transaction_to_approve`
+    fn get_transactions_to_approve(pmnc: &mut PaymonCoin, depth: u32, reference: Option<Hash>, mut num_walks: u32,
+                                   selector: Option<&TipSelector>,
+                                   mut traces: Option<&mut Vec<SelectionTrace>>) ->
+    Result<Option<(Hash, Hash)>, TransactionError> {
+        if num_walks > MAX_RANDOM_WALKS || num_walks == 0 {
+            num_walks = MAX_RANDOM_WALKS;
         }
 
-        if let Ok(tips_manager) = pmnc.tips_manager.lock() {
-            h0 = tips_manager.transaction_to_approve(&mut visited_hashes, &mut diff, reference,
-                                                     None, depth, num_walks)?;
+        let mut visited_hashes = HashSet::new();
+        let mut diff = HashMap::new();
+        let mut h0: Option<Hash>;
+        let mut h1: Option<Hash>;
+
+        let mut trace = traces.as_ref().map(|_| SelectionTrace::new());
+        let result = if let Ok(tips_manager) = pmnc.tips_manager.lock() {
+            tips_manager.transaction_to_approve(&mut visited_hashes, &mut diff, reference,
+                                                None, depth, num_walks, selector, trace.as_mut())
         } else {
             panic!("broken tips manager mutex");
+        };
+        if let (Some(t), Some(trace)) = (traces.as_mut(), trace) {
+            t.push(trace);
         }
+        h0 = result?;
 
         if let Ok(ref mut ledger_validator) = pmnc.ledger_validator.lock() {
             if h0.is_none() || !ledger_validator.update_diff(&mut visited_hashes, &mut diff, h0.unwrap())? {
@@ -155,12 +261,17 @@ impl API {
             panic!("broken tips manager mutex");
         }
 
-        if let Ok(tips_manager) = pmnc.tips_manager.lock() {
-            h1 = tips_manager.transaction_to_approve(&mut visited_hashes, &mut diff, reference,
-                                                     h0, depth, num_walks)?;
+        let mut trace = traces.as_ref().map(|_| SelectionTrace::new());
+        let result = if let Ok(tips_manager) = pmnc.tips_manager.lock() {
+            tips_manager.transaction_to_approve(&mut visited_hashes, &mut diff, reference,
+                                                h0, depth, num_walks, selector, trace.as_mut())
         } else {
             panic!("broken tips manager mutex");
+        };
+        if let (Some(t), Some(trace)) = (traces.as_mut(), trace) {
+            t.push(trace);
         }
+        h1 = result?;
 
         if let Ok(ref mut ledger_validator) = pmnc.ledger_validator.lock() {
             if h1.is_none() || !ledger_validator.update_diff(&mut visited_hashes, &mut diff, h1.unwrap())? {
@@ -183,6 +294,58 @@ impl API {
         }
     }
 
+    // checks and defaults shared by getTransactionsToApprove and getTipSelectionDiagnostics
+    fn tip_selection_params(pmnc: &mut PaymonCoin, depth: u32, num_walks: u32, reference: Hash,
+                            strategy: &Option<String>) -> Result<(Option<Hash>, u32, Option<Box<TipSelector>>), &'static str> {
+        let mut num_walks = match num_walks {
+            0 => 1,
+            v => v
+        };
+        if num_walks < MIN_RANDOM_WALKS {
+            num_walks = MIN_RANDOM_WALKS;
+        }
+
+        let reference = if reference == HASH_NULL {
+            None
+        } else {
+            Some(reference)
+        };
+
+        let selector = match *strategy {
+            Some(ref name) => match pmnc.tips_manager.lock() {
+                Ok(tm) => tm.tip_selector(name),
+                Err(_) => panic!("broken tips manager mutex")
+            } {
+                Some(selector) => Some(selector),
+                None => return Err("Unknown tip selection strategy")
+            },
+            None => None
+        };
+
+        Ok((reference, num_walks, selector))
+    }
+
+    /// Runs tip selection like `getTransactionsToApprove`, reporting every walk
+    pub fn get_tip_selection_diagnostics(pmnc: &mut PaymonCoin, depth: u32, reference: Option<Hash>, num_walks: u32,
+                                         selector: Option<&TipSelector>) -> rpc::TipSelectionDiagnostics {
+        let mut selections = Vec::new();
+        let result = API::get_transactions_to_approve(pmnc, depth, reference, num_walks, selector,
+                                                      Some(&mut selections));
+
+        let (trunk, branch, error) = match result {
+            Ok(Some((trunk, branch))) => (trunk, branch, String::new()),
+            Ok(None) => (HASH_NULL, HASH_NULL, "None".to_string()),
+            Err(e) => (HASH_NULL, HASH_NULL, e.to_string())
+        };
+
+        rpc::TipSelectionDiagnostics {
+            trunk,
+            branch,
+            error,
+            selections,
+        }
+    }
+
     fn get_balances(pmnc: &mut PaymonCoin,
                     addresses: Vec<Address>,
                     mut hashes: Vec<Hash>,
@@ -270,7 +433,10 @@ impl API {
         let mut tips_index: Vec<u32> = Vec::new();
         if let Ok(hive) = pmnc.hive.lock() {
             for tip in tips.iter() {
-                let tx = hive.storage_load_transaction(tip).unwrap();
+                let tx = match hive.storage_load_transaction(tip) {
+                    Some(tx) => tx,
+                    None => return Err(APIError::TipAbsent)
+                };
                 if tx.get_type() != TransactionType::HashOnly {
                     tips_index.push(tx.object.get_snapshot_index());
                 }
@@ -299,7 +465,14 @@ impl API {
             let mut count = 0;
             if let Ok(hive) = pmnc.hive.lock() {
                 for hash in transactions.iter() {
-                    let transaction = hive.storage_load_transaction(hash).unwrap();
+                    let transaction = match hive.storage_load_transaction(hash) {
+                        Some(t) => t,
+                        None => {
+                            inclusion_states[count] = -1;
+                            count += 1;
+                            continue;
+                        }
+                    };
                     if transaction.get_type() == TransactionType::HashOnly || transaction.object.get_snapshot_index() == 0 {
                         inclusion_states[count] = -1;
                     } else if transaction.object.get_snapshot_index() > max_tips_index {
@@ -321,14 +494,18 @@ impl API {
         if let Ok(hive) = pmnc.hive.lock() {
             // TODO: rem
             if let Ok(m) = pmnc.milestone.lock() {
-                let transaction = hive.storage_load_transaction(&m.latest_solid_subhive_milestone).unwrap();
-                same_index_tips.insert(transaction.object.snapshot, vec![m.latest_solid_subhive_milestone]);
+                if let Some(transaction) = hive.storage_load_transaction(&m.latest_solid_subhive_milestone) {
+                    same_index_tips.insert(transaction.object.snapshot, vec![m.latest_solid_subhive_milestone]);
+                }
             } else {
-                panic!()
+                panic!("broken milestone mutex");
             }
 
             for tip in tips.iter() {
-                let transaction = hive.storage_load_transaction(tip).unwrap();
+                let transaction = match hive.storage_load_transaction(tip) {
+                    Some(t) => t,
+                    None => return Err(APIError::TipAbsent)
+                };
                 if transaction.get_type() == TransactionType::HashOnly {
                     return Err(APIError::TipAbsent);
                 }
@@ -341,7 +518,14 @@ impl API {
 
             for i in 0..inclusion_states.len() {
                 if inclusion_states[i] == 0 {
-                    let transaction = hive.storage_load_transaction(&transactions[i]).unwrap();
+                    // unknown transactions can't be included
+                    let transaction = match hive.storage_load_transaction(&transactions[i]) {
+                        Some(t) => t,
+                        None => {
+                            inclusion_states[i] = -1;
+                            continue;
+                        }
+                    };
                     let snapshot_index = transaction.object.get_snapshot_index();
 
                     if !same_index_transaction_count.contains_key(&snapshot_index) {
@@ -391,7 +575,10 @@ impl API {
         'main_loop: while let Some(pointer) = non_analyzed_transactions.pop() {
             if analyzed_tips.insert(pointer) {
                 if let Ok(hive) = pmnc.hive.lock() {
-                    let transaction = hive.storage_load_transaction(&pointer).unwrap();
+                    let transaction = match hive.storage_load_transaction(&pointer) {
+                        Some(t) => t,
+                        None => return Ok(false)
+                    };
 
                     if transaction.object.get_snapshot_index() == index {
                         if transaction.get_type() == TransactionType::HashOnly {
@@ -436,6 +623,110 @@ impl API {
         }
     }
 
+    // a solid transaction the walks can't reach any more is stuck until it is promoted or replayed
+    fn transaction_status(pmnc: &mut PaymonCoin, hash: &Hash) -> TransactionStatus {
+        API::status_of(&pmnc.ledger_validator, &pmnc.tips_manager, hash)
+    }
+
+    fn status_of(ledger_validator: &AM<LedgerValidator>, tips_manager: &AM<TipsManager>, hash: &Hash) -> TransactionStatus {
+        let status = match ledger_validator.lock() {
+            Ok(lv) => lv.get_transaction_status(hash),
+            Err(_) => panic!("broken ledger validator mutex")
+        };
+
+        if status == TransactionStatus::Solid {
+            if let Ok(tm) = tips_manager.lock() {
+                if tm.is_orphaned(*hash) {
+                    return TransactionStatus::Orphaned;
+                }
+            } else {
+                panic!("broken tips manager mutex");
+            }
+        }
+        status
+    }
+
+    pub fn get_transaction_status(pmnc: &mut PaymonCoin, hashes: &Vec<Hash>) -> Result<Vec<rpc::TransactionState>, APIError> {
+        if hashes.len() > MAX_GET_TX_STATUS {
+            return Err(APIError::Overflow);
+        }
+
+        let mut statuses = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            let status = API::transaction_status(pmnc, hash);
+            statuses.push(rpc::TransactionState {
+                hash: *hash,
+                status: status.name().to_string(),
+                milestone_index: status.milestone_index(),
+            });
+        }
+
+        Ok(statuses)
+    }
+
+    // why a transaction in this status can't be approved, `None` if it can
+    fn inconsistency_reason(status: TransactionStatus) -> Option<&'static str> {
+        match status {
+            TransactionStatus::Unknown => Some("transaction is missing"),
+            TransactionStatus::Pending => Some("transaction is not solid"),
+            TransactionStatus::Conflicting => Some("inconsistent ledger"),
+            TransactionStatus::Orphaned => Some("transaction is below max depth"),
+            TransactionStatus::Solid | TransactionStatus::Confirmed(_) => None
+        }
+    }
+
+    /// Whether the transactions can be approved together, i.e. whether reattaching on top of them
+    /// can still be confirmed
+    pub fn check_consistency(ledger_validator: &AM<LedgerValidator>, tips_manager: &AM<TipsManager>,
+                             hashes: &Vec<Hash>) -> Result<rpc::Consistency, APIError> {
+        if hashes.is_empty() {
+            return Err(APIError::InvalidData);
+        }
+        if hashes.len() > MAX_CHECK_CONSISTENCY {
+            return Err(APIError::Overflow);
+        }
+
+        let mut reason = None;
+        let mut states = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            let status = API::status_of(ledger_validator, tips_manager, hash);
+            let hash_reason = API::inconsistency_reason(status);
+            if reason.is_none() {
+                reason = hash_reason.map(|r| format!("{}: {:?}", r, hash));
+            }
+            states.push(rpc::ConsistencyState {
+                hash: *hash,
+                status: status.name().to_string(),
+                consistent: hash_reason.is_none(),
+            });
+        }
+
+        // the ledger can only be checked when every transaction is known and solid
+        if reason.is_none() {
+            let consistent = match ledger_validator.lock() {
+                Ok(mut lv) => lv.check_consistency(hashes)?,
+                Err(_) => panic!("broken ledger validator mutex")
+            };
+            if !consistent {
+                reason = Some("inconsistent ledger".to_string());
+            }
+        }
+
+        Ok(rpc::Consistency {
+            consistent: reason.is_none(),
+            reason: reason.unwrap_or(String::new()),
+            states,
+        })
+    }
+
+    pub fn get_conflicts(pmnc: &mut PaymonCoin) -> Vec<Conflict> {
+        if let Ok(lv) = pmnc.ledger_validator.lock() {
+            lv.get_conflicts()
+        } else {
+            panic!("broken ledger validator mutex")
+        }
+    }
+
     pub fn get_transactions_data(pmnc: &mut PaymonCoin, hashes: &Vec<Hash>) -> Result<Vec<String>, APIError> {
         use network::packet::SerializedBuffer;
         let mut elements = Vec::<String>::new();
@@ -509,6 +800,160 @@ impl API {
         Ok(found_transactions.into_iter().collect::<Vec<Hash>>())
     }
 
+    // Does PoW for already hashed and signed transactions. The first one approves trunk and
+    // branch, every next one approves the previous transaction and trunk. The hash covers the
+    // attachment, so every attached transaction gets a new one.
+    pub fn attach_to_tangle(trunk: Hash, branch: Hash, mwm: Difficulty,
+                            transactions: Vec<TransactionObject>) -> Result<Vec<TransactionObject>, APIError> {
+        if transactions.is_empty() {
+            return Err(APIError::InvalidRequest);
+        }
+
+        if transactions.len() > MAX_ATTACH_TXS {
+            return Err(APIError::Overflow);
+        }
+
+        let token = AttachToken::register();
+
+        let mut attached = Vec::<TransactionObject>::with_capacity(transactions.len());
+        let mut previous: Option<Hash> = None;
+
+        for object in transactions {
+            let mut transaction = Transaction::from_object(object);
+            if transaction.object.hash != transaction.calculate_hash() {
+                return Err(APIError::InvalidData);
+            }
+
+            match previous {
+                Some(hash) => {
+                    transaction.object.trunk_transaction = hash;
+                    transaction.object.branch_transaction = trunk;
+                }
+                None => {
+                    transaction.object.trunk_transaction = trunk;
+                    transaction.object.branch_transaction = branch;
+                }
+            }
+
+            transaction.object.attachment_timestamp = SystemClock.now_ms();
+            transaction.object.attachment_timestamp_lower_bound = 0;
+            transaction.object.attachment_timestamp_upper_bound = std::u64::MAX;
+            transaction.object.hash = transaction.calculate_hash();
+
+            transaction.object.nonce = match transaction.find_nonce_interruptible(mwm, &token.0) {
+                Some(nonce) => nonce,
+                None => return Err(APIError::Interrupted)
+            };
+
+            previous = Some(transaction.get_hash());
+            attached.push(transaction.object);
+        }
+
+        Ok(attached)
+    }
+
+    /// Issues a zero-value transaction approving the stuck transaction and a fresh tip, so walks
+    /// that reach the tip confirm the stuck one as well. PMNC is not locked during PoW.
+    fn promote_transaction(arc: &AM<PaymonCoin>, hash: Hash, depth: u32) -> Result<Hash, APIError> {
+        let (tip, mwm, keys) = match arc.lock() {
+            Ok(ref mut pmnc) => {
+                API::check_promotable(API::transaction_status(pmnc, &hash))?;
+
+                let tip = match API::get_transactions_to_approve(pmnc, depth, None, MIN_RANDOM_WALKS, None, None)? {
+                    Some((trunk, _)) => trunk,
+                    None => return Err(APIError::TipAbsent)
+                };
+                let mwm = match pmnc.node.lock() {
+                    Ok(node) => node.required_min_weight_magnitude(&TransactionSource::Api),
+                    Err(_) => panic!("broken node mutex")
+                };
+                (tip, mwm, pmnc.node_keys.clone())
+            }
+            Err(_) => panic!("broken pmnc mutex")
+        };
+
+        let (ref sk, ref pk) = *keys;
+        let transaction = API::promotion(hash, tip, mwm, sk, pk)?;
+        let promotion = transaction.get_hash();
+
+        API::broadcast_transaction(arc, rpc::BroadcastTransaction {
+            transaction: transaction.object
+        })?;
+
+        Ok(promotion)
+    }
+
+    pub fn check_promotable(status: TransactionStatus) -> Result<(), TransactionError> {
+        match status {
+            TransactionStatus::Solid => Ok(()),
+            _ => Err(TransactionError::NotPromotable(status))
+        }
+    }
+
+    // Stuck transactions, below max depth or with missing history, are what replaying is for
+    pub fn check_replayable(status: TransactionStatus) -> Result<(), TransactionError> {
+        match status {
+            TransactionStatus::Confirmed(_) | TransactionStatus::Conflicting =>
+                Err(TransactionError::NotReplayable(status)),
+            _ => Ok(())
+        }
+    }
+
+    /// Zero-value transaction to the node's own address approving `hash` and `tip`
+    pub fn promotion(hash: Hash, tip: Hash, mwm: Difficulty, sk: &PrivateKey, pk: &PublicKey)
+        -> Result<Transaction, TransactionError> {
+        TransactionBuilder::new()
+            .recipient(Address::from_public_key(pk))
+            .parents(hash, tip)
+            .min_weight_magnitude(mwm)
+            .build(sk, pk)
+    }
+
+    /// Attaches already signed transactions on top of fresh tips and broadcasts them. Each one
+    /// gets a new hash, stored ones are left as they are.
+    fn replay_bundle(arc: &AM<PaymonCoin>, depth: u32, transactions: Vec<TransactionObject>) -> Result<Vec<Hash>, APIError> {
+        let (trunk, branch, mwm) = match arc.lock() {
+            Ok(ref mut pmnc) => {
+                for object in &transactions {
+                    API::check_replayable(API::transaction_status(pmnc, &object.hash))?;
+                }
+
+                let (trunk, branch) = match API::get_transactions_to_approve(pmnc, depth, None, MIN_RANDOM_WALKS, None, None)? {
+                    Some(tips) => tips,
+                    None => return Err(APIError::TipAbsent)
+                };
+                let mwm = match pmnc.node.lock() {
+                    Ok(node) => node.required_min_weight_magnitude(&TransactionSource::Api),
+                    Err(_) => panic!("broken node mutex")
+                };
+                (trunk, branch, mwm)
+            }
+            Err(_) => panic!("broken pmnc mutex")
+        };
+
+        let attached = API::attach_to_tangle(trunk, branch, mwm, transactions)?;
+
+        let mut hashes = Vec::with_capacity(attached.len());
+        for object in attached {
+            hashes.push(object.hash);
+            API::broadcast_transaction(arc, rpc::BroadcastTransaction {
+                transaction: object
+            })?;
+        }
+
+        Ok(hashes)
+    }
+
+    /// Interrupts the attaches in progress, later ones are not affected
+    pub fn interrupt_attaching_to_tangle() {
+        match ATTACHES_IN_PROGRESS.lock() {
+            Ok(attaches) => for interrupted in attaches.iter() {
+                interrupted.store(true, Ordering::SeqCst);
+            },
+            Err(_) => panic!("broken attaches mutex")
+        };
+    }
+
     fn api(req: &mut Request) -> IronResult<Response> {
         if req.method != iron::method::Post {
             return Ok(API::format_error_response("Wrong HTTP method"));
@@ -550,11 +995,10 @@ impl API {
                                     Ok(bt) => {
                                         unsafe {
                                             if let Some(ref arc) = PMNC {
-                                                if let Ok(pmnc) = arc.lock() {
-                                                    if let Ok(mut node) = pmnc.node.lock() {
-                                                        debug!("rcvd tx");
-                                                        node.on_api_broadcast_transaction_received(bt);
-                                                    }
+                                                debug!("rcvd tx");
+                                                if let Err(e) = API::broadcast_transaction(arc, bt) {
+                                                    warn!("rejected tx: {} (code {})", e, e.code());
+                                                    return Ok(API::format_transaction_error(&e));
                                                 }
                                             }
                                         }
@@ -575,40 +1019,21 @@ impl API {
                                                     if API::invalid_subtangle_status(pmnc) {
                                                         return Ok(API::format_error_response("The subhive has not been updated yet"));
                                                     }
-                                                    use rand::{thread_rng, Rng};
-//                                                    let depth = 3;
-//                                                    let num_walks = thread_rng().gen_range(2, 5);
                                                     let depth = object.depth;
-                                                    let mut num_walks = match object.num_walks {
-                                                        0 => 1,
-                                                        v => v
+                                                    let (reference, num_walks, selector) = match API::tip_selection_params(
+                                                        pmnc, depth, object.num_walks, object.reference, &object.strategy) {
+                                                        Ok(params) => params,
+                                                        Err(e) => return Ok(API::format_error_response(e))
                                                     };
-                                                    if num_walks < MIN_RANDOM_WALKS {
-                                                        num_walks = MIN_RANDOM_WALKS;
-                                                    }
-
-//                                                    let reference = match object.reference {
-//                                                        v => Some(v),
-//                                                        HASH_NULL => None,
-//                                                    };
-
-                                                    let reference;
-                                                    if object.reference == HASH_NULL {
-                                                        reference = None;
-                                                    } else {
-                                                        reference = Some(object.reference);
-                                                    }
-
-                                                    if depth < 0 || (reference.is_none() && depth == 0) {
-                                                        return Ok(API::format_error_response("Invalid depth input"));
-                                                    }
 
                                                     debug!("num_walks={}", num_walks);
 
                                                     match API::get_transactions_to_approve(pmnc,
                                                                                            depth,
                                                                                            reference,
-                                                                                           num_walks) {
+                                                                                           num_walks,
+                                                                                           selector.as_ref().map(|s| &**s),
+                                                                                           None) {
                                                         Ok(Some((trunk, branch))) => {
                                                             let result = rpc::TransactionsToApprove {
                                                                 branch,
@@ -617,7 +1042,7 @@ impl API {
                                                             return format_success_response!(result);
                                                         }
                                                         Ok(None) => return Ok(API::format_error_response("None")),
-                                                        _ => return Ok(API::format_error_response("Internal error"))
+                                                        Err(e) => return Ok(API::format_transaction_error(&e))
                                                     }
                                                 }
                                             }
@@ -629,11 +1054,59 @@ impl API {
                             }
                             "getNodeInfo" => {
                                 debug!("getNodeInfo");
+                                let mut min_weight_magnitude = 0;
+                                let mut excluded_tips = (0, 0);
+                                unsafe {
+                                    if let Some(ref arc) = PMNC {
+                                        if let Ok(pmnc) = arc.lock() {
+                                            if let Ok(node) = pmnc.node.lock() {
+                                                min_weight_magnitude = node.current_min_weight_magnitude().bits();
+                                            }
+                                            if let Ok(tm) = pmnc.tips_manager.lock() {
+                                                excluded_tips = tm.excluded_tips();
+                                            }
+                                        }
+                                    }
+                                }
                                 let result = rpc::NodeInfo {
-                                    name: "PMNC 0.1".to_string()
+                                    name: "PMNC 0.1".to_string(),
+                                    min_weight_magnitude,
+                                    lazy_tips_excluded: excluded_tips.0 as u64,
+                                    parasitic_tips_excluded: excluded_tips.1 as u64,
                                 };
                                 format_success_response!(result)
                             }
+                            "getTipSelectionDiagnostics" => {
+                                debug!("getTipSelectionDiagnostics");
+                                match json::decode::<rpc::GetTipSelectionDiagnostics>(&json_str) {
+                                    Ok(object) => {
+                                        unsafe {
+                                            if let Some(ref arc) = PMNC {
+                                                if let Ok(ref mut pmnc) = arc.lock() {
+                                                    if API::invalid_subtangle_status(pmnc) {
+                                                        return Ok(API::format_error_response("The subhive has not been updated yet"));
+                                                    }
+                                                    let depth = object.depth;
+                                                    let (reference, num_walks, selector) = match API::tip_selection_params(
+                                                        pmnc, depth, object.num_walks, object.reference, &object.strategy) {
+                                                        Ok(params) => params,
+                                                        Err(e) => return Ok(API::format_error_response(e))
+                                                    };
+
+                                                    let result = API::get_tip_selection_diagnostics(pmnc, depth, reference, num_walks,
+                                                                                                    selector.as_ref().map(|s| &**s));
+                                                    return format_success_response!(result);
+                                                } else {
+                                                    panic!("broken pmnc mutex");
+                                                }
+                                            } else {
+                                                panic!("None returned");
+                                            }
+                                        }
+                                    }
+                                    Err(e) => return Ok(API::format_error_response("Invalid data"))
+                                }
+                            }
                             "getBalances" => {
                                 debug!("getBalances");
 
@@ -654,9 +1127,7 @@ impl API {
                                                         }
                                                         Err(e) => {
                                                             error!("{:?}", e);
-                                                            return Ok
-                                                                (API::format_error_response
-                                                                    ("Internal error"));
+                                                            return Ok(API::format_transaction_error(&e));
                                                         }
                                                     }
                                                 }
@@ -682,6 +1153,8 @@ impl API {
                                                             };
                                                             return format_success_response!(result);
                                                         }
+                                                        Err(APIError::TipAbsent) => return Ok(API::format_error_response("One of the tips is absent")),
+                                                        Err(APIError::TheSubHiveIsNotSolid) => return Ok(API::format_error_response("The subhive is not solid")),
                                                         _ => return Ok(API::format_error_response("Internal error"))
                                                     }
                                                 } else {
@@ -773,6 +1246,201 @@ impl API {
                                     Err(e) => return Ok(API::format_error_response("Invalid data"))
                                 }
                             }
+                            "getTransactionStatus" => {
+                                debug!("getTransactionStatus");
+                                match json::decode::<rpc::GetTransactionStatus>(&json_str) {
+                                    Ok(object) => {
+                                        unsafe {
+                                            if let Some(ref mut arc) = PMNC {
+                                                if let Ok(ref mut pmnc) = arc.lock() {
+                                                    match API::get_transaction_status(pmnc, &object.hashes) {
+                                                        Ok(statuses) => {
+                                                            let result = rpc::TransactionStatuses {
+                                                                statuses
+                                                            };
+                                                            return format_success_response!(result);
+                                                        }
+                                                        Err(APIError::Overflow) => return Ok(API::format_error_response("Too many hashes")),
+                                                        _ => return Ok(API::format_error_response("Internal error"))
+                                                    }
+                                                } else {
+                                                    panic!("broken pmnc mutex");
+                                                }
+                                            } else {
+                                                panic!("None returned");
+                                            }
+                                        }
+                                    }
+                                    Err(e) => return Ok(API::format_error_response("Invalid data"))
+                                }
+                            }
+                            "checkConsistency" => {
+                                debug!("checkConsistency");
+                                match json::decode::<rpc::CheckConsistency>(&json_str) {
+                                    Ok(object) => {
+                                        unsafe {
+                                            if let Some(ref mut arc) = PMNC {
+                                                if let Ok(ref mut pmnc) = arc.lock() {
+                                                    if API::invalid_subtangle_status(pmnc) {
+                                                        return Ok(API::format_error_response("The subhive has not been updated yet"));
+                                                    }
+                                                    match API::check_consistency(&pmnc.ledger_validator, &pmnc.tips_manager, &object.hashes) {
+                                                        Ok(result) => return format_success_response!(result),
+                                                        Err(APIError::Overflow) => return Ok(API::format_error_response("Too many hashes")),
+                                                        Err(APIError::InvalidData) => return Ok(API::format_error_response("No hashes")),
+                                                        Err(APIError::Transaction(e)) => return Ok(API::format_transaction_error(&e)),
+                                                        _ => return Ok(API::format_error_response("Internal error"))
+                                                    }
+                                                } else {
+                                                    panic!("broken pmnc mutex");
+                                                }
+                                            } else {
+                                                panic!("None returned");
+                                            }
+                                        }
+                                    }
+                                    Err(e) => return Ok(API::format_error_response("Invalid data"))
+                                }
+                            }
+                            "getConflicts" => {
+                                debug!("getConflicts");
+                                unsafe {
+                                    if let Some(ref mut arc) = PMNC {
+                                        if let Ok(ref mut pmnc) = arc.lock() {
+                                            let result = rpc::Conflicts {
+                                                conflicts: API::get_conflicts(pmnc)
+                                            };
+                                            return format_success_response!(result);
+                                        } else {
+                                            panic!("broken pmnc mutex");
+                                        }
+                                    } else {
+                                        panic!("None returned");
+                                    }
+                                }
+                            }
+                            "attachToTangle" => {
+                                debug!("attachToTangle");
+                                match json::decode::<rpc::AttachToTangle>(&json_str) {
+                                    Ok(object) => {
+                                        let node_mwm;
+                                        unsafe {
+                                            if let Some(ref arc) = PMNC {
+                                                if let Ok(pmnc) = arc.lock() {
+                                                    node_mwm = match pmnc.node.lock() {
+                                                        Ok(node) => node.required_min_weight_magnitude(&TransactionSource::Api),
+                                                        Err(_) => panic!("broken node mutex")
+                                                    };
+                                                } else {
+                                                    panic!("broken pmnc mutex");
+                                                }
+                                            } else {
+                                                panic!("None returned");
+                                            }
+                                        }
+
+                                        if object.min_weight_magnitude > Difficulty::MAX ||
+                                            !node_mwm.is_met_by(Difficulty(object.min_weight_magnitude)) {
+                                            return Ok(API::format_error_response("Invalid min_weight_magnitude"));
+                                        }
+
+                                        // PoW may take a while, so PMNC must not be locked here
+                                        match API::attach_to_tangle(object.trunk_transaction,
+                                                                    object.branch_transaction,
+                                                                    Difficulty(object.min_weight_magnitude),
+                                                                    object.transactions) {
+                                            Ok(transactions) => {
+                                                let result = rpc::AttachedTransactions {
+                                                    transactions
+                                                };
+                                                return format_success_response!(result);
+                                            }
+                                            Err(APIError::Interrupted) => return Ok(API::format_error_response("Interrupted")),
+                                            Err(e) => {
+                                                error!("{:?}", e);
+                                                return Ok(API::format_error_response("Invalid data"));
+                                            }
+                                        }
+                                    }
+                                    Err(e) => return Ok(API::format_error_response("Invalid data"))
+                                }
+                            }
+                            "promoteTransaction" => {
+                                debug!("promoteTransaction");
+                                match json::decode::<rpc::PromoteTransaction>(&json_str) {
+                                    Ok(object) => {
+                                        unsafe {
+                                            if let Some(ref arc) = PMNC {
+                                                match arc.lock() {
+                                                    Ok(ref mut pmnc) => if API::invalid_subtangle_status(pmnc) {
+                                                        return Ok(API::format_error_response("The subhive has not been updated yet"));
+                                                    },
+                                                    Err(_) => panic!("broken pmnc mutex")
+                                                };
+
+                                                match API::promote_transaction(arc, object.hash, object.depth) {
+                                                    Ok(hash) => {
+                                                        let result = rpc::PromotedTransaction {
+                                                            hash
+                                                        };
+                                                        return format_success_response!(result);
+                                                    }
+                                                    Err(APIError::Transaction(e)) => return Ok(API::format_transaction_error(&e)),
+                                                    Err(APIError::TipAbsent) => return Ok(API::format_error_response("No tip to approve")),
+                                                    Err(e) => {
+                                                        error!("{:?}", e);
+                                                        return Ok(API::format_error_response("Internal error"));
+                                                    }
+                                                }
+                                            } else {
+                                                panic!("None returned");
+                                            }
+                                        }
+                                    }
+                                    Err(e) => return Ok(API::format_error_response("Invalid data"))
+                                }
+                            }
+                            "replayBundle" => {
+                                debug!("replayBundle");
+                                match json::decode::<rpc::ReplayBundle>(&json_str) {
+                                    Ok(object) => {
+                                        unsafe {
+                                            if let Some(ref arc) = PMNC {
+                                                match arc.lock() {
+                                                    Ok(ref mut pmnc) => if API::invalid_subtangle_status(pmnc) {
+                                                        return Ok(API::format_error_response("The subhive has not been updated yet"));
+                                                    },
+                                                    Err(_) => panic!("broken pmnc mutex")
+                                                };
+
+                                                match API::replay_bundle(arc, object.depth, object.transactions) {
+                                                    Ok(hashes) => {
+                                                        let result = rpc::ReplayedBundle {
+                                                            hashes
+                                                        };
+                                                        return format_success_response!(result);
+                                                    }
+                                                    Err(APIError::Transaction(e)) => return Ok(API::format_transaction_error(&e)),
+                                                    Err(APIError::TipAbsent) => return Ok(API::format_error_response("No tip to approve")),
+                                                    Err(APIError::Interrupted) => return Ok(API::format_error_response("Interrupted")),
+                                                    Err(e) => {
+                                                        error!("{:?}", e);
+                                                        return Ok(API::format_error_response("Invalid data"));
+                                                    }
+                                                }
+                                            } else {
+                                                panic!("None returned");
+                                            }
+                                        }
+                                    }
+                                    Err(e) => return Ok(API::format_error_response("Invalid data"))
+                                }
+                            }
+                            "interruptAttachingToTangle" => {
+                                debug!("interruptAttachingToTangle");
+                                API::interrupt_attaching_to_tangle();
+                                return Ok(Response::with((iron::status::Ok, "{}")));
+                            }
                             _ => Ok(API::format_error_response("Unknown 'method' parameter"))
                         }
                     }
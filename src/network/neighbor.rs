@@ -9,9 +9,14 @@ use std::collections::VecDeque;
 use network::packet;
 use network::packet::{SerializedBuffer, Serializable};
 use std::net::{SocketAddr, IpAddr};
+use std::time::{Duration, Instant};
 //use network::replicator::*;
 use network::replicator_new::*;
 
+// milestone subhives served to one neighbor per window, each may load a page of the storage
+pub const MAX_MILESTONE_REQUESTS: u32 = 30;
+pub const MILESTONE_REQUEST_WINDOW: u64 = 10; // seconds
+
 pub struct Neighbor {
     pub addr: SocketAddr,
 //    pub replicator_source: Option<Weak<Mutex<ReplicatorSource>>>,
@@ -19,6 +24,8 @@ pub struct Neighbor {
     pub sink: Option<ReplicatorSink>,
     pub source: Option<ReplicatorSource>,
     pub connecting: bool,
+    // start of the current window and the milestone requests served in it
+    milestone_requests: Option<(Instant, u32)>,
 }
 
 impl Neighbor {
@@ -31,6 +38,7 @@ impl Neighbor {
             source: None,
             sink: None,
             connecting: false,
+            milestone_requests: None,
         }
     }
 
@@ -40,6 +48,7 @@ impl Neighbor {
             source: None,
             sink: None,
             connecting: false,
+            milestone_requests: None,
         }
     }
 
@@ -49,6 +58,7 @@ impl Neighbor {
             source: Some(replicator),
             sink: None,
             connecting: false,
+            milestone_requests: None,
         }
     }
 
@@ -59,7 +69,22 @@ impl Neighbor {
             source: None,
             sink: Some(replicator),
             connecting: false,
+            milestone_requests: None,
+        }
+    }
+
+    /// Whether a milestone request of this neighbor may be served now, which uses up its budget
+    pub fn take_milestone_request(&mut self, now: Instant) -> bool {
+        let (start, served) = match self.milestone_requests {
+            Some((start, served)) if now.duration_since(start) < Duration::from_secs(MILESTONE_REQUEST_WINDOW) =>
+                (start, served),
+            _ => (now, 0)
+        };
+        if served >= MAX_MILESTONE_REQUESTS {
+            return false;
         }
+        self.milestone_requests = Some((start, served + 1));
+        true
     }
 
     pub fn get_sockaddr(&self) -> SocketAddr {
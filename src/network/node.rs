@@ -15,19 +15,23 @@ use storage::Hive;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use model::transaction;
-use model::transaction::{Hash, HASH_NULL};
-use rand::{Rng, thread_rng};
-use utils::{AM, AWM};
+use model::transaction::{Hash, HASH_NULL, Difficulty};
+use rand::{Rng, StdRng};
+use utils::{AM, AWM, seeded_rng, random};
 use network::rpc;
 use model::*;
+use model::transaction_validator::TransactionError;
 
 extern fn handle_sigint(_:i32) {
     println!("Interrupted!");
     panic!();
 }
 
+const RECEIVE_BATCH_SIZE: usize = 64;
+const MAX_SUBHIVE_TRANSACTIONS: usize = 500;
+
 struct Pair<U, V> {
     pub low: U,
     pub hi: V,
@@ -40,20 +44,24 @@ pub struct Node {
     node_tx: Sender<()>,
     pmnc_rx: Receiver<()>,
     pub broadcast_queue: AM<VecDeque<Transaction>>,
-    receive_queue: AM<VecDeque<Transaction>>,
+    receive_queue: AM<VecDeque<(Transaction, TransactionSource)>>,
     reply_queue: AM<VecDeque<(Hash, AM<Neighbor>)>>,
     running: Arc<AtomicBool>,
     thread_join_handles: VecDeque<JoinHandle<()>>,
     transaction_validator: AM<TransactionValidator>,
     transaction_requester: AM<TransactionRequester>,
     tips_vm: AM<TipsViewModel>,
-    milestone: AM<Milestone>
+    milestone: AM<Milestone>,
+    milestone_sync: AM<MilestoneSync>,
+    difficulty_policy: AM<DifficultyPolicy>,
+    signature_verifier: AM<SignatureVerifier>,
 }
 
 impl Node {
     pub fn new(hive: Weak<Mutex<Hive>>, config: &Configuration, node_tx: Sender<()>, pmnc_rx:
     Receiver<()>, transaction_validator: AM<TransactionValidator>, transaction_requester:
-    AM<TransactionRequester>, tips_vm: AM<TipsViewModel>, milestone: AM<Milestone>)
+    AM<TransactionRequester>, tips_vm: AM<TipsViewModel>, milestone: AM<Milestone>,
+               difficulty_policy: AM<DifficultyPolicy>, signature_verifier: AM<SignatureVerifier>)
         -> Node {
         Node {
             hive,
@@ -69,7 +77,10 @@ impl Node {
             transaction_requester,
             transaction_validator,
             tips_vm,
-            milestone
+            milestone,
+            milestone_sync: make_am!(MilestoneSync::new()),
+            difficulty_policy,
+            signature_verifier,
         }
     }
 
@@ -99,8 +110,10 @@ impl Node {
         let broadcast_queue_weak = Arc::downgrade(&self.broadcast_queue.clone());
         let neighbors_weak = Arc::downgrade(&self.neighbors.clone());
         let tr_weak = Arc::downgrade(&self.transaction_requester.clone());
+        let seed = self.config.random_seed();
+        let rng = seeded_rng(seed, random::RNG_NODE_BROADCAST);
         let jh = thread::spawn(|| Node::broadcast_thread(running_weak, broadcast_queue_weak, neighbors_weak,
-                                                         tr_weak));
+                                                         tr_weak, rng));
         self.thread_join_handles.push_back(jh);
 
         let running_weak = Arc::downgrade(&self.running.clone());
@@ -108,8 +121,15 @@ impl Node {
         let broadcast_queue_weak = Arc::downgrade(&self.broadcast_queue.clone());
         let hive_weak = self.hive.clone();
         let tv_weak = Arc::downgrade(&self.transaction_validator.clone());
+        let policy_weak = Arc::downgrade(&self.difficulty_policy.clone());
+        let verifier_weak = Arc::downgrade(&self.signature_verifier.clone());
+        let milestone_notifier = match self.milestone.lock() {
+            Ok(milestone) => milestone.notifier(),
+            Err(_) => panic!("broken milestone mutex")
+        };
         let jh = thread::spawn(|| Node::receive_thread(running_weak, receive_queue_weak,
-                                                       broadcast_queue_weak, hive_weak, tv_weak));
+                                                       broadcast_queue_weak, hive_weak, tv_weak,
+                                                       policy_weak, verifier_weak, milestone_notifier));
         self.thread_join_handles.push_back(jh);
 
         let running_weak = Arc::downgrade(&self.running.clone());
@@ -118,16 +138,76 @@ impl Node {
         let tr_weak = Arc::downgrade(&self.transaction_requester.clone());
         let ms_weak = Arc::downgrade(&self.milestone.clone());
         let tvm_weak = Arc::downgrade(&self.tips_vm.clone());
+        let rng = seeded_rng(seed, random::RNG_NODE_REPLY);
         let jh = thread::spawn(|| Node::reply_thread(running_weak, reply_queue_weak, hive_weak,
-                                                     tr_weak, ms_weak, tvm_weak));
+                                                     tr_weak, ms_weak, tvm_weak, rng));
+        self.thread_join_handles.push_back(jh);
+
+        let running_weak = Arc::downgrade(&self.running.clone());
+        let neighbors_weak = Arc::downgrade(&self.neighbors.clone());
+        let ms_weak = Arc::downgrade(&self.milestone.clone());
+        let sync_weak = Arc::downgrade(&self.milestone_sync.clone());
+        let rng = seeded_rng(seed, random::RNG_NODE_SYNC);
+        let jh = thread::spawn(|| Node::sync_thread(running_weak, neighbors_weak, ms_weak, sync_weak, rng));
         self.thread_join_handles.push_back(jh);
 
         self.thread_join_handles.push_back(replicator_jh);
     }
 
+    // Requests missing milestones with their subhives one by one while the node is behind, and
+    // asks for the next milestone now and then to learn the network's latest index
+    fn sync_thread(running: Weak<AtomicBool>, neighbors: AWM<Vec<AM<Neighbor>>>, milestone: AWM<Milestone>,
+                   milestone_sync: AWM<MilestoneSync>, mut rng: StdRng) {
+        loop {
+            if let Some(arc) = running.upgrade() {
+                if !arc.load(Ordering::SeqCst) { break; }
+            } else {
+                break;
+            }
+
+            let (latest, latest_solid) = match milestone.upgrade() {
+                Some(arc) => match arc.lock() {
+                    Ok(ms) => (ms.latest_milestone_index, ms.latest_solid_subhive_milestone_index),
+                    Err(_) => panic!("broken milestone mutex")
+                },
+                None => break
+            };
+
+            let sync_arc = match milestone_sync.upgrade() {
+                Some(arc) => arc,
+                None => break
+            };
+
+            let mut sync = match sync_arc.lock() {
+                Ok(sync) => sync,
+                Err(_) => panic!("broken milestone sync mutex")
+            };
+
+            if let Some((index, offset)) = sync.next_request(latest, latest_solid, Instant::now()) {
+                if let Some(arc) = neighbors.upgrade() {
+                    if let Ok(neighbors) = arc.lock() {
+                        if !neighbors.is_empty() {
+                            let n = &neighbors[rng.gen_range(0, neighbors.len())];
+                            if let Ok(mut n) = n.lock() {
+                                debug!("requesting milestone #{} at {} from {:?}", index, offset, n.addr);
+                                n.send_packet(rpc::RequestMilestone { index, offset });
+                                sync.on_request_sent(index, offset, n.addr.ip(), Instant::now());
+                            }
+                        }
+                    } else {
+                        panic!("broken neighbors mutex");
+                    }
+                }
+            }
+            drop(sync);
+
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
     fn reply_thread(running: Weak<AtomicBool>, reply_queue: AWM<VecDeque<(Hash, AM<Neighbor>)>>,
                     hive: AWM<Hive>, tr: AWM<TransactionRequester>, milestone: AWM<Milestone>,
-                    tvm: AWM<TipsViewModel>) {
+                    tvm: AWM<TipsViewModel>, mut rng: StdRng) {
         loop {
             if let Some(arc) = running.upgrade() {
                 let b = arc.load(Ordering::SeqCst);
@@ -143,9 +223,9 @@ impl Node {
                                     if let Ok(mut transaction_requester) = arc.lock() {
                                         // TODO: make P independent var
                                         if transaction_requester.num_transactions_to_request() >
-                                            0 && thread_rng().gen::<f64>() < 0.66 {
+                                            0 && rng.gen::<f64>() < 0.66 {
                                             let tip;
-                                            if thread_rng().gen::<f64>() < 0.02 {
+                                            if rng.gen::<f64>() < 0.02 {
                                                 if let Some(arc) = milestone.upgrade() {
                                                     if let Ok(mut ms) = arc.lock() {
                                                         tip = ms.latest_milestone;
@@ -190,7 +270,7 @@ impl Node {
                                 }
                             } else {
                                 // TODO: make P independent var
-                                if hash != HASH_NULL && thread_rng().gen::<f64>() < 0.01 {
+                                if hash != HASH_NULL && rng.gen::<f64>() < 0.01 {
                                     if let Some(arc) = tr.upgrade() {
                                         if let Ok(mut transaction_requester) = arc.lock() {
                                             transaction_requester.request_transaction(hash, false);
@@ -207,61 +287,121 @@ impl Node {
         }
     }
 
-    fn receive_thread(running: Weak<AtomicBool>, receive_queue: AWM<VecDeque<Transaction>>,
+    fn receive_thread(running: Weak<AtomicBool>, receive_queue: AWM<VecDeque<(Transaction, TransactionSource)>>,
                       broadcast_queue: AWM<VecDeque<Transaction>>, hive: AWM<Hive>, tv:
-                      AWM<TransactionValidator>) {
+                      AWM<TransactionValidator>, difficulty_policy: AWM<DifficultyPolicy>,
+                      signature_verifier: AWM<SignatureVerifier>, milestone_notifier: MilestoneNotifier) {
         loop {
             if let Some(arc) = running.upgrade() {
                 let b = arc.load(Ordering::SeqCst);
                 if !b { break; }
 
+                // cheap checks first, signatures of the whole batch are verified in parallel
+                let mut batch = Vec::<Transaction>::new();
+
                 if let Some(arc) = receive_queue.upgrade() {
                     if let Ok(mut queue) = arc.lock() {
-                        if let Some(mut t) = queue.pop_front() {
-                            info!("received tx: {:?}", t.get_hash());
-                            let address = t.object.address.clone();
-                            let validated = transaction::validate_transaction(&mut t, 3);
-                            info!("validated={}", validated);
-
-                            if validated {
-                                let mut stored;
-                                if let Some(arc) = hive.upgrade() {
-                                    if let Ok(mut hive) = arc.lock() {
-                                        stored = hive.put_transaction(&t);
-                                        info!("stored={}", stored);
-                                    } else {
-                                        panic!("broken hive mutex");
-                                    }
-                                } else {
-                                    continue;
-                                }
+                        while batch.len() < RECEIVE_BATCH_SIZE {
+                            let (mut t, source) = match queue.pop_front() {
+                                Some(item) => item,
+                                None => break
+                            };
+
+                            info!("received tx: {:?} from {:?}", t.get_hash(), source);
+                            let mwm = match difficulty_policy.upgrade() {
+                                Some(arc) => match arc.lock() {
+                                    Ok(policy) => policy.required(&source, queue.len(), Instant::now()),
+                                    Err(_) => panic!("broken difficulty policy mutex")
+                                },
+                                None => continue
+                            };
+
+                            let known = match hive.upgrade() {
+                                Some(arc) => match arc.lock() {
+                                    Ok(hive) => hive.exists_transaction(t.get_hash()),
+                                    Err(_) => panic!("broken hive mutex")
+                                },
+                                None => continue
+                            };
+                            if known || batch.iter().any(|b| b.get_hash() == t.get_hash()) {
+                                continue;
+                            }
 
-                                if stored {
-                                    if let Some(arc) = tv.upgrade() {
-                                        if let Ok(mut tv) = arc.lock() {
-                                            if let Err(e) = tv.update_status(&mut t) {
-                                                error!("update status err {:?}", e);
-                                                continue;
-                                            }
-                                        }
-                                    }
-                                    if let Some(arc) = broadcast_queue.upgrade() {
-                                        if let Ok(mut broadcast_queue) = arc.lock() {
-                                            broadcast_queue.push_back(t);
-                                        }
+                            let validated = match tv.upgrade() {
+                                Some(arc) => match arc.lock() {
+                                    Ok(tv) => tv.validate_without_signature(&mut t, mwm),
+                                    Err(_) => panic!("broken transaction validator mutex")
+                                },
+                                None => continue
+                            };
+
+                            match validated {
+                                Ok(_) => batch.push(t),
+                                Err(e) => warn!("rejected tx {:?} from {:?}: {} (code {})", t.get_hash(), source,
+                                                e, e.code())
+                            }
+                        }
+                    }
+                }
+
+                if batch.is_empty() {
+                    thread::sleep(Duration::from_secs(1));
+                    continue;
+                }
+
+                let verified = match signature_verifier.upgrade() {
+                    Some(arc) => match arc.lock() {
+                        Ok(mut verifier) => verifier.verify_batch(batch),
+                        Err(_) => panic!("broken signature verifier mutex")
+                    },
+                    None => continue
+                };
+
+                for (mut t, valid) in verified {
+                    if !valid {
+                        let e = TransactionError::InvalidSignature;
+                        warn!("rejected tx {:?}: {} (code {})", t.get_hash(), e, e.code());
+                        continue;
+                    }
+
+                    let mut stored;
+                    if let Some(arc) = hive.upgrade() {
+                        if let Ok(mut hive) = arc.lock() {
+                            stored = hive.put_transaction(&t);
+                            info!("stored={}", stored);
+                        } else {
+                            panic!("broken hive mutex");
+                        }
+                    } else {
+                        continue;
+                    }
+
+                    if stored {
+                        if let Some(arc) = tv.upgrade() {
+                            if let Ok(mut tv) = arc.lock() {
+                                match tv.update_status(&mut t) {
+                                    Ok(milestone_dependency) => milestone_notifier.on_transaction_stored(&t,
+                                                                                         milestone_dependency),
+                                    Err(e) => {
+                                        error!("update status err {:?}", e);
+                                        continue;
                                     }
                                 }
                             }
                         }
+                        if let Some(arc) = broadcast_queue.upgrade() {
+                            if let Ok(mut broadcast_queue) = arc.lock() {
+                                broadcast_queue.push_back(t);
+                            }
+                        }
                     }
                 }
-
-                thread::sleep(Duration::from_secs(1));
             }
         }
     }
 
-    fn broadcast_thread(running: Weak<AtomicBool>, broadcast_queue: AWM<VecDeque<Transaction>>, neighbors: AWM<Vec<AM<Neighbor>>>, tr: AWM<TransactionRequester>) {
+    fn broadcast_thread(running: Weak<AtomicBool>, broadcast_queue: AWM<VecDeque<Transaction>>, neighbors: AWM<Vec<AM<Neighbor>>>, tr: AWM<TransactionRequester>,
+                        mut rng: StdRng) {
         loop {
             if let Some(arc) = running.upgrade() {
                 let b = arc.load(Ordering::SeqCst);
@@ -282,7 +422,7 @@ impl Node {
                                                 if let Ok(mut tr) = arc.lock() {
                                                     // TODO: get probability from var
                                                     if let Some(hash) = tr
-                                                        .poll_transaction_to_request(thread_rng().gen::<f64>() < 0.7) {
+                                                        .poll_transaction_to_request(rng.gen::<f64>() < 0.7) {
                                                         n.send_packet(rpc::RequestTransaction {
                                                             hash
                                                         });
@@ -311,10 +451,45 @@ impl Node {
 
     // TODO: return Result
     pub fn on_api_broadcast_transaction_received(&mut self, bt: rpc::BroadcastTransaction) {
+        let mut transaction = Transaction::from_object(bt.transaction);
+        self.enqueue_received(transaction, TransactionSource::Api);
+    }
+
+    fn enqueue_received(&mut self, transaction: Transaction, source: TransactionSource) {
+        if let Ok(mut policy) = self.difficulty_policy.lock() {
+            policy.record(source, Instant::now());
+        } else {
+            panic!("broken difficulty policy mutex");
+        }
+
         if let Ok(mut queue) = self.receive_queue.lock() {
-            let mut transaction = Transaction::from_object(bt.transaction);
-            queue.push_back(transaction);
-//            return Ok(());
+            queue.push_back((transaction, source));
+        }
+    }
+
+    // MWM currently advertised by this node
+    pub fn current_min_weight_magnitude(&self) -> Difficulty {
+        let queue_len = match self.receive_queue.lock() {
+            Ok(queue) => queue.len(),
+            Err(_) => panic!("broken receive queue mutex")
+        };
+
+        match self.difficulty_policy.lock() {
+            Ok(policy) => policy.current(queue_len),
+            Err(_) => panic!("broken difficulty policy mutex")
+        }
+    }
+
+    // MWM a new transaction from `source` would have to reach right now
+    pub fn required_min_weight_magnitude(&self, source: &TransactionSource) -> Difficulty {
+        let queue_len = match self.receive_queue.lock() {
+            Ok(queue) => queue.len(),
+            Err(_) => panic!("broken receive queue mutex")
+        };
+
+        match self.difficulty_policy.lock() {
+            Ok(policy) => policy.required(source, queue_len, Instant::now()),
+            Err(_) => panic!("broken difficulty policy mutex")
         }
     }
 
@@ -358,22 +533,12 @@ impl Node {
 
         match svuid {
             TransactionObject::SVUID => {
-                let mut transaction_object = TransactionObject::new();
-                transaction_object.read_params(&mut data);
-
-                let mut transaction = Transaction::from_object(transaction_object);
-                if let Ok(mut queue) = self.receive_queue.lock() {
-                    queue.push_back(transaction);
-                }
+                let transaction = Transaction::read_from_stream(&mut data);
+                self.enqueue_received(transaction, TransactionSource::Neighbor(addr.ip()));
             }
             rpc::AttachTransaction::SVUID => {
-                let mut transaction_object = TransactionObject::new();
-                transaction_object.read_params(&mut data);
-
-                let mut transaction = Transaction::from_object(transaction_object);
-                if let Ok(mut queue) = self.receive_queue.lock() {
-                    queue.push_back(transaction);
-                }
+                let transaction = Transaction::read_from_stream(&mut data);
+                self.enqueue_received(transaction, TransactionSource::Neighbor(addr.ip()));
             }
             rpc::RequestTransaction::SVUID => {
                 let mut tx_request = rpc::RequestTransaction { hash: HASH_NULL };
@@ -384,12 +549,86 @@ impl Node {
                     queue.push_back((hash, neighbor.clone()));
                 }
             }
+            rpc::RequestMilestone::SVUID => {
+                let mut request = rpc::RequestMilestone { index: 0, offset: 0 };
+                request.read_params(&mut data);
+
+                let allowed = match neighbor.lock() {
+                    Ok(mut n) => n.take_milestone_request(Instant::now()),
+                    Err(_) => panic!("broken neighbor mutex")
+                };
+                if !allowed {
+                    debug!("dropped request of milestone #{} from {:?}: over budget", request.index, addr);
+                    return;
+                }
+
+                let subhive = self.milestone_subhive(request.index, request.offset);
+                if let Ok(mut n) = neighbor.lock() {
+                    n.send_packet(subhive);
+                }
+            }
+            rpc::MilestoneSubHive::SVUID => {
+                let mut subhive = rpc::MilestoneSubHive {
+                    index: 0,
+                    offset: 0,
+                    latest_milestone_index: 0,
+                    complete: true,
+                    transactions: Vec::new(),
+                };
+                subhive.read_params(&mut data);
+                subhive.transactions.truncate(MAX_SUBHIVE_TRANSACTIONS);
+                debug!("received subhive of milestone #{} ({} txs)", subhive.index, subhive.transactions.len());
+
+                // only an answer to our own request is synced data, anything else is gossip and has to
+                // meet the congestion MWM
+                let solicited = match self.milestone_sync.lock() {
+                    Ok(mut sync) => sync.on_subhive(subhive.index, subhive.offset, addr.ip(),
+                                                    subhive.latest_milestone_index,
+                                                    subhive.transactions.len() as u32, subhive.complete,
+                                                    Instant::now()),
+                    Err(_) => panic!("broken milestone sync mutex")
+                };
+                let source = if solicited {
+                    TransactionSource::Sync(addr.ip())
+                } else {
+                    TransactionSource::Neighbor(addr.ip())
+                };
+
+                for object in subhive.transactions {
+                    self.enqueue_received(Transaction::from_object(object), source);
+                }
+            }
             _ => {
                 warn!("Unknown SVUID {}", svuid);
             }
         }
     }
 
+    // Empty when the milestone is unknown here, the reply still tells the latest index
+    fn milestone_subhive(&self, index: u32, offset: u32) -> rpc::MilestoneSubHive {
+        let latest_milestone_index = match self.milestone.lock() {
+            Ok(milestone) => milestone.latest_milestone_index,
+            Err(_) => panic!("broken milestone mutex")
+        };
+
+        let (transactions, complete) = match self.hive.upgrade() {
+            Some(arc) => match arc.lock() {
+                Ok(hive) => hive.load_milestone_subhive(index, offset as usize, MAX_SUBHIVE_TRANSACTIONS)
+                    .unwrap_or((Vec::new(), true)),
+                Err(_) => panic!("broken hive mutex")
+            },
+            None => (Vec::new(), true)
+        };
+
+        rpc::MilestoneSubHive {
+            index,
+            offset,
+            latest_milestone_index,
+            complete,
+            transactions,
+        }
+    }
+
     pub fn broadcast(&mut self, transaction: Transaction) {
         if let Ok(ref mut queue) = self.broadcast_queue.lock() {
             queue.push_back(transaction);
@@ -1,3 +1,5 @@
+extern crate ntrumls;
+
 use std::{
     sync::{mpsc::{Receiver, Sender, channel}, Arc, Weak, Mutex},
     io::{self, Read},
@@ -14,11 +16,16 @@ use network::replicator_new::ReplicatorNew;
 use model::config::{PORT, Configuration, ConfigurationSettings};
 use model::config;
 use model::TipsViewModel;
-use model::transaction::Address;
-use utils::{AM, AWM};
+use model::transaction::{Address, Difficulty};
+use model::transaction_validator::MAINNET_MIN_WEIGHT_MAGNITUDE;
+use utils::{AM, AWM, seeded_rng, random};
 use model::*;
 use std::time;
 use std::str::FromStr;
+use rustc_serialize::hex::FromHex;
+use self::ntrumls::{NTRUMLS, PQParamSetID, PrivateKey, PublicKey};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 
 pub struct PaymonCoin {
     pub hive: AM<Hive>,
@@ -32,45 +39,127 @@ pub struct PaymonCoin {
     pub transaction_validator: AM<TransactionValidator>,
     pub milestone: AM<Milestone>,
     pub ledger_validator: AM<LedgerValidator>,
-    pub tips_manager: AM<TipsManager>
+    pub tips_manager: AM<TipsManager>,
+    pub difficulty_policy: AM<DifficultyPolicy>,
+    pub signature_verifier: AM<SignatureVerifier>,
+    pub milestone_coordinator: Option<AM<Coordinator>>,
+    // signs the node's own zero-value transactions, e.g. promotions
+    pub node_keys: Arc<(PrivateKey, PublicKey)>,
 }
 
 impl PaymonCoin {
-    pub fn new(config: Configuration) -> Self {
+    pub fn new(mut config: Configuration) -> Self {
         let snapshot_timestamp = 1526912331;
-        let coordinator = Address::from_str("P65DC4FEED4819C2910FA2DFC107399B7437ABAE2E7").unwrap();
-        let num_keys_milestone = 22;
-        let milestone_start_index = 1;
+        let testnet = config.get_bool(ConfigurationSettings::TestNet).unwrap_or(false);
+        let num_keys_milestone = config.get_int(ConfigurationSettings::NumKeysInMilestone).unwrap_or(22) as u32;
+        let milestone_start_index = config.get_int(ConfigurationSettings::MilestoneStartIndex).unwrap_or(1) as u32;
+        let accept_any_testnet_coo = config.get_bool(ConfigurationSettings::AcceptAnyTestnetCoo).unwrap_or(false);
+
+        // checked by the caller before the node is built
+        let coordinator = match config.coordinator() {
+            Ok(coordinator) => coordinator,
+            Err(e) => panic!("{}", e)
+        };
 
         let snapshot = Snapshot::init("db/snapshot.dat".to_string(), "".to_string()).expect("Can't \
         load \
         snapshot");
-        let mut hive = Arc::new(Mutex::new(Hive::new()));
+        let persistent_db = config.get_bool(ConfigurationSettings::PersistentDB).unwrap_or(false);
+        let mut hive = Arc::new(Mutex::new(Hive::open(persistent_db)));
 
         // used for shutdown replicator pool
         let (replicator_tx, replicator_rx) = channel::<()>();
         let (pmnc_tx, pmnc_rx) = channel::<()>();
 //        let (tx, rx) = channel();
 
-        let mut tips_vm: AM<TipsViewModel> = make_am!(TipsViewModel::new());
-        let mut transaction_requester: AM<TransactionRequester> = make_am!(TransactionRequester::new(hive.clone(), 0.001));
+        let seed = config.random_seed();
+        if let Some(seed) = seed {
+            info!("random seed: {}", seed);
+        }
+
+        let mut tips_vm: AM<TipsViewModel> = make_am!(TipsViewModel::new(seeded_rng(seed, random::RNG_TIPS_VIEW_MODEL)));
+        if persistent_db {
+            let tips = if let Ok(h) = hive.lock() {
+                h.storage_load_tips()
+            } else {
+                panic!("broken hive mutex")
+            };
+            if let Ok(mut t_v_m) = tips_vm.lock() {
+                t_v_m.load_tips(&tips);
+            } else {
+                panic!("broken tips view model mutex")
+            }
+            info!("restored {} tips from storage", tips.len());
+        }
+        let mut transaction_requester: AM<TransactionRequester> = make_am!(TransactionRequester::new(hive.clone(), 0.001,
+            seeded_rng(seed, random::RNG_TRANSACTION_REQUESTER)));
         let mut transaction_validator = TransactionValidator::new(hive.clone(), tips_vm.clone(),
                                                                   snapshot_timestamp, transaction_requester.clone());
         let mut milestone = Milestone::new(hive.clone(), coordinator.clone(), snapshot,
-                                           transaction_validator.clone(), true,
-                                           num_keys_milestone, milestone_start_index, true);
+                                           transaction_validator.clone(), testnet,
+                                           num_keys_milestone, milestone_start_index, accept_any_testnet_coo);
+
+        let difficulty_policy: AM<DifficultyPolicy> = make_am!(DifficultyPolicy::new(
+            Difficulty(MAINNET_MIN_WEIGHT_MAGNITUDE),
+            Difficulty::new(config.get_int(ConfigurationSettings::MaxMWM).unwrap_or(20) as u32),
+            config.get_int(ConfigurationSettings::MWMQueueThreshold).unwrap_or(100) as usize,
+            config.get_int(ConfigurationSettings::MWMRateThreshold).unwrap_or(20) as usize,
+            Duration::from_secs(config.get_int(ConfigurationSettings::MWMRateWindow).unwrap_or(10) as u64)));
+
+        let signature_verifier: AM<SignatureVerifier> = make_am!(SignatureVerifier::new(
+            config.get_int(ConfigurationSettings::SignatureVerifierThreads).unwrap_or(4) as usize,
+            config.get_int(ConfigurationSettings::SignatureCacheSize).unwrap_or(10000) as usize));
 
         let mut node = Arc::new(Mutex::new(Node::new(Arc::downgrade(&hive.clone()), &config,
                                                      replicator_tx, pmnc_rx,
                                                      transaction_validator.clone(),
                                                      transaction_requester.clone(), tips_vm
-                                                         .clone(), milestone.clone())));
+                                                         .clone(), milestone.clone(),
+                                                     difficulty_policy.clone(),
+                                                     signature_verifier.clone())));
         let mut ledger_validator: AM<LedgerValidator> = make_am!(LedgerValidator::new(hive.clone(),
                                                                               milestone.clone(),
                                                         transaction_requester.clone()));
+        let alpha = config.get_float(ConfigurationSettings::TipSelectionAlpha)
+            .map(|a| a as f64).unwrap_or(tip_selection::DEFAULT_ALPHA);
+        if !(alpha >= 0.0) {
+            panic!("Invalid param TipSelectionAlpha: must not be negative");
+        }
+        let tip_selector = tip_selection::from_name(&config.get_string(ConfigurationSettings::TipSelection)
+            .unwrap_or("mcmc".to_string()), alpha).expect("Invalid param TipSelection");
+        let tip_heuristics = tip_selection::TipHeuristics {
+            max_age_ms: config.get_int(ConfigurationSettings::MaxTipAge).unwrap_or(3600) as u64 * 1000,
+            max_milestone_lag: config.get_int(ConfigurationSettings::MaxTipMilestoneLag).unwrap_or(5) as u32,
+            max_parent_lag_ms: config.get_int(ConfigurationSettings::MaxTipParentLag).unwrap_or(1800) as u64 * 1000,
+        };
         let mut tips_manager = TipsManager::new(hive.clone(), milestone.clone(), ledger_validator
-            .clone(), transaction_validator.clone(), tips_vm.clone(), 15, true,
-                                                milestone_start_index);
+            .clone(), transaction_validator.clone(), tips_vm.clone(), 15, testnet,
+                                                milestone_start_index, tip_selector, alpha,
+                                                seeded_rng(seed, random::RNG_TIPS_MANAGER), tip_heuristics,
+                                                config.get_int(ConfigurationSettings::TipSelectionThreads)
+                                                    .unwrap_or(4) as usize);
+
+        let mut milestone_coordinator = None;
+        if config.get_bool(ConfigurationSettings::CoordinatorEnabled).unwrap_or(false) {
+            let keys = PaymonCoin::load_coordinator_keys(&config.get_string(ConfigurationSettings::CoordinatorKeys)
+                .unwrap_or(String::new()));
+            let coo = Coordinator::new(hive.clone(), milestone.clone(), tips_manager.clone(), node.clone(), keys,
+                                       num_keys_milestone,
+                                       Duration::from_secs(config.get_int(ConfigurationSettings::CoordinatorInterval)
+                                           .unwrap_or(60) as u64),
+                                       15, config.get_int(ConfigurationSettings::MinRandomWalks).unwrap_or(5) as u32);
+
+            if let Ok(c) = coo.lock() {
+                if c.get_address() != coordinator {
+                    warn!("coordinator keys commit to {:?}, not to coordinator address {:?}, milestones will be \
+                    rejected", c.get_address(), coordinator);
+                }
+            }
+            milestone_coordinator = Some(coo);
+        }
+
+        let node_keys = Arc::new(NTRUMLS::with_param_set(PQParamSetID::Security269Bit).generate_keypair()
+            .expect("failed to generate node keypair"));
         PaymonCoin {
             hive,
             node,
@@ -84,19 +173,47 @@ impl PaymonCoin {
             milestone,
             ledger_validator,
             tips_manager,
+            difficulty_policy,
+            signature_verifier,
+            milestone_coordinator,
+            node_keys,
         }
     }
 
+    // one hex encoded private key per line, key #n signs milestone #n
+    fn load_coordinator_keys(path: &str) -> Vec<(PrivateKey, PublicKey)> {
+        let file = File::open(path).expect("coordinator keys file not found");
+        let mls = NTRUMLS::with_param_set(PQParamSetID::Security269Bit);
+
+        let mut keys = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.expect("can't read coordinator keys file");
+            if line.trim().is_empty() {
+                continue;
+            }
+            let sk = line.trim().from_hex().expect("invalid coordinator key");
+            let fg = mls.unpack_fg_from_private_key(&PrivateKey(sk)).expect("invalid coordinator key");
+            keys.push(mls.generate_keypair_from_fg(&fg).expect("invalid coordinator key"));
+        }
+        keys
+    }
+
     pub fn run(&mut self) -> AM<Node> {
         Milestone::init(self.milestone.clone(), self.ledger_validator.clone());
         if let Ok(mut tv) = self.transaction_validator.lock() {
             let test_net = self.config.get_bool(ConfigurationSettings::TestNet).unwrap_or(false);
-            let wmw = if test_net {
-                self.config.get_int(ConfigurationSettings::MainNetMWM).unwrap_or(9)
-            } else {
+            let mwm = if test_net {
                 self.config.get_int(ConfigurationSettings::TestNetMWM).unwrap_or(8)
+            } else {
+                self.config.get_int(ConfigurationSettings::MainNetMWM).unwrap_or(9)
             } as u32;
-            tv.init(test_net, wmw);
+            tv.init(test_net, Difficulty::new(mwm));
+
+            if let Ok(mut policy) = self.difficulty_policy.lock() {
+                policy.set_base(tv.get_min_weight_magnitude());
+            } else {
+                panic!("broken difficulty policy mutex");
+            }
         }
         TipsManager::init(self.tips_manager.clone());
 
@@ -114,11 +231,18 @@ impl PaymonCoin {
             guard.init(replicator_jh);
         }
 
+        if let Some(ref coo) = self.milestone_coordinator {
+            Coordinator::init(coo.clone());
+        }
+
         self.node.clone()
     }
 
     pub fn shutdown(&mut self) {
 //        self.node.lock().unwrap().shutdown();
+        if let Some(coo) = self.milestone_coordinator.take() {
+            Coordinator::shutdown(coo);
+        }
         if let Ok(mut m) = self.milestone.lock() {
             m.shutdown();
         }
@@ -1,7 +1,8 @@
 use network::packet::{Serializable, SerializedBuffer};
 use model::{
-    Transaction, TransactionObject,
-    transaction::*
+    Transaction, TransactionObject, Conflict,
+    transaction::*,
+    tip_selection::{SelectionTrace, WalkTrace, WalkStep, StopReason, TipExclusion}
 };
 
 /**
@@ -29,6 +30,9 @@ impl Serializable for GetNodeInfo {
 #[derive(RustcDecodable, RustcEncodable)]
 pub struct NodeInfo {
     pub name: String,
+    pub min_weight_magnitude: u32,
+    pub lazy_tips_excluded: u64,
+    pub parasitic_tips_excluded: u64,
 }
 
 impl NodeInfo {
@@ -39,10 +43,16 @@ impl Serializable for NodeInfo {
     fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
         stream.write_i32(Self::SVUID);
         stream.write_string(self.name.clone());
+        stream.write_u32(self.min_weight_magnitude);
+        stream.write_u64(self.lazy_tips_excluded);
+        stream.write_u64(self.parasitic_tips_excluded);
     }
 
     fn read_params(&mut self, stream: &mut SerializedBuffer) {
         self.name = stream.read_string();
+        self.min_weight_magnitude = stream.read_u32();
+        self.lazy_tips_excluded = stream.read_u64();
+        self.parasitic_tips_excluded = stream.read_u64();
     }
 }
 
@@ -97,7 +107,9 @@ impl Serializable for BroadcastTransaction {
 pub struct GetTransactionsToApprove {
     pub depth: u32,
     pub num_walks: u32,
-    pub reference: Hash
+    pub reference: Hash,
+    // tip selection strategy, the configured one if absent
+    pub strategy: Option<String>
 }
 
 impl GetTransactionsToApprove { pub const SVUID : i32 = 5; }
@@ -114,6 +126,14 @@ impl Serializable for GetTransactionsToApprove {
         } else {
             stream.write_bool(false);
         }
+
+        match self.strategy {
+            Some(ref strategy) => {
+                stream.write_bool(true);
+                stream.write_string(strategy.clone());
+            }
+            None => stream.write_bool(false)
+        }
     }
 
     fn read_params(&mut self, stream: &mut SerializedBuffer) {
@@ -125,6 +145,12 @@ impl Serializable for GetTransactionsToApprove {
         } else {
             self.reference = HASH_NULL;
         }
+
+        self.strategy = if stream.read_bool() {
+            Some(stream.read_string())
+        } else {
+            None
+        };
     }
 }
 
@@ -508,3 +534,665 @@ impl Serializable for TransactionsData {
         }
     }
 }
+
+/**
+    AttachToTangle
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct AttachToTangle {
+    pub trunk_transaction: Hash,
+    pub branch_transaction: Hash,
+    pub min_weight_magnitude: u32,
+    pub transactions: Vec<TransactionObject>,
+}
+
+impl AttachToTangle { pub const SVUID : i32 = 18; }
+
+impl Serializable for AttachToTangle {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_bytes(&self.trunk_transaction);
+        stream.write_bytes(&self.branch_transaction);
+        stream.write_u32(self.min_weight_magnitude);
+
+        stream.write_u32(self.transactions.len() as u32);
+        for tx in &self.transactions {
+            tx.serialize_to_stream(stream);
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        stream.read_bytes(&mut self.trunk_transaction, HASH_SIZE);
+        stream.read_bytes(&mut self.branch_transaction, HASH_SIZE);
+        self.min_weight_magnitude = stream.read_u32();
+
+        self.transactions.clear();
+        let len = stream.read_u32();
+        for _ in 0..len {
+            let _ = stream.read_i32();
+            let mut tx = TransactionObject::new();
+            tx.read_params(stream);
+            self.transactions.push(tx);
+        }
+    }
+}
+
+/**
+    AttachedTransactions
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct AttachedTransactions {
+    pub transactions: Vec<TransactionObject>,
+}
+
+impl AttachedTransactions { pub const SVUID : i32 = 19; }
+
+impl Serializable for AttachedTransactions {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+
+        stream.write_u32(self.transactions.len() as u32);
+        for tx in &self.transactions {
+            tx.serialize_to_stream(stream);
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        self.transactions.clear();
+
+        let len = stream.read_u32();
+        for _ in 0..len {
+            let _ = stream.read_i32();
+            let mut tx = TransactionObject::new();
+            tx.read_params(stream);
+            self.transactions.push(tx);
+        }
+    }
+}
+
+/**
+    InterruptAttachingToTangle
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct InterruptAttachingToTangle {}
+
+impl InterruptAttachingToTangle { pub const SVUID : i32 = 20; }
+
+impl Serializable for InterruptAttachingToTangle {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {}
+}
+
+/**
+    RequestMilestone
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct RequestMilestone {
+    pub index: u32,
+    // transactions of the subhive to skip, to continue a truncated one
+    pub offset: u32,
+}
+
+impl RequestMilestone { pub const SVUID : i32 = 21; }
+
+impl Serializable for RequestMilestone {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_u32(self.index);
+        stream.write_u32(self.offset);
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        self.index = stream.read_u32();
+        self.offset = stream.read_u32();
+    }
+}
+
+/**
+    MilestoneSubHive
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct MilestoneSubHive {
+    pub index: u32,
+    pub offset: u32,
+    pub latest_milestone_index: u32,
+    // false when the subhive was truncated, the rest is requested from `offset + transactions.len()`
+    pub complete: bool,
+    pub transactions: Vec<TransactionObject>,
+}
+
+impl MilestoneSubHive { pub const SVUID : i32 = 22; }
+
+impl Serializable for MilestoneSubHive {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_u32(self.index);
+        stream.write_u32(self.offset);
+        stream.write_u32(self.latest_milestone_index);
+        stream.write_bool(self.complete);
+
+        stream.write_u32(self.transactions.len() as u32);
+        for tx in &self.transactions {
+            tx.serialize_to_stream(stream);
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        self.index = stream.read_u32();
+        self.offset = stream.read_u32();
+        self.latest_milestone_index = stream.read_u32();
+        self.complete = stream.read_bool();
+
+        self.transactions.clear();
+        let len = stream.read_u32();
+        for _ in 0..len {
+            // sent by peers, don't trust the count and stop at the first malformed transaction
+            if !stream.has_remaining() {
+                break;
+            }
+            let _ = stream.read_i32();
+            let mut tx = TransactionObject::new();
+            if !Transaction::read_object(&mut tx, stream) {
+                break;
+            }
+            self.transactions.push(tx);
+        }
+    }
+}
+
+/**
+    GetConflicts
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct GetConflicts {}
+
+impl GetConflicts { pub const SVUID : i32 = 23; }
+
+impl Serializable for GetConflicts {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {}
+}
+
+/**
+    Conflicts
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct Conflicts {
+    pub conflicts: Vec<Conflict>,
+}
+
+impl Conflicts { pub const SVUID : i32 = 24; }
+
+impl Serializable for Conflicts {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_u32(self.conflicts.len() as u32);
+        for conflict in &self.conflicts {
+            stream.write_u32(conflict.milestone_index);
+            stream.write_bytes(&conflict.address);
+            stream.write_i64(conflict.balance);
+            stream.write_u32(conflict.transactions.len() as u32);
+            for hash in &conflict.transactions {
+                stream.write_bytes(&hash);
+            }
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        self.conflicts.clear();
+
+        let len = stream.read_u32();
+        for _ in 0..len {
+            let milestone_index = stream.read_u32();
+            let mut address = ADDRESS_NULL;
+            stream.read_bytes(&mut address.0, ADDRESS_SIZE);
+            let balance = stream.read_i64();
+
+            let mut transactions = Vec::new();
+            let tx_len = stream.read_u32();
+            for _ in 0..tx_len {
+                let mut hash: Hash = HASH_NULL;
+                stream.read_bytes(&mut hash, HASH_SIZE);
+                transactions.push(hash);
+            }
+
+            self.conflicts.push(Conflict {
+                milestone_index,
+                address,
+                balance,
+                transactions,
+            });
+        }
+    }
+}
+
+/**
+    GetTransactionStatus
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct GetTransactionStatus {
+    pub hashes: Vec<Hash>
+}
+
+impl GetTransactionStatus { pub const SVUID : i32 = 25; }
+
+impl Serializable for GetTransactionStatus {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_u32(self.hashes.len() as u32);
+        for hash in &self.hashes {
+            stream.write_bytes(&hash);
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        self.hashes.clear();
+
+        let len = stream.read_u32();
+        for _ in 0..len {
+            let mut hash: Hash = HASH_NULL;
+            stream.read_bytes(&mut hash, HASH_SIZE);
+            self.hashes.push(hash);
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct TransactionState {
+    pub hash: Hash,
+    pub status: String,
+    // index of the confirming milestone, 0 unless confirmed
+    pub milestone_index: u32,
+}
+
+/**
+    TransactionStatuses
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct TransactionStatuses {
+    pub statuses: Vec<TransactionState>
+}
+
+impl TransactionStatuses { pub const SVUID : i32 = 26; }
+
+impl Serializable for TransactionStatuses {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_u32(self.statuses.len() as u32);
+        for state in &self.statuses {
+            stream.write_bytes(&state.hash);
+            stream.write_string(state.status.clone());
+            stream.write_u32(state.milestone_index);
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        self.statuses.clear();
+
+        let len = stream.read_u32();
+        for _ in 0..len {
+            let mut hash: Hash = HASH_NULL;
+            stream.read_bytes(&mut hash, HASH_SIZE);
+            let status = stream.read_string();
+            let milestone_index = stream.read_u32();
+            self.statuses.push(TransactionState {
+                hash,
+                status,
+                milestone_index,
+            });
+        }
+    }
+}
+
+/**
+    PromoteTransaction
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct PromoteTransaction {
+    pub hash: Hash,
+    pub depth: u32,
+}
+
+impl PromoteTransaction { pub const SVUID : i32 = 27; }
+
+impl Serializable for PromoteTransaction {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_bytes(&self.hash);
+        stream.write_u32(self.depth);
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        stream.read_bytes(&mut self.hash, HASH_SIZE);
+        self.depth = stream.read_u32();
+    }
+}
+
+/**
+    PromotedTransaction
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct PromotedTransaction {
+    pub hash: Hash,
+}
+
+impl PromotedTransaction { pub const SVUID : i32 = 28; }
+
+impl Serializable for PromotedTransaction {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_bytes(&self.hash);
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        stream.read_bytes(&mut self.hash, HASH_SIZE);
+    }
+}
+
+/**
+    ReplayBundle
+*/
+// the hash does not cover the parents, so the transactions must be signed again with a new
+// timestamp, otherwise they are duplicates of the stuck ones
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct ReplayBundle {
+    pub depth: u32,
+    pub transactions: Vec<TransactionObject>,
+}
+
+impl ReplayBundle { pub const SVUID : i32 = 29; }
+
+impl Serializable for ReplayBundle {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_u32(self.depth);
+
+        stream.write_u32(self.transactions.len() as u32);
+        for tx in &self.transactions {
+            tx.serialize_to_stream(stream);
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        self.depth = stream.read_u32();
+
+        self.transactions.clear();
+        let len = stream.read_u32();
+        for _ in 0..len {
+            let _ = stream.read_i32();
+            let mut tx = TransactionObject::new();
+            tx.read_params(stream);
+            self.transactions.push(tx);
+        }
+    }
+}
+
+/**
+    ReplayedBundle
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct ReplayedBundle {
+    pub hashes: Vec<Hash>,
+}
+
+impl ReplayedBundle { pub const SVUID : i32 = 30; }
+
+impl Serializable for ReplayedBundle {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_u32(self.hashes.len() as u32);
+        for hash in &self.hashes {
+            stream.write_bytes(&hash);
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        self.hashes.clear();
+
+        let len = stream.read_u32();
+        for _ in 0..len {
+            let mut hash: Hash = HASH_NULL;
+            stream.read_bytes(&mut hash, HASH_SIZE);
+            self.hashes.push(hash);
+        }
+    }
+}
+
+/**
+    CheckConsistency
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct CheckConsistency {
+    pub hashes: Vec<Hash>
+}
+
+impl CheckConsistency { pub const SVUID : i32 = 31; }
+
+impl Serializable for CheckConsistency {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_u32(self.hashes.len() as u32);
+        for hash in &self.hashes {
+            stream.write_bytes(&hash);
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        self.hashes.clear();
+
+        let len = stream.read_u32();
+        for _ in 0..len {
+            let mut hash: Hash = HASH_NULL;
+            stream.read_bytes(&mut hash, HASH_SIZE);
+            self.hashes.push(hash);
+        }
+    }
+}
+
+/**
+    Consistency
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct ConsistencyState {
+    pub hash: Hash,
+    pub status: String,
+    // false if this transaction alone can't be approved
+    pub consistent: bool,
+}
+
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct Consistency {
+    pub consistent: bool,
+    // empty when consistent
+    pub reason: String,
+    pub states: Vec<ConsistencyState>,
+}
+
+impl Consistency { pub const SVUID : i32 = 32; }
+
+impl Serializable for Consistency {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_bool(self.consistent);
+        stream.write_string(self.reason.clone());
+        stream.write_u32(self.states.len() as u32);
+        for state in &self.states {
+            stream.write_bytes(&state.hash);
+            stream.write_string(state.status.clone());
+            stream.write_bool(state.consistent);
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        self.consistent = stream.read_bool();
+        self.reason = stream.read_string();
+        self.states.clear();
+
+        let len = stream.read_u32();
+        for _ in 0..len {
+            let mut hash: Hash = HASH_NULL;
+            stream.read_bytes(&mut hash, HASH_SIZE);
+            let status = stream.read_string();
+            let consistent = stream.read_bool();
+            self.states.push(ConsistencyState {
+                hash,
+                status,
+                consistent,
+            });
+        }
+    }
+}
+
+/**
+    GetTipSelectionDiagnostics
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct GetTipSelectionDiagnostics {
+    pub depth: u32,
+    pub num_walks: u32,
+    pub reference: Hash,
+    pub strategy: Option<String>
+}
+
+impl GetTipSelectionDiagnostics { pub const SVUID : i32 = 33; }
+
+impl Serializable for GetTipSelectionDiagnostics {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_u32(self.depth);
+        stream.write_u32(self.num_walks);
+        stream.write_bytes(&self.reference);
+
+        match self.strategy {
+            Some(ref strategy) => {
+                stream.write_bool(true);
+                stream.write_string(strategy.clone());
+            }
+            None => stream.write_bool(false)
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        self.depth = stream.read_u32();
+        self.num_walks = stream.read_u32();
+        stream.read_bytes(&mut self.reference, HASH_SIZE);
+
+        self.strategy = if stream.read_bool() {
+            Some(stream.read_string())
+        } else {
+            None
+        };
+    }
+}
+
+/**
+    TipSelectionDiagnostics
+*/
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct TipSelectionDiagnostics {
+    // HASH_NULL unless a pair was selected
+    pub trunk: Hash,
+    pub branch: Hash,
+    // what getTransactionsToApprove would have answered, empty on success
+    pub error: String,
+    // one per selected transaction, trunk first
+    pub selections: Vec<SelectionTrace>,
+}
+
+impl TipSelectionDiagnostics { pub const SVUID : i32 = 34; }
+
+impl Serializable for TipSelectionDiagnostics {
+    fn serialize_to_stream(&self, stream: &mut SerializedBuffer) {
+        stream.write_i32(Self::SVUID);
+        stream.write_bytes(&self.trunk);
+        stream.write_bytes(&self.branch);
+        stream.write_string(self.error.clone());
+
+        stream.write_u32(self.selections.len() as u32);
+        for selection in &self.selections {
+            stream.write_string(selection.strategy.clone());
+            stream.write_bytes(&selection.entry_point);
+            stream.write_bool(selection.entry_point_consistent);
+
+            stream.write_u32(selection.walks.len() as u32);
+            for walk in &selection.walks {
+                stream.write_u32(walk.path.len() as u32);
+                for step in &walk.path {
+                    stream.write_bytes(&step.hash);
+                    stream.write_u32(step.approvers.len() as u32);
+                    for (approver, rating) in step.approvers.iter().zip(step.ratings.iter()) {
+                        stream.write_bytes(approver);
+                        stream.write_i64(*rating);
+                    }
+                    stream.write_u32(step.excluded.len() as u32);
+                    for &(ref approver, exclusion) in &step.excluded {
+                        stream.write_bytes(approver);
+                        stream.write_i32(exclusion.code());
+                    }
+                }
+                stream.write_bytes(&walk.tail.unwrap_or(HASH_NULL));
+                stream.write_i32(walk.stop_reason.map(|r| r.code()).unwrap_or(-1));
+            }
+        }
+    }
+
+    fn read_params(&mut self, stream: &mut SerializedBuffer) {
+        stream.read_bytes(&mut self.trunk, HASH_SIZE);
+        stream.read_bytes(&mut self.branch, HASH_SIZE);
+        self.error = stream.read_string();
+        self.selections.clear();
+
+        let len = stream.read_u32();
+        for _ in 0..len {
+            let mut selection = SelectionTrace::new();
+            selection.strategy = stream.read_string();
+            stream.read_bytes(&mut selection.entry_point, HASH_SIZE);
+            selection.entry_point_consistent = stream.read_bool();
+
+            let walks_len = stream.read_u32();
+            for _ in 0..walks_len {
+                let mut walk = WalkTrace::new();
+                let path_len = stream.read_u32();
+                for _ in 0..path_len {
+                    let mut hash: Hash = HASH_NULL;
+                    stream.read_bytes(&mut hash, HASH_SIZE);
+                    let mut step = WalkStep {
+                        hash,
+                        approvers: Vec::new(),
+                        ratings: Vec::new(),
+                        excluded: Vec::new(),
+                    };
+                    let approvers_len = stream.read_u32();
+                    for _ in 0..approvers_len {
+                        let mut approver: Hash = HASH_NULL;
+                        stream.read_bytes(&mut approver, HASH_SIZE);
+                        step.approvers.push(approver);
+                        step.ratings.push(stream.read_i64());
+                    }
+                    let excluded_len = stream.read_u32();
+                    for _ in 0..excluded_len {
+                        let mut approver: Hash = HASH_NULL;
+                        stream.read_bytes(&mut approver, HASH_SIZE);
+                        if let Some(exclusion) = TipExclusion::from_code(stream.read_i32()) {
+                            step.excluded.push((approver, exclusion));
+                        }
+                    }
+                    walk.path.push(step);
+                }
+
+                let mut tail: Hash = HASH_NULL;
+                stream.read_bytes(&mut tail, HASH_SIZE);
+                walk.tail = if tail == HASH_NULL { None } else { Some(tail) };
+                walk.stop_reason = StopReason::from_code(stream.read_i32());
+                selection.walks.push(walk);
+            }
+            self.selections.push(selection);
+        }
+    }
+}
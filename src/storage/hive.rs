@@ -19,12 +19,13 @@ use model::transaction_validator::TransactionError;
 use model::transaction::*;
 use model::approvee::Approvee;
 use model::{StateDiffObject, StateDiff};
+use model::TransactionMetadata;
 use network::packet::{SerializedBuffer, Serializable, get_serialized_object};
 use std::time;
 use std::str::FromStr;
 
-static CF_NAMES: [&str; 7] = ["transaction", "transaction-metadata", "address",
-    "address_transactions", "approvee", "milestone", "state_diff"];
+static CF_NAMES: [&str; 8] = ["transaction", "transaction-metadata", "address",
+    "address_transactions", "approvee", "milestone", "state_diff", "confirmed_essence"];
 pub const SUPPLY : u64 = 10_000;
 
 pub enum Error {
@@ -42,6 +43,7 @@ pub enum CFType {
     Approvee,
     Milestone,
     StateDiff,
+    ConfirmedEssence,
 }
 
 pub struct Hive {
@@ -51,7 +53,12 @@ pub struct Hive {
 
 impl Hive {
     pub fn new() -> Self {
-        let db = Hive::init_db();
+        Hive::open(false)
+    }
+
+    /// A non-persistent hive starts empty, a persistent one keeps what the previous run stored
+    pub fn open(persistent: bool) -> Self {
+        let db = Hive::init_db(persistent);
 
         Hive {
             db,
@@ -90,6 +97,7 @@ impl Hive {
                 snapshot: 1,
                 solid: true,
                 height: 1,
+                payload: vec![],
             };
             let mut genesis = Transaction::from_object(genesis);
             genesis.object.nonce = 518;
@@ -128,6 +136,7 @@ impl Hive {
                 snapshot: 1,
                 solid: true,
                 height: 1,
+                payload: vec![],
             };
             let mut genesis = Transaction::from_object(genesis);
             genesis.object.nonce = 456;
@@ -167,6 +176,7 @@ impl Hive {
                 snapshot: 1,
                 solid: true,
                 height: 1,
+                payload: vec![],
             };
             let mut ms = Transaction::from_object(ms);
             ms.object.nonce = 46;
@@ -210,6 +220,7 @@ impl Hive {
                 snapshot: 1,
                 solid: true,
                 height: 2,
+                payload: vec![],
             };
             let mut genesis = Transaction::from_object(genesis);
             genesis.object.nonce = 46;
@@ -248,6 +259,7 @@ impl Hive {
                 snapshot: 1,
                 solid: true,
                 height: 3,
+                payload: vec![],
             };
             let mut genesis = Transaction::from_object(genesis);
             genesis.object.nonce = 134;
@@ -287,6 +299,7 @@ impl Hive {
                 snapshot: 1,
                 solid: true,
                 height: 4,
+                payload: vec![],
             };
             let mut ms = Transaction::from_object(ms);
             ms.object.nonce =152;
@@ -395,6 +408,39 @@ impl Hive {
         }
     }
 
+    // Transactions approved by milestone #index but not by an earlier one, parents first. At most
+    // `limit` of them from `offset` on, and whether those were the last ones.
+    pub fn load_milestone_subhive(&self, index: u32, offset: usize, limit: usize)
+        -> Option<(Vec<TransactionObject>, bool)> {
+        let milestone = self.storage_load_milestone(index)?;
+
+        let mut transactions = Vec::new();
+        let mut visited = HashSet::new();
+        let mut non_analyzed = LinkedList::new();
+        non_analyzed.push_back(milestone.get_hash());
+
+        while let Some(hash) = non_analyzed.pop_front() {
+            if hash == HASH_NULL || !visited.insert(hash) {
+                continue;
+            }
+
+            if let Some(t) = self.storage_load_transaction(&hash) {
+                if t.get_type() != TransactionType::Full ||
+                    (t.object.snapshot != 0 && t.object.snapshot < index) {
+                    continue;
+                }
+
+                non_analyzed.push_back(t.get_trunk_transaction_hash());
+                non_analyzed.push_back(t.get_branch_transaction_hash());
+                transactions.push(t.object);
+            }
+        }
+
+        transactions.reverse();
+        let complete = transactions.len() <= offset + limit;
+        Some((transactions.into_iter().skip(offset).take(limit).collect(), complete))
+    }
+
     pub fn find_closest_next_milestone(&self, index: u32, testnet: bool, milestone_start_index: u32) -> Option<MilestoneObject> {
         if !testnet && index <= milestone_start_index {
             return self.storage_first_milestone();
@@ -498,6 +544,56 @@ impl Hive {
         self.storage_put(CFType::Transaction, &t.object.hash, &t.object)
     }
 
+    /// Stored full transactions nobody approves yet, with their solid flag
+    pub fn storage_load_tips(&self) -> Vec<(Hash, bool)> {
+        let mut tips = Vec::new();
+        let it = self.db.iterator_cf(self.db.cf_handle(CF_NAMES[CFType::Transaction as usize]).unwrap(),
+                                     IteratorMode::Start).unwrap();
+        for (key, bytes) in it {
+            if key.len() != HASH_SIZE {
+                continue;
+            }
+            let mut hash = HASH_NULL;
+            hash.clone_from_slice(&key);
+
+            let has_approvers = match self.storage_load_approvee(&hash) {
+                Some(approvers) => !approvers.is_empty(),
+                None => false
+            };
+            if has_approvers {
+                continue;
+            }
+
+            let t = Transaction::from_bytes(SerializedBuffer::from_slice(&bytes));
+            if t.get_type() == TransactionType::Full {
+                tips.push((hash, t.is_solid()));
+            }
+        }
+        tips
+    }
+
+    pub fn put_transaction_metadata(&mut self, hash: &Hash, metadata: &TransactionMetadata) -> bool {
+        self.storage_put(CFType::TransactionMetadata, hash, metadata)
+    }
+
+    pub fn storage_load_transaction_metadata(&self, hash: &Hash) -> TransactionMetadata {
+        let mut metadata = TransactionMetadata::new();
+        match self.db.get_cf(self.db.cf_handle(CF_NAMES[CFType::TransactionMetadata as usize]).unwrap(), hash) {
+            Ok(Some(res)) => metadata.read_params(&mut SerializedBuffer::from_slice(&res)),
+            Ok(None) => {}
+            Err(e) => warn!("get transaction metadata from storage error ({})", e)
+        };
+        metadata
+    }
+
+    pub fn is_conflicting(&self, hash: &Hash) -> bool {
+        self.storage_load_transaction_metadata(hash).is_conflicting()
+    }
+
+    pub fn cumulative_weight(&self, hash: &Hash) -> i64 {
+        self.storage_load_transaction_metadata(hash).cumulative_weight()
+    }
+
     pub fn storage_put<T>(&mut self, t: CFType, key: &[u8], packet: &T) -> bool where T : Serializable {
         let object = get_serialized_object(packet, false);
         self.db.put_cf(self.db.cf_handle(CF_NAMES[t as usize]).unwrap(), key, &object).is_ok()
@@ -512,6 +608,26 @@ impl Hive {
         false
     }
 
+    /// Records the transaction a milestone confirmed for a signed essence, the first one wins
+    pub fn put_confirmed_essence(&mut self, essence: &Hash, hash: &Hash) -> bool {
+        if self.storage_load_confirmed_essence(essence).is_some() {
+            return false;
+        }
+        self.storage_put(CFType::ConfirmedEssence, essence, hash)
+    }
+
+    /// The confirmed attachment of a signed essence
+    pub fn storage_load_confirmed_essence(&self, essence: &Hash) -> Option<Hash> {
+        match self.db.get_cf(self.db.cf_handle(CF_NAMES[CFType::ConfirmedEssence as usize]).unwrap(), essence) {
+            Ok(Some(res)) => {
+                let mut hash = HASH_NULL;
+                hash.read_params(&mut SerializedBuffer::from_slice(&res));
+                Some(hash)
+            }
+            _ => None
+        }
+    }
+
     pub fn exists_transaction(&self, hash: Hash) -> bool {
         let vec = self.db.get_cf(self.db.cf_handle(CF_NAMES[CFType::Transaction as usize]).unwrap(), &hash);
         match vec {
@@ -618,7 +734,7 @@ impl Hive {
         }
     }
 
-    fn init_db() -> DB {
+    fn init_db(persistent: bool) -> DB {
         use self::rocksdb::merge_operator::MergeOperands;
         fn concat_merge(new_key: &[u8],
                         existing_val: Option<&[u8]>,
@@ -643,6 +759,8 @@ impl Hive {
         opts.set_max_background_compactions(2);
         opts.set_max_background_flushes(2);
         opts.set_merge_operator("bytes_concat", concat_merge, None);
+        // databases created before a column family was added
+        opts.create_missing_column_families(true);
 
         let cfs_v = CF_NAMES.to_vec().iter().map(|name| {
             let mut opts = Options::default();
@@ -659,7 +777,9 @@ impl Hive {
 
         match DB::open_cf_descriptors(&opts, path.clone(), cfs_v) {
             Ok(mut db) => {
-                Hive::clear_db(&mut db);
+                if !persistent {
+                    Hive::clear_db(&mut db);
+                }
                 return db;
             },
             Err(e) => {
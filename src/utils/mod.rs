@@ -1,5 +1,9 @@
 pub mod defines;
+pub mod clock;
+pub mod random;
 pub use self::defines::{AM, AWM};
+pub use self::clock::{Clock, SystemClock, FixedClock};
+pub use self::random::seeded_rng;
 
 #[macro_export]
 macro_rules! make_am {
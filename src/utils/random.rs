@@ -0,0 +1,20 @@
+use rand::{SeedableRng, StdRng};
+
+// one stream per consumer, so seeding one doesn't shift the numbers another one sees
+pub const RNG_TIPS_MANAGER: usize = 1;
+pub const RNG_TIPS_VIEW_MODEL: usize = 2;
+pub const RNG_TRANSACTION_REQUESTER: usize = 3;
+pub const RNG_NODE_REPLY: usize = 4;
+pub const RNG_NODE_BROADCAST: usize = 5;
+pub const RNG_NODE_SYNC: usize = 6;
+
+/// Reproducible generator for `stream` when a seed is configured, seeded from the OS otherwise
+pub fn seeded_rng(seed: Option<u64>, stream: usize) -> StdRng {
+    match seed {
+        Some(seed) => {
+            let key = [seed as usize, (seed >> 32) as usize, stream];
+            SeedableRng::from_seed(&key[..])
+        }
+        None => StdRng::new().expect("failed to seed rng")
+    }
+}